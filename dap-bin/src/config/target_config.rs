@@ -1,12 +1,19 @@
-use super::{toml::ConfigToml, utils_verifier::*};
+use super::{
+  toml::{ConfigToml, SocketBinding},
+  utils_verifier::*,
+};
 use crate::{constants::*, error::*, log::*};
 use async_trait::async_trait;
 use doh_auth_proxy_lib::{
-  AuthenticationConfig, NextHopRelayConfig, ProxyConfig, QueryManipulationConfig, SubseqRelayConfig,
+  AuthenticationConfig, ClientIdentityConfig, DirectoryConfig, DnsCryptConfig, DnsStamp, EndpointHeaderConfig,
+  EndpointTlsConfig, HealthCheckAnswer, HealthCheckConfig, HopCompatibilityConfig, HopLabelConfig, HopStampConfig,
+  HttpStamp, NextHopRelayConfig, ODoHPaddingPolicy, ObliviousHttpConfig, ProxyConfig, QueryManipulationConfig,
+  RelayConfig, SocketBindingConfig, SubseqRelayConfig, TokenValidationConfig, UserAgentPolicy,
 };
 use hot_reload::{Reload, ReloaderError};
 use std::{env, sync::Arc};
 use tokio::time::Duration;
+use url::Url;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 /// Wrapper of config toml and manipulation plugin settings
@@ -58,6 +65,81 @@ impl TargetConfig {
   }
 }
 
+/// Parse source address, interface and fwmark of sockets, where the latter two are available only on Linux
+fn parse_socket_binding(val: &SocketBinding) -> anyhow::Result<SocketBindingConfig> {
+  let source_ip = match &val.source_ip {
+    Some(ip) => Some(ip.parse().map_err(|_| anyhow!("Invalid source_ip: {ip}"))?),
+    None => None,
+  };
+  if matches!(&val.interface, Some(v) if v.is_empty()) {
+    bail!("interface must not be empty");
+  }
+  let binding = SocketBindingConfig {
+    source_ip,
+    interface: val.interface.clone(),
+    fwmark: val.fwmark,
+  };
+  if binding.has_socket_options() && !cfg!(any(target_os = "android", target_os = "fuchsia", target_os = "linux")) {
+    bail!("interface and fwmark of sockets are supported only on Linux");
+  }
+  Ok(binding)
+}
+
+/// Parse a url of (O)DoH target or relay given in a https url or a DNS stamp of DoH server, ODoH target or ODoH relay.
+/// Hops given in stamps are converted into https urls, and returned with their stamps.
+fn parse_hop_url(url: &str) -> anyhow::Result<(Url, Option<HttpStamp>)> {
+  if verify_target_url(url).is_ok() {
+    return Ok((Url::parse(url).unwrap(), None));
+  }
+  let stamp = match parse_dns_stamp(url) {
+    Ok(DnsStamp::DoH(stamp)) | Ok(DnsStamp::ODoHTarget(stamp)) | Ok(DnsStamp::ODoHRelay(stamp)) => stamp,
+    Ok(_) => bail!("DNSCrypt stamp cannot be used here: {url}"),
+    Err(e) => bail!("Invalid url or DNS stamp: {e}"),
+  };
+  Ok((stamp.url()?, Some(stamp)))
+}
+
+/// Parse urls of (O)DoH targets and relays given in https urls or DNS stamps of DoH servers, ODoH targets and ODoH relays.
+/// This is used to refer to hops, and properties of stamps are not recorded.
+fn parse_hop_urls(urls: &[String]) -> anyhow::Result<Vec<Url>> {
+  urls.iter().map(|url| Ok(parse_hop_url(url)?.0)).collect()
+}
+
+/// Parse urls of (O)DoH targets and relays queried by the proxy in the same way as `parse_hop_urls`. Properties of
+/// hops given in stamps are recorded in the proxy config with their bootstrap ips appended to the bootstrap DNS.
+fn parse_queried_hop_urls(urls: &[String], proxy_config: &mut ProxyConfig) -> anyhow::Result<Vec<Url>> {
+  let mut parsed = vec![];
+  for url in urls.iter() {
+    let (url, stamp) = parse_hop_url(url)?;
+    if let Some(stamp) = stamp {
+      let hop = HopStampConfig {
+        url: url.clone(),
+        addr: stamp.addr,
+        cert_hashes: stamp.cert_hashes,
+        props: stamp.props,
+      };
+      if !proxy_config.hop_stamps.contains(&hop) {
+        info!(
+          "{} is given in DNS stamp (address: {:?}, {} certificate hashes, {:?})",
+          hop.url,
+          hop.addr,
+          hop.cert_hashes.len(),
+          hop.props
+        );
+        proxy_config.hop_stamps.push(hop);
+      }
+      for ip in stamp.bootstrap_ips.iter() {
+        if !proxy_config.bootstrap_dns.ips.contains(ip) {
+          info!("Bootstrap DNS {ip} given in DNS stamp of {url} is added");
+          proxy_config.bootstrap_dns.ips.push(*ip);
+        }
+      }
+    }
+    parsed.push(url);
+  }
+  Ok(parsed)
+}
+
 impl TryInto<ProxyConfig> for &TargetConfig {
   type Error = anyhow::Error;
 
@@ -93,6 +175,42 @@ impl TryInto<ProxyConfig> for &TargetConfig {
       proxy_config.endpoint_resolution_period_sec.as_secs() / 60
     );
 
+    /////////////////////////////
+    // egress proxy
+    if let Some(val) = &self.config_toml.egress_proxy {
+      let Ok(proxy_url) = Url::parse(val) else {
+        bail!("Invalid egress proxy url: {val}");
+      };
+      if proxy_url.scheme() == "socks5" {
+        bail!("egress_proxy must be socks5h:// instead of socks5://, which leaks hostnames to the system resolver");
+      }
+      if !["socks5h", "http", "https"].contains(&proxy_url.scheme()) || proxy_url.host_str().is_none() {
+        bail!("egress_proxy must be a socks5h://, http:// or https:// url: {val}");
+      }
+      info!(
+        "All HTTP connections go out through the egress proxy {}:{}",
+        proxy_url.host_str().unwrap_or_default(),
+        proxy_url.port_or_known_default().unwrap_or_default()
+      );
+      proxy_config.egress_proxy = Some(proxy_url);
+    }
+
+    /////////////////////////////
+    // socket binding of upstream and bootstrap connections
+    if let Some(bind) = &self.config_toml.bind {
+      if let Some(val) = &bind.upstream {
+        proxy_config.upstream_binding = parse_socket_binding(val)?;
+        if proxy_config.upstream_binding.has_socket_options() && proxy_config.egress_proxy.is_some() {
+          bail!("interface and fwmark of upstream sockets cannot be used with egress_proxy");
+        }
+        info!("Upstream sockets are bound: {:?}", proxy_config.upstream_binding);
+      }
+      if let Some(val) = &bind.bootstrap {
+        proxy_config.bootstrap_binding = parse_socket_binding(val)?;
+        info!("Bootstrap DNS sockets are bound: {:?}", proxy_config.bootstrap_binding);
+      }
+    }
+
     /////////////////////////////
     // health check period
     if let Some(val) = self.config_toml.healthcheck_period {
@@ -102,6 +220,93 @@ impl TryInto<ProxyConfig> for &TargetConfig {
       "Check for health of all possible path candidates and purge DNS cache every {:?} min",
       proxy_config.healthcheck_period_sec.as_secs() / 60
     );
+    if let Some(val) = self.config_toml.healthcheck_initial_backoff {
+      if val == 0 {
+        bail!("healthcheck_initial_backoff must be greater than 0");
+      }
+      proxy_config.healthcheck_initial_backoff_sec = Duration::from_secs(val as u64);
+    }
+    info!(
+      "Unhealthy paths are re-probed after {:?} sec at first, with exponential backoff",
+      proxy_config.healthcheck_initial_backoff_sec.as_secs()
+    );
+    if let Some(val) = self.config_toml.healthcheck_max_concurrency {
+      if val == 0 {
+        bail!("healthcheck_max_concurrency must be greater than 0");
+      }
+      proxy_config.healthcheck_max_concurrency = val;
+    }
+    info!(
+      "Max number of concurrent health checks: {}",
+      proxy_config.healthcheck_max_concurrency
+    );
+
+    /////////////////////////////
+    // health check probes
+    if let Some(val) = &self.config_toml.healthcheck {
+      let mut healthcheck_configs = vec![];
+      for hc in val.iter() {
+        let mut healthcheck_config = HealthCheckConfig::default();
+        if let Some(target_urls) = &hc.target_urls {
+          let Ok(target_urls) = parse_hop_urls(target_urls) else {
+            bail!("Invalid target urls for health check");
+          };
+          healthcheck_config.target_urls = Some(target_urls);
+        }
+        let Some(query_name) = &hc.query_name else {
+          bail!("query_name must be specified for health check");
+        };
+        healthcheck_config.query_name = query_name.clone();
+        if let Some(query_type) = &hc.query_type {
+          healthcheck_config.query_type = query_type.clone();
+        }
+        if let Some(expected_rcode) = &hc.expected_rcode {
+          healthcheck_config.expected_rcode = expected_rcode.clone();
+        }
+        healthcheck_config.expected_answer = match (&hc.expected_rdata, hc.any_answer) {
+          (Some(rdata), _) => {
+            if rdata.is_empty() {
+              bail!("expected_rdata must have at least one value if specified");
+            }
+            Some(HealthCheckAnswer::OneOf(rdata.clone()))
+          }
+          (None, Some(false)) => None,
+          (None, Some(true)) => Some(HealthCheckAnswer::Any),
+          // any answer is expected by default only for NOERROR
+          (None, None) => healthcheck_config
+            .expected_rcode
+            .eq_ignore_ascii_case("NOERROR")
+            .then_some(HealthCheckAnswer::Any),
+        };
+        if let Some(probes) = hc.probes {
+          healthcheck_config.probes = probes;
+        }
+        healthcheck_config.success_threshold = hc.success_threshold.unwrap_or(healthcheck_config.probes);
+        if let Err(e) = healthcheck_config.validate() {
+          bail!("{e} (query_name: {query_name})");
+        }
+        info!(
+          "Health check for {}: {} {} (expected rcode: {}, answer: {:?}) x {} probes, {} successes required",
+          healthcheck_config
+            .target_urls
+            .as_ref()
+            .map(|v| format!("{:?}", v.iter().map(|x| x.as_str()).collect::<Vec<_>>()))
+            .unwrap_or("other targets".to_string()),
+          healthcheck_config.query_name,
+          healthcheck_config.query_type,
+          healthcheck_config.expected_rcode,
+          healthcheck_config.expected_answer,
+          healthcheck_config.probes,
+          healthcheck_config.success_threshold
+        );
+        healthcheck_configs.push(healthcheck_config);
+      }
+      // built-in probe is applied to targets not covered by any configured probe
+      if healthcheck_configs.iter().all(|v| v.target_urls.is_some()) {
+        healthcheck_configs.push(HealthCheckConfig::default());
+      }
+      proxy_config.healthcheck_configs = healthcheck_configs;
+    }
 
     /////////////////////////////
     // cache size
@@ -113,10 +318,27 @@ impl TryInto<ProxyConfig> for &TargetConfig {
     /////////////////////////////
     // DoH target and method
     if let Some(val) = &self.config_toml.target_urls {
-      if !val.iter().all(|x| verify_target_url(x).is_ok()) {
-        bail!("Invalid target urls");
+      // dnscrypt servers are given in DNS stamps, which cannot be mixed with (O)DoH targets
+      if !val.is_empty()
+        && val
+          .iter()
+          .all(|x| matches!(parse_dns_stamp(x), Ok(DnsStamp::DnsCrypt(_))))
+      {
+        if proxy_config.egress_proxy.is_some() {
+          bail!("DNSCrypt targets cannot be used with egress_proxy, which relays only HTTP connections");
+        }
+        proxy_config.dnscrypt_config = Some(DnsCryptConfig {
+          relay_urls: vec![],
+          relay_randomization: true,
+        });
+        info!("[DNSCrypt] DNSCrypt is enabled for targets given in DNS stamps");
+        proxy_config.target_config.doh_target_urls = val.iter().map(|v| Url::parse(v).unwrap()).collect();
+      } else {
+        let Ok(target_urls) = parse_queried_hop_urls(val, &mut proxy_config) else {
+          bail!("Invalid target urls");
+        };
+        proxy_config.target_config.doh_target_urls = target_urls;
       }
-      proxy_config.target_config.doh_target_urls = val.iter().map(|v| url::Url::parse(v).unwrap()).collect();
     }
     info!(
       "Target (O)DoH resolvers: {:?}",
@@ -146,12 +368,14 @@ impl TryInto<ProxyConfig> for &TargetConfig {
       /////////////////////////////
       // odoh and next hop of modoh
       if let Some(odoh_relay_urls) = &anon.odoh_relay_urls {
-        if !odoh_relay_urls.iter().all(|x| verify_target_url(x).is_ok()) {
+        let Ok(odoh_relay_urls) = parse_queried_hop_urls(odoh_relay_urls, &mut proxy_config) else {
           bail!("Invalid ODoH relay urls");
-        }
+        };
         let mut nexthop_relay_config = NextHopRelayConfig {
-          odoh_relay_urls: odoh_relay_urls.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
+          odoh_relay_urls,
           odoh_relay_randomization: true,
+          odoh_config_via_dns: false,
+          odoh_padding: ODoHPaddingPolicy::Block(ODOH_PADDING_BLOCK_SIZE),
         };
         info!("[ODoH] Oblivious DNS over HTTPS is enabled");
         info!(
@@ -169,23 +393,79 @@ impl TryInto<ProxyConfig> for &TargetConfig {
         if nexthop_relay_config.odoh_relay_randomization {
           info!("ODoH relay randomization is enabled");
         }
+        if let Some(val) = anon.odoh_config_via_dns {
+          nexthop_relay_config.odoh_config_via_dns = val;
+        }
+        if nexthop_relay_config.odoh_config_via_dns {
+          info!("[ODoH] Target configs are discovered in HTTPS records via the oblivious path");
+        }
+        if let Some(val) = &anon.odoh_padding {
+          nexthop_relay_config.odoh_padding = match val.as_str() {
+            "none" => ODoHPaddingPolicy::None,
+            "block" => {
+              let block_size = anon.odoh_padding_block_size.unwrap_or(ODOH_PADDING_BLOCK_SIZE);
+              if block_size == 0 || block_size > u16::MAX as usize {
+                bail!("odoh_padding_block_size must be between 1 and 65535");
+              }
+              ODoHPaddingPolicy::Block(block_size)
+            }
+            "random" => {
+              let min = anon.odoh_padding_min.unwrap_or(0);
+              let Some(max) = anon.odoh_padding_max else {
+                bail!("odoh_padding_max must be specified for random padding");
+              };
+              if min > max || max > u16::MAX as usize {
+                bail!("odoh_padding_min must be equal to or less than odoh_padding_max, which is at most 65535");
+              }
+              ODoHPaddingPolicy::Random { min, max }
+            }
+            "max" => {
+              let Some(max) = anon.odoh_padding_max else {
+                bail!("odoh_padding_max must be specified for max padding");
+              };
+              if max > u16::MAX as usize {
+                bail!("odoh_padding_max must be at most 65535");
+              }
+              ODoHPaddingPolicy::Max(max)
+            }
+            _ => bail!("Invalid odoh_padding: {val}"),
+          };
+        }
+        info!(
+          "[ODoH] Padding policy of queries: {:?}",
+          nexthop_relay_config.odoh_padding
+        );
         proxy_config.nexthop_relay_config = Some(nexthop_relay_config);
 
         /////////////////////////////
         // modoh
         if let Some(val) = &anon.mid_relay_urls {
-          if !val.iter().all(|x| verify_target_url(x).is_ok()) {
+          let Ok(mid_relay_urls) = parse_queried_hop_urls(val, &mut proxy_config) else {
             bail!("Invalid mid relay urls");
-          }
+          };
           if val.is_empty() {
             bail!("mid_relay_urls must specify at least one relay url");
           }
-          if anon.max_mid_relays.is_some() && anon.max_mid_relays.unwrap_or(1) > val.len() {
+          let max_mid_relays = anon.max_mid_relays.unwrap_or(1);
+          let min_mid_relays = anon.min_mid_relays.unwrap_or(0);
+          if max_mid_relays > val.len() {
             bail!("max_mid_relays must be equal to or less than # of mid_relay_urls.");
           }
+          if min_mid_relays > max_mid_relays {
+            bail!("min_mid_relays must be equal to or less than max_mid_relays.");
+          }
+          let mut exclusions = vec![];
+          for urls in anon.mid_relay_exclusions.iter().flatten() {
+            match parse_hop_urls(urls) {
+              Ok(urls) if urls.len() > 1 => exclusions.push(urls),
+              _ => bail!("Each of mid_relay_exclusions must specify at least two valid urls"),
+            }
+          }
           let subseq_relay_config = SubseqRelayConfig {
-            mid_relay_urls: val.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
-            max_mid_relays: anon.max_mid_relays.unwrap_or(1),
+            mid_relay_urls,
+            min_mid_relays,
+            max_mid_relays,
+            exclusions,
           };
 
           info!("[m-ODoH] Multiple-relay-based Oblivious DNS over HTTPS is enabled");
@@ -198,13 +478,189 @@ impl TryInto<ProxyConfig> for &TargetConfig {
               .collect::<Vec<_>>()
           );
           info!(
-            "[m-ODoH] Maximum number of intermediate relays after the nexthop: {}",
-            subseq_relay_config.max_mid_relays
+            "[m-ODoH] Number of intermediate relays after the nexthop: {} to {}",
+            subseq_relay_config.min_mid_relays, subseq_relay_config.max_mid_relays
           );
+          for urls in subseq_relay_config.exclusions.iter() {
+            info!(
+              "[m-ODoH] Hops never placed in the same path: {:?}",
+              urls.iter().map(|x| x.as_str()).collect::<Vec<_>>()
+            );
+          }
 
           proxy_config.subseq_relay_config = Some(subseq_relay_config);
         }
       }
+
+      /////////////////////////////
+      // oblivious http
+      if let Some(ohttp_relay_urls) = &anon.ohttp_relay_urls {
+        if proxy_config.nexthop_relay_config.is_some() {
+          bail!("ohttp_relay_urls cannot be used with odoh_relay_urls");
+        }
+        if ohttp_relay_urls.is_empty() || !ohttp_relay_urls.iter().all(|x| verify_target_url(x).is_ok()) {
+          bail!("Invalid OHTTP relay urls");
+        }
+        let Some(gateway_key_url) = &anon.ohttp_gateway_key_url else {
+          bail!("ohttp_gateway_key_url must be specified with ohttp_relay_urls");
+        };
+        if verify_target_url(gateway_key_url).is_err() {
+          bail!("Invalid OHTTP gateway key url");
+        }
+        let oblivious_http_config = ObliviousHttpConfig {
+          relay_urls: ohttp_relay_urls.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
+          relay_randomization: anon.ohttp_relay_randomization.unwrap_or(true),
+          gateway_key_url: url::Url::parse(gateway_key_url).unwrap(),
+        };
+        info!("[OHTTP] Oblivious HTTP is enabled");
+        info!(
+          "[OHTTP] Relay URL: {:?}, gateway key URL: {}",
+          oblivious_http_config
+            .relay_urls
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>(),
+          oblivious_http_config.gateway_key_url
+        );
+        if oblivious_http_config.relay_randomization {
+          info!("OHTTP relay randomization is enabled");
+        }
+        proxy_config.oblivious_http_config = Some(oblivious_http_config);
+      }
+
+      /////////////////////////////
+      // anonymized dnscrypt
+      if let Some(dnscrypt_relay_urls) = &anon.dnscrypt_relay_urls {
+        let Some(dnscrypt_config) = proxy_config.dnscrypt_config.as_mut() else {
+          bail!("dnscrypt_relay_urls must be used with DNSCrypt targets given in DNS stamps");
+        };
+        if dnscrypt_relay_urls.is_empty()
+          || !dnscrypt_relay_urls
+            .iter()
+            .all(|x| matches!(parse_dns_stamp(x), Ok(DnsStamp::DnsCryptRelay(_))))
+        {
+          bail!("Invalid Anonymized DNSCrypt relay stamps");
+        }
+        dnscrypt_config.relay_urls = dnscrypt_relay_urls
+          .iter()
+          .map(|v| url::Url::parse(v).unwrap())
+          .collect();
+        if let Some(val) = anon.dnscrypt_relay_randomization {
+          dnscrypt_config.relay_randomization = val;
+        }
+        info!("[DNSCrypt] Anonymized DNSCrypt is enabled");
+        info!(
+          "[DNSCrypt] Relay stamps: {:?}",
+          dnscrypt_config
+            .relay_urls
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>()
+        );
+        if dnscrypt_config.relay_randomization {
+          info!("Anonymized DNSCrypt relay randomization is enabled");
+        }
+      }
+      if proxy_config.dnscrypt_config.is_some()
+        && (proxy_config.nexthop_relay_config.is_some() || proxy_config.oblivious_http_config.is_some())
+      {
+        bail!("DNSCrypt targets cannot be used with odoh_relay_urls or ohttp_relay_urls");
+      }
+
+      /////////////////////////////
+      // operator and jurisdiction labels of hops
+      if let Some(hop_labels) = &anon.hop_labels {
+        for hop_label in hop_labels.iter() {
+          let urls = match parse_hop_urls(&hop_label.urls) {
+            Ok(urls) if !urls.is_empty() => urls,
+            _ => bail!("Invalid urls in hop_labels"),
+          };
+          if hop_label.operator.is_none() && hop_label.jurisdiction.is_none() {
+            bail!("hop_labels must specify operator and/or jurisdiction");
+          }
+          info!(
+            "Hops labeled with operator {:?} and jurisdiction {:?}, which are never chained: {:?}",
+            hop_label.operator, hop_label.jurisdiction, hop_label.urls
+          );
+          proxy_config.hop_labels.push(HopLabelConfig {
+            urls,
+            operator: hop_label.operator.clone(),
+            jurisdiction: hop_label.jurisdiction.clone(),
+          });
+        }
+      }
+
+      /////////////////////////////
+      // compatibility between relays and targets
+      if let Some(allowed_targets) = &anon.allowed_targets {
+        for v in allowed_targets.iter() {
+          let relay_url = parse_hop_urls(std::slice::from_ref(&v.relay_url));
+          let target_urls = parse_hop_urls(&v.target_urls);
+          let (Ok(mut relay_url), Ok(target_urls)) = (relay_url, target_urls) else {
+            bail!("Invalid urls in allowed_targets");
+          };
+          if target_urls.is_empty() {
+            bail!("Invalid urls in allowed_targets");
+          }
+          info!("Relay {} forwards only to {:?}", v.relay_url, v.target_urls);
+          proxy_config
+            .hop_compatibility
+            .push(HopCompatibilityConfig::AllowedTargets {
+              relay_url: relay_url.remove(0),
+              target_urls,
+            });
+        }
+      }
+      if let Some(allowed_relays) = &anon.allowed_relays {
+        for v in allowed_relays.iter() {
+          let target_url = parse_hop_urls(std::slice::from_ref(&v.target_url));
+          let relay_urls = parse_hop_urls(&v.relay_urls);
+          let (Ok(mut target_url), Ok(relay_urls)) = (target_url, relay_urls) else {
+            bail!("Invalid urls in allowed_relays");
+          };
+          if relay_urls.is_empty() {
+            bail!("Invalid urls in allowed_relays");
+          }
+          info!("Target {} accepts only queries from {:?}", v.target_url, v.relay_urls);
+          proxy_config
+            .hop_compatibility
+            .push(HopCompatibilityConfig::AllowedRelays {
+              target_url: target_url.remove(0),
+              relay_urls,
+            });
+        }
+      }
+    }
+
+    /////////////////////////////
+    // Signed remote directory of targets and relays
+    if let Some(directory) = &self.config_toml.directory {
+      if verify_target_url(&directory.url).is_err() {
+        bail!("Invalid directory url");
+      }
+      if directory.public_key.is_empty() {
+        bail!("public_key of directory must be given");
+      }
+      if proxy_config.dnscrypt_config.is_some() {
+        bail!("directory cannot be used with DNSCrypt targets, which are not listed in directories");
+      }
+      let refresh_period = directory
+        .refresh_period
+        .map(|v| v as u64)
+        .unwrap_or(DIRECTORY_REFRESH_PERIOD_MIN);
+      if refresh_period == 0 {
+        bail!("refresh_period of directory must be greater than 0");
+      }
+      let directory_config = DirectoryConfig {
+        url: url::Url::parse(&directory.url).unwrap(),
+        public_key: directory.public_key.clone(),
+        refresh_period_sec: Duration::from_secs(refresh_period * 60),
+      };
+      info!(
+        "Targets and relays are overridden by the signed directory {}, refreshed every {} min",
+        directory_config.url, refresh_period
+      );
+      proxy_config.directory_config = Some(directory_config);
     }
 
     /////////////////////////////
@@ -238,11 +694,156 @@ impl TryInto<ProxyConfig> for &TargetConfig {
       }
     };
 
+    /////////////////////////////
+    // TLS settings of targets, relays and token api
+    if let Some(endpoint_tls) = &self.config_toml.endpoint_tls {
+      for tls in endpoint_tls.iter() {
+        let urls = match parse_hop_urls(&tls.urls) {
+          Ok(urls) if !urls.is_empty() => urls,
+          _ => bail!("Invalid urls in endpoint_tls"),
+        };
+        let spki_pins = tls.spki_pins.clone().unwrap_or_default();
+        let tls13_only = tls.tls13_only.unwrap_or(false);
+        let current_dir = env::current_dir()?;
+        let client_identity = match (&tls.client_cert_file, &tls.client_key_file, &tls.client_pkcs12_file) {
+          (Some(cert_file), Some(key_file), None) => Some(ClientIdentityConfig::Pem {
+            cert_file: current_dir.join(cert_file),
+            key_file: current_dir.join(key_file),
+          }),
+          (None, None, Some(file)) => Some(ClientIdentityConfig::Pkcs12 {
+            file: current_dir.join(file),
+            password: tls.client_pkcs12_password.clone().unwrap_or_default(),
+          }),
+          (None, None, None) => None,
+          _ => bail!("endpoint_tls must specify both client_cert_file and client_key_file, or client_pkcs12_file"),
+        };
+        if tls.ca_file.is_none() && spki_pins.is_empty() && !tls13_only && client_identity.is_none() {
+          bail!("endpoint_tls must specify ca_file, spki_pins, tls13_only and/or client certificate");
+        }
+        if spki_pins.len() == 1 {
+          warn!(
+            "Only one SPKI pin is given for {:?}, a backup pin is recommended",
+            tls.urls
+          );
+        }
+        info!(
+          "TLS settings for {:?}: CA file {:?}, {} SPKI pins, TLS 1.3 only: {}, client certificate: {:?}",
+          tls.urls,
+          tls.ca_file,
+          spki_pins.len(),
+          tls13_only,
+          client_identity.as_ref().map(|v| v.files())
+        );
+        proxy_config.endpoint_tls_configs.push(EndpointTlsConfig {
+          urls,
+          ca_file: tls.ca_file.as_ref().map(|v| current_dir.join(v)),
+          spki_pins,
+          tls13_only,
+          client_identity,
+          cert_hashes: vec![],
+        });
+      }
+    }
+
+    /////////////////////////////
+    // User-Agent and custom headers of requests to endpoints
+    if let Some(endpoint_headers) = &self.config_toml.endpoint_headers {
+      if endpoint_headers.iter().filter(|v| v.urls.is_none()).count() > 1 {
+        bail!("endpoint_headers without urls must be given at most once");
+      }
+      for val in endpoint_headers.iter() {
+        let urls = match &val.urls {
+          Some(urls) => match parse_hop_urls(urls) {
+            Ok(urls) if !urls.is_empty() => urls,
+            _ => bail!("Invalid urls in endpoint_headers"),
+          },
+          None => vec![],
+        };
+        let user_agent = match (&val.user_agent, val.suppress_user_agent, &val.user_agent_pool) {
+          (Some(v), None | Some(false), None) => UserAgentPolicy::Fixed(v.clone()),
+          (None, Some(true), None) => UserAgentPolicy::Suppressed,
+          (None, None | Some(false), Some(pool)) if !pool.is_empty() => UserAgentPolicy::Random(pool.clone()),
+          (None, None | Some(false), None) => UserAgentPolicy::Default,
+          _ => {
+            bail!("endpoint_headers must specify one of user_agent, suppress_user_agent and non-empty user_agent_pool")
+          }
+        };
+        let headers = val.headers.clone().unwrap_or_default().into_iter().collect::<Vec<_>>();
+        if headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("user-agent")) {
+          bail!("User-Agent must be given by user_agent, suppress_user_agent or user_agent_pool in endpoint_headers");
+        }
+        // values of custom headers may be secrets like api keys, and are not logged
+        info!(
+          "HTTP headers for {:?}: User-Agent {:?}, custom headers {:?}",
+          val.urls.as_deref().unwrap_or(&["(others)".to_string()]),
+          user_agent,
+          headers.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+        proxy_config.endpoint_header_configs.push(EndpointHeaderConfig {
+          urls,
+          user_agent,
+          headers,
+        });
+      }
+    }
+
+    /////////////////////////////
+    // Built-in odoh relay
+    if let Some(relay) = &self.config_toml.relay {
+      if relay.listen_addresses.is_empty() || !relay.listen_addresses.iter().all(|v| verify_sock_addr(v).is_ok()) {
+        bail!("Invalid listen address of relay");
+      }
+      let path = relay.path.clone().unwrap_or(RELAY_PATH.to_string());
+      if !path.starts_with('/') {
+        bail!("Relay path must start with '/'");
+      }
+      let allowed_targets = match &relay.allowed_target_urls {
+        Some(urls) => {
+          let Ok(urls) = parse_hop_urls(urls) else {
+            bail!("Invalid allowed target urls of relay");
+          };
+          Some(urls)
+        }
+        None => None,
+      };
+      let token_validation = match &relay.token_public_keys {
+        Some(public_keys) => {
+          if public_keys.is_empty() {
+            bail!("token_public_keys of relay must specify at least one key");
+          }
+          Some(TokenValidationConfig {
+            public_keys: public_keys.clone(),
+            issuer: relay.token_issuer.clone(),
+            audiences: relay.token_audiences.clone(),
+          })
+        }
+        None => None,
+      };
+      if allowed_targets.is_none() && token_validation.is_none() {
+        bail!("Relay must specify token_public_keys and/or allowed_target_urls, otherwise it is an open forwarder");
+      }
+      let relay_config = RelayConfig {
+        listen_addresses: relay.listen_addresses.iter().map(|x| x.parse().unwrap()).collect(),
+        path,
+        allowed_targets,
+        max_subseq_relays: relay.max_subseq_relays.unwrap_or(RELAY_MAX_SUBSEQ_RELAYS),
+        token_validation,
+      };
+      info!(
+        "[Relay] Built-in ODoH relay is enabled on {:?} at {}",
+        relay_config.listen_addresses, relay_config.path
+      );
+      if relay_config.token_validation.is_none() {
+        warn!("[Relay] Relay accepts queries without bearer tokens");
+      }
+      proxy_config.relay_config = Some(relay_config);
+    }
+
     ////////////////////////
     if proxy_config.authentication_config.is_some() {
-      if proxy_config.nexthop_relay_config.is_some() {
+      if proxy_config.nexthop_relay_config.is_some() || proxy_config.oblivious_http_config.is_some() {
         warn!("-----------------------------------");
-        warn!("[NOTE!!!!] Both credential and ODoH nexthop proxy is set up.");
+        warn!("[NOTE!!!!] Both credential and ODoH nexthop proxy or OHTTP relay is set up.");
         warn!("[NOTE!!!!] This means the authorization token will be sent not to the target but to the proxy.");
         warn!("[NOTE!!!!] Check if this is your intended behavior.");
         warn!("-----------------------------------");
@@ -265,3 +866,75 @@ impl TryInto<ProxyConfig> for &TargetConfig {
     Ok(proxy_config)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// DoH stamp of dns.example.com with a certificate hash and a bootstrap resolver 203.0.113.53
+  const DOH_STAMP: &str = "sdns://AgcAAAAAAAAACTE5Mi4wLjIuMSAAAQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHw9kbnMuZXhhbXBsZS5jb20KL2Rucy1xdWVyeQwyMDMuMC4xMTMuNTM";
+
+  /// DNSCrypt stamp of 192.0.2.53
+  const DNSCRYPT_STAMP: &str = "sdns://AQcAAAAAAAAACjE5Mi4wLjIuNTMgAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8bMi5kbnNjcnlwdC1jZXJ0LmV4YW1wbGUuY29t";
+
+  fn proxy_config(toml_str: &str) -> anyhow::Result<ProxyConfig> {
+    let target_config = TargetConfig {
+      config_toml: toml::from_str(toml_str)?,
+      query_manipulation_config: None,
+    };
+    (&target_config).try_into()
+  }
+
+  #[test]
+  fn stamps_of_queried_hops_are_recorded() {
+    let bootstrap_ip = "203.0.113.53".parse().unwrap();
+
+    // stamps only referred to by other settings add neither properties nor bootstrap resolvers
+    let config = proxy_config(&format!(
+      r#"
+      target_urls = ["https://dns.google/dns-query"]
+      [[endpoint_headers]]
+      urls = ["{DOH_STAMP}"]
+      user_agent = "agent"
+      "#
+    ))
+    .unwrap();
+    assert!(config.hop_stamps.is_empty());
+    assert!(!config.bootstrap_dns.ips.contains(&bootstrap_ip));
+
+    let config = proxy_config(&format!(r#"target_urls = ["{DOH_STAMP}"]"#)).unwrap();
+    assert_eq!(config.hop_stamps.len(), 1);
+    assert_eq!(config.hop_stamps[0].cert_hashes.len(), 1);
+    assert!(config.bootstrap_dns.ips.contains(&bootstrap_ip));
+  }
+
+  #[test]
+  fn healthcheck_is_validated() {
+    let healthcheck = |extra: &str| proxy_config(&format!("[[healthcheck]]\nquery_name = \"example.com\"\n{extra}"));
+    assert_eq!(
+      healthcheck("probes = 3").unwrap().healthcheck_configs[0].success_threshold,
+      3
+    );
+    assert!(healthcheck("probes = 3\nsuccess_threshold = 2").is_ok());
+    assert!(healthcheck("probes = 0").is_err());
+    assert!(healthcheck("probes = 2\nsuccess_threshold = 3").is_err());
+    assert!(healthcheck("query_type = \"INVALID\"").is_err());
+  }
+
+  #[test]
+  fn directory_is_rejected_with_dnscrypt() {
+    let directory = r#"
+      [directory]
+      url = "https://directory.example.org/odoh.json"
+      public_key = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+    "#;
+    let config = proxy_config(&format!(
+      "target_urls = [\"https://dns.google/dns-query\"]\n{directory}"
+    ))
+    .unwrap();
+    assert!(config.directory_config.is_some());
+    let dnscrypt = format!("target_urls = [\"{DNSCRYPT_STAMP}\"]");
+    assert!(proxy_config(&dnscrypt).unwrap().dnscrypt_config.is_some());
+    assert!(proxy_config(&format!("{dnscrypt}\n{directory}")).is_err());
+  }
+}
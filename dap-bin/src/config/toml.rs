@@ -1,22 +1,43 @@
 use crate::error::*;
 use serde::Deserialize;
-use std::fs;
+use std::{collections::BTreeMap, fs};
 
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct ConfigToml {
   pub listen_addresses: Option<Vec<String>>,
   pub bootstrap_dns: Option<Vec<String>>,
   pub endpoint_resolution_period: Option<usize>,
+  pub egress_proxy: Option<String>,
+  pub bind: Option<Bind>,
   pub healthcheck_period: Option<usize>,
+  pub healthcheck_initial_backoff: Option<usize>,
+  pub healthcheck_max_concurrency: Option<usize>,
+  pub healthcheck: Option<Vec<HealthCheck>>,
   pub max_cache_size: Option<usize>,
   pub target_urls: Option<Vec<String>>,
   pub target_randomization: Option<bool>,
   pub use_get_method: Option<bool>,
   pub authentication: Option<Authentication>,
   pub anonymization: Option<Anonymization>,
+  pub directory: Option<Directory>,
+  pub relay: Option<Relay>,
+  pub endpoint_tls: Option<Vec<EndpointTls>>,
+  pub endpoint_headers: Option<Vec<EndpointHeaders>>,
   pub plugins: Option<Plugins>,
 }
 
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct HealthCheck {
+  pub target_urls: Option<Vec<String>>,
+  pub query_name: Option<String>,
+  pub query_type: Option<String>,
+  pub expected_rcode: Option<String>,
+  pub expected_rdata: Option<Vec<String>>,
+  pub any_answer: Option<bool>,
+  pub probes: Option<usize>,
+  pub success_threshold: Option<usize>,
+}
+
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct Plugins {
   pub domains_blocked_file: Option<String>,
@@ -27,9 +48,95 @@ pub struct Plugins {
 pub struct Anonymization {
   pub odoh_relay_urls: Option<Vec<String>>,
   pub odoh_relay_randomization: Option<bool>,
+  pub odoh_config_via_dns: Option<bool>,
+  pub odoh_padding: Option<String>,
+  pub odoh_padding_block_size: Option<usize>,
+  pub odoh_padding_min: Option<usize>,
+  pub odoh_padding_max: Option<usize>,
+  pub ohttp_relay_urls: Option<Vec<String>>,
+  pub ohttp_relay_randomization: Option<bool>,
+  pub ohttp_gateway_key_url: Option<String>,
+  pub dnscrypt_relay_urls: Option<Vec<String>>,
+  pub dnscrypt_relay_randomization: Option<bool>,
   pub mid_relay_urls: Option<Vec<String>>,
+  pub min_mid_relays: Option<usize>,
   pub max_mid_relays: Option<usize>,
+  pub mid_relay_exclusions: Option<Vec<Vec<String>>>,
+  pub hop_labels: Option<Vec<HopLabel>>,
+  pub allowed_targets: Option<Vec<AllowedTargets>>,
+  pub allowed_relays: Option<Vec<AllowedRelays>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct HopLabel {
+  pub urls: Vec<String>,
+  pub operator: Option<String>,
+  pub jurisdiction: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct AllowedTargets {
+  pub relay_url: String,
+  pub target_urls: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct AllowedRelays {
+  pub target_url: String,
+  pub relay_urls: Vec<String>,
+}
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Directory {
+  pub url: String,
+  pub public_key: String,
+  pub refresh_period: Option<usize>,
 }
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Relay {
+  pub listen_addresses: Vec<String>,
+  pub path: Option<String>,
+  pub allowed_target_urls: Option<Vec<String>>,
+  pub max_subseq_relays: Option<usize>,
+  pub token_public_keys: Option<Vec<String>>,
+  pub token_issuer: Option<String>,
+  pub token_audiences: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Bind {
+  pub upstream: Option<SocketBinding>,
+  pub bootstrap: Option<SocketBinding>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct SocketBinding {
+  pub source_ip: Option<String>,
+  pub interface: Option<String>,
+  pub fwmark: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct EndpointTls {
+  pub urls: Vec<String>,
+  pub ca_file: Option<String>,
+  pub spki_pins: Option<Vec<String>>,
+  pub tls13_only: Option<bool>,
+  pub client_cert_file: Option<String>,
+  pub client_key_file: Option<String>,
+  pub client_pkcs12_file: Option<String>,
+  pub client_pkcs12_password: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct EndpointHeaders {
+  pub urls: Option<Vec<String>>,
+  pub user_agent: Option<String>,
+  pub suppress_user_agent: Option<bool>,
+  pub user_agent_pool: Option<Vec<String>>,
+  pub headers: Option<BTreeMap<String, String>>,
+}
+
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct Authentication {
   pub token_api: Option<String>,
@@ -1,4 +1,5 @@
 // functions to verify the startup arguments as correct
+use doh_auth_proxy_lib::DnsStamp;
 use std::net::{IpAddr, SocketAddr};
 use url::Url;
 
@@ -36,3 +37,7 @@ pub(crate) fn verify_target_url(arg_val: &str) -> Result<(), String> {
   }
   Ok(())
 }
+
+pub(crate) fn parse_dns_stamp(arg_val: &str) -> Result<DnsStamp, String> {
+  DnsStamp::try_from(arg_val).map_err(|e| format!("Could not parse \"{}\" as a valid DNS stamp: {}", arg_val, e))
+}
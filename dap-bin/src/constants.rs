@@ -3,3 +3,14 @@ pub const CONFIG_WATCH_DELAY_SECS: u32 = 30;
 pub const CREDENTIAL_USERNAME_FIELD: &str = "username";
 pub const CREDENTIAL_API_KEY_FIELD: &str = "password";
 pub const CREDENTIAL_CLIENT_ID_FIELD: &str = "client_id";
+
+/// Refresh the signed directory of targets and relays every 60 minutes by default
+pub const DIRECTORY_REFRESH_PERIOD_MIN: u64 = 60;
+
+/// Pad ODoH plaintext queries to the multiple of 128 bytes by default
+pub const ODOH_PADDING_BLOCK_SIZE: usize = 128;
+
+/// Path of the built-in relay endpoint
+pub const RELAY_PATH: &str = "/proxy";
+/// Built-in relay accepts at most 2 subsequent relays after itself by default
+pub const RELAY_MAX_SUBSEQ_RELAYS: usize = 2;
@@ -78,7 +78,7 @@ async fn proxy_service_without_watcher(
     }
   };
 
-  entrypoint(&proxy_conf, &runtime_handle, None)
+  entrypoint(&proxy_conf, &runtime_handle, None, None)
     .await
     .map_err(|e| anyhow::anyhow!(e))
 }
@@ -105,7 +105,7 @@ async fn proxy_service_with_watcher(
   // Continuous monitoring
   loop {
     tokio::select! {
-      _ = entrypoint(&proxy_conf, &runtime_handle, Some(term_notify.clone())) => {
+      _ = entrypoint(&proxy_conf, &runtime_handle, Some(term_notify.clone()), None) => {
         error!("proxy entrypoint exited");
         break;
       }
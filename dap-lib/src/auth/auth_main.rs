@@ -19,7 +19,12 @@ impl TokenHttpClient for HttpClientInner {
     S: Serialize + Send + Sync,
     R: DeserializeOwned + Send + Sync,
   {
-    let res = self.client.post(url.to_owned()).json(json_body).send().await?;
+    let res = self
+      .post(url.to_owned())
+      .json(json_body)
+      .send()
+      .await
+      .map_err(DapError::from)?;
     if !res.status().is_success() {
       let err_res = res.error_for_status_ref();
       bail!(DapError::HttpClientError(err_res.unwrap_err()));
@@ -32,7 +37,7 @@ impl TokenHttpClient for HttpClientInner {
   where
     R: DeserializeOwned + Send + Sync,
   {
-    let res = self.client.get(url.to_owned()).send().await?;
+    let res = self.get(url.to_owned()).send().await.map_err(DapError::from)?;
     if !res.status().is_success() {
       let err_res = res.error_for_status_ref();
       bail!(DapError::HttpClientError(err_res.unwrap_err()));
@@ -1,35 +1,63 @@
 use crate::{
   error::*,
-  globals::BootstrapDns,
+  globals::{BootstrapDns, SocketBindingConfig},
   log::*,
+  outbound_socket::{bind_udp, connect_tcp, is_same_family},
   trait_resolve_ips::{ResolveIpResponse, ResolveIps},
 };
 use async_trait::async_trait;
 use hickory_resolver::{
   config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
-  name_server::{GenericConnector, TokioRuntimeProvider},
-  AsyncResolver, TokioAsyncResolver,
+  name_server::{GenericConnector, RuntimeProvider, TokioHandle, TokioRuntimeProvider},
+  proto::{iocompat::AsyncIoTokioAsStd, TokioTime},
+  AsyncResolver,
 };
 use reqwest::Url;
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::Future, io, net::SocketAddr, pin::Pin, sync::Arc};
+use tokio::net::{TcpStream, UdpSocket};
 
 #[derive(Clone)]
 /// stub resolver using bootstrap DNS resolver
 pub struct BootstrapDnsResolver {
   /// wrapper of trust-dns-resolver
-  pub inner: AsyncResolver<GenericConnector<TokioRuntimeProvider>>,
+  pub inner: AsyncResolver<GenericConnector<BoundRuntimeProvider>>,
 }
 
 impl BootstrapDnsResolver {
   /// Build stub resolver using bootstrap dns resolver
-  pub async fn try_new(bootstrap_dns: &BootstrapDns, runtime_handle: tokio::runtime::Handle) -> Result<Self> {
-    let ips = &bootstrap_dns.ips;
+  pub async fn try_new(
+    bootstrap_dns: &BootstrapDns,
+    binding: &SocketBindingConfig,
+    runtime_handle: tokio::runtime::Handle,
+  ) -> Result<Self> {
+    // bootstrap resolvers of another address family than the source address are unreachable
+    let ips = bootstrap_dns
+      .ips
+      .iter()
+      .filter(|ip| is_same_family(binding, ip))
+      .copied()
+      .collect::<Vec<_>>();
+    if ips.is_empty() {
+      return Err(DapError::Other(anyhow!(
+        "No bootstrap DNS resolver of the address family of the source address"
+      )));
+    }
     let port = &bootstrap_dns.port;
-    let name_servers = NameServerConfigGroup::from_ips_clear(ips, *port, true);
+    let name_servers = NameServerConfigGroup::from_ips_clear(&ips, *port, true);
     let resolver_config = ResolverConfig::from_parts(None, vec![], name_servers);
+    let provider = BoundRuntimeProvider {
+      inner: TokioRuntimeProvider::new(),
+      binding: Arc::new(binding.clone()),
+    };
 
     let resolver = runtime_handle
-      .spawn(async { TokioAsyncResolver::tokio(resolver_config, ResolverOpts::default()) })
+      .spawn(async {
+        AsyncResolver::new(
+          resolver_config,
+          ResolverOpts::default(),
+          GenericConnector::new(provider),
+        )
+      })
       .await
       .map_err(|e| DapError::Other(anyhow!(e)))?;
 
@@ -37,6 +65,39 @@ impl BootstrapDnsResolver {
   }
 }
 
+#[derive(Clone)]
+/// Tokio runtime provider creating sockets to bootstrap DNS resolvers with the source address, the interface and
+/// the fwmark
+pub struct BoundRuntimeProvider {
+  inner: TokioRuntimeProvider,
+  binding: Arc<SocketBindingConfig>,
+}
+
+impl RuntimeProvider for BoundRuntimeProvider {
+  type Handle = TokioHandle;
+  type Timer = TokioTime;
+  type Udp = UdpSocket;
+  type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+  fn create_handle(&self) -> Self::Handle {
+    self.inner.create_handle()
+  }
+
+  fn connect_tcp(&self, server_addr: SocketAddr) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+    let binding = self.binding.clone();
+    Box::pin(async move { connect_tcp(&binding, server_addr).await.map(AsyncIoTokioAsStd) })
+  }
+
+  fn bind_udp(
+    &self,
+    _local_addr: SocketAddr,
+    server_addr: SocketAddr,
+  ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+    let socket = bind_udp(&self.binding, server_addr);
+    Box::pin(async move { socket })
+  }
+}
+
 #[async_trait]
 impl ResolveIps for Arc<BootstrapDnsResolver> {
   /// Lookup the IP addresses associated with a name using the bootstrap resolver
@@ -90,9 +151,13 @@ mod tests {
       ips: vec![IpAddr::from([8, 8, 8, 8])],
       port: 53,
     };
-    let resolver = BootstrapDnsResolver::try_new(&bootstrap_dns, tokio::runtime::Handle::current())
-      .await
-      .unwrap();
+    let resolver = BootstrapDnsResolver::try_new(
+      &bootstrap_dns,
+      &SocketBindingConfig::default(),
+      tokio::runtime::Handle::current(),
+    )
+    .await
+    .unwrap();
     let resolver = Arc::new(resolver);
     let target_url = Url::parse("https://dns.google").unwrap();
     let response = resolver.resolve_ips(&target_url).await.unwrap();
@@ -18,6 +18,8 @@ pub const MAX_CONNECTIONS: usize = 128;
 pub const HTTP_TIMEOUT_SEC: u64 = 10;
 /// TTL for overridden records (plugin) in synthetic response
 pub const MIN_TTL: u32 = 10;
+/// Block size of EDNS(0) padding of queries recommended in RFC 8467
+pub const EDNS_PADDING_QUERY_BLOCK_SIZE: usize = 128;
 
 ////////////////////////////////
 // Default Values for Config  //
@@ -37,6 +39,10 @@ pub const ENDPOINT_RESOLUTION_PERIOD_MIN: u64 = 60;
 
 /// Health check: Check for health of paths and purge cache for every 600 secs
 pub const HEALTHCHECK_PERIOD_MIN: u64 = 10;
+/// Health check: Re-probe unhealthy paths after 5 secs at first, with exponential backoff
+pub const HEALTHCHECK_INITIAL_BACKOFF_SEC: u64 = 5;
+/// Health check: Max number of concurrent health checks
+pub const HEALTHCHECK_MAX_CONCURRENCY: usize = 8;
 
 /// Default DoH target server
 pub const DOH_TARGET_URL: &[&str] = &["https://dns.google/dns-query"];
@@ -56,8 +62,43 @@ pub const HTTP_USER_AGENT: &str = "doh-auth-proxy";
 
 /// ODoH config path
 pub const ODOH_CONFIG_PATH: &str = ".well-known/odohconfigs";
-/// ODoH config is retrieved every 3600 secs
-pub const ODOH_CONFIG_WATCH_DELAY: i64 = 3600;
+/// SvcParamKey of odohconfig in HTTPS records, i.e., key32769
+pub const ODOH_CONFIG_SVC_PARAM_KEY: u16 = 32769;
+/// ODoH config is retrieved every 3600 secs if max-age is not given in Cache-Control of the response
+pub const ODOH_CONFIG_WATCH_DELAY: u64 = 3600;
+/// ODoH config is not retrieved more often than every 60 secs even if max-age is smaller
+pub const ODOH_CONFIG_MIN_REFRESH_SEC: u64 = 60;
+/// ODoH config is still used for 86400 secs after max-age while refetching fails
+pub const ODOH_CONFIG_MAX_STALE_SEC: u64 = 86400;
+/// Failed ODoH config fetch is first retried after 10 secs, doubled on every failure up to the watch delay
+pub const ODOH_CONFIG_RETRY_INITIAL_SEC: u64 = 10;
+/// OHTTP key configs of the gateway are retrieved every 3600 secs if max-age is not given in Cache-Control of the response
+pub const OHTTP_KEY_CONFIG_WATCH_DELAY: u64 = 3600;
+/// Failed OHTTP key config fetch is retried after 10 secs
+pub const OHTTP_KEY_CONFIG_RETRY_SEC: u64 = 10;
+/// A pair of a relay and a target is learned as incompatible after this number of consecutive rejections like 403
+pub const ODOH_REJECTION_THRESHOLD: usize = 3;
+/// Certificates of DNSCrypt servers are refetched when they are older than 3600 secs
+pub const DNSCRYPT_CERT_REFRESH_SEC: u64 = 3600;
+/// DNSCrypt queries over UDP are padded at least to 256 bytes
+pub const DNSCRYPT_MIN_QUERY_LEN: usize = 256;
+
+// Directory
+
+/// Allowed clock skew for the issued time of the signed directory
+pub const DIRECTORY_MAX_CLOCK_SKEW_SEC: u64 = 300;
+/// Retry fetching the directory after 60 secs on failure
+pub const DIRECTORY_RETRY_WAITING_SEC: u64 = 60;
+
+// Relay
+
+/// Maximum size of ODoH messages accepted by the relay
+pub const RELAY_MAX_BODY_SIZE: usize = 65535;
+
+// Http client
+
+/// Check modification of client certificate files for mutual TLS every 60 secs
+pub const CLIENT_IDENTITY_WATCH_PERIOD_SEC: u64 = 60;
 
 // Authentication
 
@@ -78,3 +119,15 @@ pub const MAX_RELOGIN_ATTEMPTS: usize = 5;
 pub const HEALTHCHECK_TARGET_FQDN: &str = "dns.google.";
 /// Health check target IP address for assertion
 pub const HEALTHCHECK_TARGET_ADDR: &str = "8.8.8.8";
+/// Health check query type
+pub const HEALTHCHECK_QUERY_TYPE: &str = "A";
+/// Health check expected response code
+pub const HEALTHCHECK_EXPECTED_RCODE: &str = "NOERROR";
+/// Health check: number of probes for every check
+pub const HEALTHCHECK_PROBES: usize = 1;
+/// Health check: number of successful probes to flag healthy
+pub const HEALTHCHECK_SUCCESS_THRESHOLD: usize = 1;
+/// Randomized jitter added to the health check interval, ratio to the interval
+pub const HEALTHCHECK_JITTER_RATIO: f64 = 0.1;
+/// Minimum sleep of the health check scheduler until paths fall due
+pub const HEALTHCHECK_MIN_SLEEP_SEC: u64 = 1;
@@ -0,0 +1,412 @@
+use crate::error::*;
+use data_encoding::BASE64URL_NOPAD;
+use std::net::{IpAddr, SocketAddr};
+use url::Url;
+
+/// Scheme of DNS stamps
+pub(crate) const DNS_STAMP_SCHEME: &str = "sdns";
+/// Default port of servers given in DNS stamps
+const DNS_STAMP_DEFAULT_PORT: u16 = 443;
+
+/// Protocol identifier of DNSCrypt stamps
+const PROTOCOL_DNSCRYPT: u8 = 0x01;
+/// Protocol identifier of DoH stamps
+const PROTOCOL_DOH: u8 = 0x02;
+/// Protocol identifier of ODoH target stamps
+const PROTOCOL_ODOH_TARGET: u8 = 0x05;
+/// Protocol identifier of Anonymized DNSCrypt relay stamps
+const PROTOCOL_DNSCRYPT_RELAY: u8 = 0x81;
+/// Protocol identifier of ODoH relay stamps
+const PROTOCOL_ODOH_RELAY: u8 = 0x85;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Informal properties announced by the operator in DNS stamps
+pub struct StampProps {
+  /// the server supports DNSSEC
+  pub dnssec: bool,
+  /// the server doesn't keep logs
+  pub no_logs: bool,
+  /// the server doesn't intentionally block domains
+  pub no_filter: bool,
+}
+
+impl From<u64> for StampProps {
+  fn from(props: u64) -> Self {
+    Self {
+      dnssec: props & 0x01 != 0,
+      no_logs: props & 0x02 != 0,
+      no_filter: props & 0x04 != 0,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// DNSCrypt server given in a DNS stamp
+pub struct DnsCryptStamp {
+  /// informal properties
+  pub props: StampProps,
+  /// ip address and port of the server
+  pub addr: SocketAddr,
+  /// ed25519 public key of the provider to verify certificates
+  pub provider_public_key: [u8; 32],
+  /// provider name like "2.dnscrypt-cert.example.com"
+  pub provider_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// DoH server, ODoH target or ODoH relay given in a DNS stamp
+pub struct HttpStamp {
+  /// informal properties
+  pub props: StampProps,
+  /// ip address of the server, with which the hostname is resolved if given
+  pub addr: Option<SocketAddr>,
+  /// SHA-256 digests of the TBS certificates found in the validation chain of the server
+  pub cert_hashes: Vec<[u8; 32]>,
+  /// hostname with an optional port like "dns.example.com:8443"
+  pub hostname: String,
+  /// path like "/dns-query"
+  pub path: String,
+  /// ip addresses of recommended resolvers to resolve the hostname
+  pub bootstrap_ips: Vec<IpAddr>,
+}
+
+impl HttpStamp {
+  /// Build https url from the hostname and the path
+  pub fn url(&self) -> Result<Url> {
+    let url = Url::parse(&format!("https://{}{}", self.hostname, self.path))?;
+    if url.host_str().is_none() || url.path() != self.path {
+      return Err(DapError::InvalidDnsStamp(format!(
+        "invalid hostname or path {}{}",
+        self.hostname, self.path
+      )));
+    }
+    Ok(url)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// DNS stamp (https://dnscrypt.info/stamps-specifications) of the supported protocols
+pub enum DnsStamp {
+  /// DNSCrypt server
+  DnsCrypt(DnsCryptStamp),
+  /// DoH server
+  DoH(HttpStamp),
+  /// ODoH target, where only the props, hostname and path are given
+  ODoHTarget(HttpStamp),
+  /// Anonymized DNSCrypt relay given by its ip address and port
+  DnsCryptRelay(SocketAddr),
+  /// ODoH relay
+  ODoHRelay(HttpStamp),
+}
+
+impl TryFrom<&str> for DnsStamp {
+  type Error = DapError;
+
+  fn try_from(stamp: &str) -> Result<Self> {
+    let invalid = |e: &str| DapError::InvalidDnsStamp(e.to_string());
+    let Some(encoded) = stamp.strip_prefix(&format!("{DNS_STAMP_SCHEME}://")) else {
+      return Err(invalid("stamp must start with sdns://"));
+    };
+    let bytes = BASE64URL_NOPAD
+      .decode(encoded.trim_end_matches('=').as_bytes())
+      .map_err(|_| invalid("stamp is not encoded in base64url"))?;
+    let Some((protocol, rest)) = bytes.split_first() else {
+      return Err(invalid("empty stamp"));
+    };
+    let mut reader = StampReader(rest);
+
+    let stamp = match *protocol {
+      PROTOCOL_DNSCRYPT => {
+        let props = reader.props()?;
+        let addr = parse_addr(reader.lp()?)?;
+        let provider_public_key = reader
+          .lp()?
+          .try_into()
+          .map_err(|_| invalid("provider public key must be 32 bytes"))?;
+        let provider_name = String::from_utf8(reader.lp()?.to_vec()).map_err(|_| invalid("invalid provider name"))?;
+        if provider_name.is_empty() {
+          return Err(invalid("empty provider name"));
+        }
+        DnsStamp::DnsCrypt(DnsCryptStamp {
+          props,
+          addr,
+          provider_public_key,
+          provider_name,
+        })
+      }
+      PROTOCOL_DOH => DnsStamp::DoH(reader.http_stamp()?),
+      PROTOCOL_ODOH_TARGET => {
+        let props = reader.props()?;
+        let hostname = reader.hostname()?;
+        let path = reader.path()?;
+        DnsStamp::ODoHTarget(HttpStamp {
+          props,
+          addr: None,
+          cert_hashes: vec![],
+          hostname,
+          path,
+          bootstrap_ips: vec![],
+        })
+      }
+      PROTOCOL_DNSCRYPT_RELAY => DnsStamp::DnsCryptRelay(parse_addr(reader.lp()?)?),
+      PROTOCOL_ODOH_RELAY => DnsStamp::ODoHRelay(reader.http_stamp()?),
+      _ => return Err(invalid(&format!("unsupported protocol {protocol:#04x}"))),
+    };
+    if !reader.0.is_empty() {
+      return Err(invalid("trailing bytes in stamp"));
+    }
+    Ok(stamp)
+  }
+}
+
+impl TryFrom<&Url> for DnsStamp {
+  type Error = DapError;
+
+  fn try_from(url: &Url) -> Result<Self> {
+    // base64url is case-sensitive, which is kept in the opaque host of sdns urls
+    Self::try_from(url.as_str().trim_end_matches('/'))
+  }
+}
+
+/// Reader of fields in the decoded stamp
+struct StampReader<'a>(&'a [u8]);
+
+impl<'a> StampReader<'a> {
+  /// take the given number of bytes
+  fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+    if self.0.len() < len {
+      return Err(DapError::InvalidDnsStamp("truncated stamp".to_string()));
+    }
+    let (taken, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(taken)
+  }
+
+  /// take a length-prefixed field
+  fn lp(&mut self) -> Result<&'a [u8]> {
+    let len = self.take(1)?[0] as usize;
+    self.take(len)
+  }
+
+  /// take a set of variable length fields, where the length of each field but the last one has the 0x80 bit set
+  fn vlp(&mut self) -> Result<Vec<&'a [u8]>> {
+    let mut fields = vec![];
+    loop {
+      let len = self.take(1)?[0];
+      fields.push(self.take((len & 0x7f) as usize)?);
+      if len & 0x80 == 0 {
+        return Ok(fields);
+      }
+    }
+  }
+
+  /// take the 8-byte little-endian props
+  fn props(&mut self) -> Result<StampProps> {
+    Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()).into())
+  }
+
+  /// take the hostname with an optional port
+  fn hostname(&mut self) -> Result<String> {
+    let hostname =
+      String::from_utf8(self.lp()?.to_vec()).map_err(|_| DapError::InvalidDnsStamp("invalid hostname".to_string()))?;
+    if hostname.is_empty() {
+      return Err(DapError::InvalidDnsStamp("empty hostname".to_string()));
+    }
+    Ok(hostname)
+  }
+
+  /// take the absolute path
+  fn path(&mut self) -> Result<String> {
+    let path =
+      String::from_utf8(self.lp()?.to_vec()).map_err(|_| DapError::InvalidDnsStamp("invalid path".to_string()))?;
+    if !path.starts_with('/') {
+      return Err(DapError::InvalidDnsStamp("path must start with /".to_string()));
+    }
+    Ok(path)
+  }
+
+  /// take fields of DoH and ODoH relay stamps, which share the layout
+  fn http_stamp(&mut self) -> Result<HttpStamp> {
+    let props = self.props()?;
+    let addr = match self.lp()? {
+      [] => None,
+      addr => Some(parse_addr(addr)?),
+    };
+    let cert_hashes = self
+      .vlp()?
+      .into_iter()
+      .filter(|hash| !hash.is_empty())
+      .map(|hash| {
+        hash
+          .try_into()
+          .map_err(|_| DapError::InvalidDnsStamp("certificate hash must be 32 bytes".to_string()))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let hostname = self.hostname()?;
+    let path = self.path()?;
+    // bootstrap ips are optional
+    let bootstrap_ips = match self.0.is_empty() {
+      true => vec![],
+      false => self
+        .vlp()?
+        .into_iter()
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| parse_addr(ip).map(|v| v.ip()))
+        .collect::<Result<Vec<_>>>()?,
+    };
+    Ok(HttpStamp {
+      props,
+      addr,
+      cert_hashes,
+      hostname,
+      path,
+      bootstrap_ips,
+    })
+  }
+}
+
+/// Parse the address like "192.0.2.1", "192.0.2.1:8443" or "[2001:db8::1]:8443" in stamps, where the port defaults to 443
+fn parse_addr(addr: &[u8]) -> Result<SocketAddr> {
+  let invalid = || DapError::InvalidDnsStamp(format!("invalid address {}", String::from_utf8_lossy(addr)));
+  let addr = std::str::from_utf8(addr).map_err(|_| invalid())?;
+  if let Ok(sock_addr) = addr.parse::<SocketAddr>() {
+    return Ok(sock_addr);
+  }
+  let ip = addr.trim_start_matches('[').trim_end_matches(']');
+  ip.parse::<IpAddr>()
+    .map(|ip| SocketAddr::new(ip, DNS_STAMP_DEFAULT_PORT))
+    .map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(protocol: u8, fields: &[&[u8]], props: Option<u64>) -> String {
+    let mut bytes = vec![protocol];
+    if let Some(props) = props {
+      bytes.extend_from_slice(&props.to_le_bytes());
+    }
+    for field in fields {
+      bytes.push(field.len() as u8);
+      bytes.extend_from_slice(field);
+    }
+    format!("sdns://{}", BASE64URL_NOPAD.encode(&bytes))
+  }
+
+  #[test]
+  fn dnscrypt_stamp_works() {
+    let stamp = encode(
+      PROTOCOL_DNSCRYPT,
+      &[b"192.0.2.1:8443", &[7u8; 32], b"2.dnscrypt-cert.example.com"],
+      Some(0x03),
+    );
+    let url = Url::parse(&stamp).unwrap();
+    let DnsStamp::DnsCrypt(parsed) = DnsStamp::try_from(&url).unwrap() else {
+      panic!("not a dnscrypt stamp");
+    };
+    assert_eq!(
+      parsed.props,
+      StampProps {
+        dnssec: true,
+        no_logs: true,
+        no_filter: false
+      }
+    );
+    assert_eq!(parsed.addr, "192.0.2.1:8443".parse().unwrap());
+    assert_eq!(parsed.provider_public_key, [7u8; 32]);
+    assert_eq!(parsed.provider_name, "2.dnscrypt-cert.example.com");
+
+    // default port and ipv6
+    let stamp = encode(
+      PROTOCOL_DNSCRYPT,
+      &[b"[2001:db8::1]", &[7u8; 32], b"2.dnscrypt-cert.example.com"],
+      Some(0),
+    );
+    let DnsStamp::DnsCrypt(parsed) = DnsStamp::try_from(stamp.as_str()).unwrap() else {
+      panic!("not a dnscrypt stamp");
+    };
+    assert_eq!(parsed.addr, "[2001:db8::1]:443".parse().unwrap());
+
+    // short public key, truncated, trailing bytes and unsupported protocol
+    let short = encode(
+      PROTOCOL_DNSCRYPT,
+      &[b"192.0.2.1", &[7u8; 31], b"2.dnscrypt-cert.example.com"],
+      Some(0),
+    );
+    assert!(DnsStamp::try_from(short.as_str()).is_err());
+    let truncated = encode(PROTOCOL_DNSCRYPT, &[b"192.0.2.1", &[7u8; 32]], Some(0));
+    assert!(DnsStamp::try_from(truncated.as_str()).is_err());
+    let trailing = encode(PROTOCOL_DNSCRYPT_RELAY, &[b"192.0.2.1", b"x"], None);
+    assert!(DnsStamp::try_from(trailing.as_str()).is_err());
+    assert!(DnsStamp::try_from(encode(0x7f, &[b"192.0.2.1"], None).as_str()).is_err());
+    assert!(DnsStamp::try_from("https://dns.example/dns-query").is_err());
+  }
+
+  #[test]
+  fn doh_stamp_works() {
+    // sets of certificate hashes and bootstrap ips are encoded as vlp, so build the stamp by hand
+    let mut bytes = vec![PROTOCOL_DOH];
+    bytes.extend_from_slice(&0x06u64.to_le_bytes());
+    bytes.extend_from_slice(b"\x09192.0.2.1");
+    bytes.push(0x80 | 32);
+    bytes.extend_from_slice(&[1u8; 32]);
+    bytes.push(32);
+    bytes.extend_from_slice(&[2u8; 32]);
+    bytes.extend_from_slice(b"\x0fdns.example.com\x0a/dns-query");
+    bytes.push(0x80 | 7);
+    bytes.extend_from_slice(b"1.1.1.1");
+    bytes.push(13);
+    bytes.extend_from_slice(b"[2001:db8::2]");
+    let stamp = format!("sdns://{}", BASE64URL_NOPAD.encode(&bytes));
+
+    let DnsStamp::DoH(parsed) = DnsStamp::try_from(stamp.as_str()).unwrap() else {
+      panic!("not a doh stamp");
+    };
+    assert_eq!(parsed.props, StampProps::from(0x06));
+    assert_eq!(parsed.addr, Some("192.0.2.1:443".parse().unwrap()));
+    assert_eq!(parsed.cert_hashes, vec![[1u8; 32], [2u8; 32]]);
+    assert_eq!(parsed.url().unwrap().as_str(), "https://dns.example.com/dns-query");
+    assert_eq!(
+      parsed.bootstrap_ips,
+      vec!["1.1.1.1".parse::<IpAddr>().unwrap(), "2001:db8::2".parse().unwrap()]
+    );
+
+    // odoh target without addr, hashes and bootstrap ips
+    let stamp = encode(PROTOCOL_ODOH_TARGET, &[b"odoh.example.com", b"/dns-query"], Some(0));
+    let DnsStamp::ODoHTarget(parsed) = DnsStamp::try_from(stamp.as_str()).unwrap() else {
+      panic!("not an odoh target stamp");
+    };
+    assert_eq!(parsed.url().unwrap().as_str(), "https://odoh.example.com/dns-query");
+    assert!(parsed.addr.is_none() && parsed.cert_hashes.is_empty());
+
+    // odoh relay with an empty addr and an empty hash, and a relative path
+    let stamp = encode(
+      PROTOCOL_ODOH_RELAY,
+      &[b"", b"", b"relay.example.com:8443", b"/proxy"],
+      Some(0),
+    );
+    let DnsStamp::ODoHRelay(parsed) = DnsStamp::try_from(stamp.as_str()).unwrap() else {
+      panic!("not an odoh relay stamp");
+    };
+    assert_eq!(parsed.url().unwrap().as_str(), "https://relay.example.com:8443/proxy");
+    assert!(parsed.bootstrap_ips.is_empty());
+    let relative = encode(
+      PROTOCOL_ODOH_RELAY,
+      &[b"", b"", b"relay.example.com", b"proxy"],
+      Some(0),
+    );
+    assert!(DnsStamp::try_from(relative.as_str()).is_err());
+  }
+
+  #[test]
+  fn relay_stamp_works() {
+    let stamp = encode(PROTOCOL_DNSCRYPT_RELAY, &[b"198.51.100.1"], None);
+    assert_eq!(
+      DnsStamp::try_from(stamp.as_str()).unwrap(),
+      DnsStamp::DnsCryptRelay("198.51.100.1:443".parse().unwrap())
+    );
+    let invalid = encode(PROTOCOL_DNSCRYPT_RELAY, &[b"relay.example"], None);
+    assert!(DnsStamp::try_from(invalid.as_str()).is_err());
+  }
+}
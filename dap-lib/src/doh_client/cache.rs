@@ -96,6 +96,7 @@ impl Cache {
     let now = Instant::now();
     let mut response_message_clone = response_message.clone();
     response_message_clone.set_id(0); // when cache hit, update with given query id to respond
+    dns_message::strip_padding(&mut response_message_clone);
     let cache_object = CacheObject {
       message: response_message_clone,
       create_at: now,
@@ -0,0 +1,401 @@
+use super::{
+  path_manage::{DoHPathManager, HopUrls, PathSetUpdate},
+  path_update::PathSetUpdater,
+};
+use crate::{
+  constants::{DIRECTORY_MAX_CLOCK_SKEW_SEC, DIRECTORY_RETRY_WAITING_SEC},
+  error::*,
+  globals::{DirectoryConfig, ProxyConfig},
+  http_client::HttpClientInner,
+  log::*,
+};
+use data_encoding::BASE64;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+use std::{
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+  sync::{Notify, RwLock},
+  time::{sleep, Duration},
+};
+use url::Url;
+
+#[derive(Deserialize)]
+/// Signed envelope served at the directory url, where the payload is the base64-encoded json of `Directory`
+/// and the signature is the base64-encoded Ed25519 signature over the decoded payload.
+struct SignedDirectory {
+  payload: String,
+  signature: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Directory of targets and relays
+struct Directory {
+  /// unix time when the directory was issued
+  issued_at: u64,
+  /// unix time after which the directory must not be used
+  expires_at: u64,
+  /// urls of targets
+  targets: Vec<String>,
+  /// urls of next-hop relays
+  #[serde(default)]
+  relays: Vec<String>,
+  /// urls of intermediate relays
+  #[serde(default)]
+  mid_relays: Vec<String>,
+}
+
+impl Directory {
+  /// Verify the signature and the freshness of the signed directory, and parse it
+  fn verify(body: &[u8], key: &VerifyingKey, now: u64) -> Result<Self> {
+    let invalid = |e: &str| DapError::InvalidDirectory(e.to_string());
+    let signed: SignedDirectory = serde_json::from_slice(body).map_err(|e| invalid(&e.to_string()))?;
+    let payload = BASE64
+      .decode(signed.payload.as_bytes())
+      .map_err(|e| invalid(&e.to_string()))?;
+    let signature = BASE64
+      .decode(signed.signature.as_bytes())
+      .map_err(|e| invalid(&e.to_string()))?;
+    let signature = Signature::from_slice(&signature).map_err(|e| invalid(&e.to_string()))?;
+    key
+      .verify(&payload, &signature)
+      .map_err(|_| invalid("signature verification failed"))?;
+
+    let directory: Directory = serde_json::from_slice(&payload).map_err(|e| invalid(&e.to_string()))?;
+    if directory.issued_at > now + DIRECTORY_MAX_CLOCK_SKEW_SEC {
+      return Err(invalid("issued in the future"));
+    }
+    if directory.expires_at <= now {
+      return Err(invalid("expired"));
+    }
+    if directory.targets.is_empty() {
+      return Err(invalid("no target"));
+    }
+    Ok(directory)
+  }
+
+  /// Check if the directory lists the same targets and relays as the other
+  fn has_same_hops(&self, other: &Self) -> bool {
+    self.targets == other.targets && self.relays == other.relays && self.mid_relays == other.mid_relays
+  }
+
+  /// Build urls of hops from the directory.
+  /// Relays are applied only when the base config enables ODoH or MODoH, and the current ones are kept if not listed.
+  fn hop_urls(&self, base: &ProxyConfig, current: &HopUrls) -> Result<HopUrls> {
+    let parse = |urls: &[String]| {
+      urls
+        .iter()
+        .map(|v| {
+          let url = Url::parse(v)?;
+          if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+            return Err(DapError::InvalidDirectory(format!("invalid url {v}")));
+          }
+          Ok(url)
+        })
+        .collect::<Result<Vec<_>>>()
+    };
+
+    let mut hop_urls = current.clone();
+    hop_urls.targets = parse(&self.targets)?;
+    if base.nexthop_relay_config.is_some() && !self.relays.is_empty() {
+      hop_urls.nexthops = parse(&self.relays)?;
+    }
+    if base.nexthop_relay_config.is_some() && base.subseq_relay_config.is_some() && !self.mid_relays.is_empty() {
+      hop_urls.mid_relays = parse(&self.mid_relays)?;
+    }
+    Ok(hop_urls)
+  }
+}
+
+/// Build urls of hops from the base config, to which the path set falls back when the directory expires.
+/// Relays are restored only when the directory can override them, and the current ones are kept otherwise.
+fn base_hop_urls(base: &ProxyConfig, current: &HopUrls) -> HopUrls {
+  let mut hop_urls = current.clone();
+  hop_urls.targets = base.target_config.doh_target_urls.clone();
+  if let Some(nexthop_relay_config) = &base.nexthop_relay_config {
+    hop_urls.nexthops = nexthop_relay_config.odoh_relay_urls.clone();
+    if let Some(subseq_relay_config) = &base.subseq_relay_config {
+      hop_urls.mid_relays = subseq_relay_config.mid_relay_urls.clone();
+    }
+  }
+  hop_urls
+}
+
+#[derive(Debug, Default)]
+/// State of the directory applied to the path set
+struct DirectoryState {
+  /// directory applied to the path set, none if hops of the base config are applied
+  applied: Option<Directory>,
+  /// issue time of the latest directory, before which directories are rejected
+  issued_at: Option<u64>,
+}
+
+impl DirectoryState {
+  /// Check if the applied directory has expired
+  fn is_expired(&self, now: u64) -> bool {
+    self.applied.as_ref().is_some_and(|v| v.expires_at <= now)
+  }
+}
+
+/// Watcher of the signed remote directory, which updates the path set when the directory is updated
+pub(super) struct DirectoryWatcher {
+  /// url of the directory
+  url: Url,
+  /// pinned public key
+  key: VerifyingKey,
+  /// refresh period
+  refresh_period: Duration,
+  /// base config overridden by the directory
+  base_config: ProxyConfig,
+  /// http client to fetch the directory
+  http_client: Arc<RwLock<HttpClientInner>>,
+  /// path manager to read the current hops
+  path_manager: Arc<DoHPathManager>,
+  /// updater of the path set
+  path_updater: Arc<PathSetUpdater>,
+}
+
+impl DirectoryWatcher {
+  /// Create a new directory watcher
+  pub fn new(
+    directory_config: &DirectoryConfig,
+    base_config: &ProxyConfig,
+    http_client: Arc<RwLock<HttpClientInner>>,
+    path_manager: Arc<DoHPathManager>,
+    path_updater: Arc<PathSetUpdater>,
+  ) -> Result<Self> {
+    let key = BASE64
+      .decode(directory_config.public_key.as_bytes())
+      .map_err(|e| DapError::InvalidDirectory(format!("public key: {e}")))?;
+    let key: [u8; 32] = key
+      .try_into()
+      .map_err(|_| DapError::InvalidDirectory("public key must be 32 bytes".to_string()))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|e| DapError::InvalidDirectory(format!("public key: {e}")))?;
+    Ok(Self {
+      url: directory_config.url.clone(),
+      key,
+      refresh_period: directory_config.refresh_period_sec,
+      base_config: base_config.clone(),
+      http_client,
+      path_manager,
+      path_updater,
+    })
+  }
+
+  /// start directory watch service
+  pub async fn start_service(&self, term_notify: Option<Arc<Notify>>) -> Result<()> {
+    info!("Start periodic directory watch service for {}", self.url);
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          _ = self.watch_service() => {
+            warn!("Directory watch service is down");
+          }
+          _ = term.notified() => {
+            info!("Directory watch service receives term signal");
+          }
+        }
+      }
+      None => {
+        self.watch_service().await?;
+        warn!("Directory watch service is down.");
+      }
+    }
+    Ok(())
+  }
+
+  /// watch service
+  async fn watch_service(&self) -> Result<()> {
+    let mut state = DirectoryState::default();
+    loop {
+      let waiting = match self.refresh(&mut state).await {
+        Ok(_) => self.refresh_period,
+        Err(e) => {
+          warn!("Failed to refresh directory from {}: {e}", self.url);
+          self
+            .refresh_period
+            .min(Duration::from_secs(DIRECTORY_RETRY_WAITING_SEC))
+        }
+      };
+      if let Err(e) = self.fall_back_if_expired(&mut state).await {
+        warn!("Failed to fall back to targets and relays of the config: {e}");
+      }
+      sleep(waiting).await;
+    }
+  }
+
+  /// Fall back to hops of the base config if the applied directory has expired without being refreshed
+  async fn fall_back_if_expired(&self, state: &mut DirectoryState) -> Result<()> {
+    if !state.is_expired(unix_time_now()) {
+      return Ok(());
+    }
+    let current_hops = self.path_manager.hop_urls();
+    let hop_urls = base_hop_urls(&self.base_config, &current_hops);
+    self
+      .path_updater
+      .apply(&PathSetUpdate::diff(&current_hops, &hop_urls))
+      .await?;
+    warn!("Directory has expired, and targets and relays of the config are applied");
+    state.applied = None;
+    Ok(())
+  }
+
+  /// Fetch and verify the directory, and update the path set if targets or relays are updated
+  async fn refresh(&self, state: &mut DirectoryState) -> Result<()> {
+    let lock = self.http_client.read().await;
+    let response = lock.get(self.url.clone()).send().await?;
+    drop(lock);
+    if response.status() != reqwest::StatusCode::OK {
+      return Err(DapError::InvalidDirectory(format!("status {}", response.status())));
+    }
+    let body = response.bytes().await?;
+    let directory = Directory::verify(&body, &self.key, unix_time_now())?;
+
+    if state.issued_at.is_some_and(|v| directory.issued_at < v) {
+      return Err(DapError::InvalidDirectory("older than the current one".to_string()));
+    }
+    state.issued_at = Some(directory.issued_at);
+    if let Some(applied) = state.applied.as_mut() {
+      if directory.has_same_hops(applied) {
+        debug!("Directory is not changed");
+        *applied = directory;
+        return Ok(());
+      }
+    }
+
+    let current_hops = self.path_manager.hop_urls();
+    let hop_urls = directory.hop_urls(&self.base_config, &current_hops)?;
+    self
+      .path_updater
+      .apply(&PathSetUpdate::diff(&current_hops, &hop_urls))
+      .await?;
+    info!("Path set is updated from directory issued at {}", directory.issued_at);
+    state.applied = Some(directory);
+    Ok(())
+  }
+}
+
+/// Current unix time in secs
+fn unix_time_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|v| v.as_secs())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::globals::{NextHopRelayConfig, ODoHPaddingPolicy, SubseqRelayConfig};
+  use ed25519_dalek::{Signer, SigningKey};
+
+  fn sign(key: &SigningKey, payload: &str) -> Vec<u8> {
+    let signature = key.sign(payload.as_bytes());
+    format!(
+      r#"{{"payload":"{}","signature":"{}"}}"#,
+      BASE64.encode(payload.as_bytes()),
+      BASE64.encode(&signature.to_bytes())
+    )
+    .into_bytes()
+  }
+
+  #[tokio::test]
+  async fn verify_directory_works() {
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let now = 1_700_000_000;
+    let payload = format!(
+      r#"{{"issued_at":{},"expires_at":{},"targets":["https://target.example/dns-query"],"relays":["https://relay.example/proxy"]}}"#,
+      now - 10,
+      now + 3600
+    );
+    let directory = Directory::verify(&sign(&key, &payload), &key.verifying_key(), now).unwrap();
+    assert_eq!(directory.relays, vec!["https://relay.example/proxy".to_string()]);
+    assert!(directory.mid_relays.is_empty());
+
+    // expired
+    assert!(Directory::verify(&sign(&key, &payload), &key.verifying_key(), now + 3600).is_err());
+    // signed by another key
+    let another = SigningKey::from_bytes(&[8u8; 32]);
+    assert!(Directory::verify(&sign(&another, &payload), &key.verifying_key(), now).is_err());
+    // tampered payload
+    let tampered = sign(&key, &payload);
+    let tampered = String::from_utf8(tampered)
+      .unwrap()
+      .replacen("payload\":\"", "payload\":\"A", 1);
+    assert!(Directory::verify(tampered.as_bytes(), &key.verifying_key(), now).is_err());
+
+    // apply to the current hops
+    let base = ProxyConfig {
+      nexthop_relay_config: Some(NextHopRelayConfig {
+        odoh_relay_urls: vec![Url::parse("https://old-relay.example/proxy").unwrap()],
+        odoh_relay_randomization: true,
+        odoh_config_via_dns: false,
+        odoh_padding: ODoHPaddingPolicy::None,
+      }),
+      ..Default::default()
+    };
+    let current = HopUrls {
+      targets: base.target_config.doh_target_urls.clone(),
+      nexthops: vec![Url::parse("https://old-relay.example/proxy").unwrap()],
+      mid_relays: vec![Url::parse("https://mid.example/proxy").unwrap()],
+    };
+    let hop_urls = directory.hop_urls(&base, &current).unwrap();
+    assert_eq!(hop_urls.targets[0].as_str(), "https://target.example/dns-query");
+    assert_eq!(hop_urls.nexthops[0].as_str(), "https://relay.example/proxy");
+    assert_eq!(hop_urls.mid_relays, current.mid_relays);
+    // relays are ignored for the standard doh
+    let hop_urls = directory
+      .hop_urls(&ProxyConfig::default(), &HopUrls::default())
+      .unwrap();
+    assert!(hop_urls.nexthops.is_empty());
+  }
+
+  #[test]
+  fn expired_directory_falls_back_to_base_config() {
+    let now = 1_700_000_000;
+    let directory = Directory {
+      issued_at: now - 3600,
+      expires_at: now,
+      targets: vec!["https://target.example/dns-query".to_string()],
+      relays: vec![],
+      mid_relays: vec![],
+    };
+    let mut state = DirectoryState::default();
+    assert!(!state.is_expired(now));
+    state.applied = Some(directory);
+    state.issued_at = Some(now - 3600);
+    assert!(!state.is_expired(now - 1));
+    assert!(state.is_expired(now));
+
+    let base = ProxyConfig {
+      nexthop_relay_config: Some(NextHopRelayConfig {
+        odoh_relay_urls: vec![Url::parse("https://base-relay.example/proxy").unwrap()],
+        odoh_relay_randomization: true,
+        odoh_config_via_dns: false,
+        odoh_padding: ODoHPaddingPolicy::None,
+      }),
+      subseq_relay_config: Some(SubseqRelayConfig {
+        mid_relay_urls: vec![Url::parse("https://base-mid.example/proxy").unwrap()],
+        min_mid_relays: 0,
+        max_mid_relays: 1,
+        exclusions: vec![],
+      }),
+      ..Default::default()
+    };
+    let current = HopUrls {
+      targets: vec![Url::parse("https://target.example/dns-query").unwrap()],
+      nexthops: vec![Url::parse("https://relay.example/proxy").unwrap()],
+      mid_relays: vec![Url::parse("https://mid.example/proxy").unwrap()],
+    };
+    let hop_urls = base_hop_urls(&base, &current);
+    assert_eq!(hop_urls.targets, base.target_config.doh_target_urls);
+    assert_eq!(hop_urls.nexthops[0].as_str(), "https://base-relay.example/proxy");
+    assert_eq!(hop_urls.mid_relays[0].as_str(), "https://base-mid.example/proxy");
+    // relays are kept for the standard doh
+    let hop_urls = base_hop_urls(&ProxyConfig::default(), &current);
+    assert_eq!(hop_urls.nexthops, current.nexthops);
+    assert_eq!(hop_urls.mid_relays, current.mid_relays);
+  }
+}
@@ -4,7 +4,10 @@ use hickory_proto::{
   op::{update_message::MAX_PAYLOAD_LEN, Edns, Message, MessageType, OpCode, Query},
   rr::{
     domain::Name,
-    rdata::{A, AAAA},
+    rdata::{
+      opt::{EdnsCode, EdnsOption},
+      A, AAAA,
+    },
     DNSClass, RData, Record, RecordType,
   },
   serialize::binary::{BinDecodable, BinEncodable},
@@ -87,10 +90,55 @@ pub fn encode(msg: &Message) -> anyhow::Result<Vec<u8>> {
     .map_err(|e| anyhow!("Failed to encode DNS message: {}", e))
 }
 
+/// Replace the message ID of an encoded DNS message, i.e., the first two bytes
+pub fn replace_id(packet_buf: &[u8], id: u16) -> anyhow::Result<Vec<u8>> {
+  if packet_buf.len() < 2 {
+    bail!("Too short DNS message to replace id");
+  }
+  let mut buf = packet_buf.to_vec();
+  buf[..2].copy_from_slice(&id.to_be_bytes());
+  Ok(buf)
+}
+
+/// Pad the DNS message to the multiple of the block size with the EDNS(0) padding option (RFC 7830, RFC 8467),
+/// replacing the existing padding. Returns the encoded message and whether EDNS is newly added to the message.
+pub fn pad(msg: &mut Message, block_size: usize) -> anyhow::Result<(Vec<u8>, bool)> {
+  let edns_added = msg.extensions().is_none();
+  let edns = msg.extensions_mut().get_or_insert_with(|| {
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD_LEN).set_version(0);
+    edns
+  });
+  edns
+    .options_mut()
+    .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![]));
+
+  let unpadded_len = encode(msg)?.len();
+  let padding_len = (block_size - unpadded_len % block_size) % block_size;
+  if let Some(edns) = msg.extensions_mut() {
+    edns
+      .options_mut()
+      .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![0; padding_len]));
+  }
+  Ok((encode(msg)?, edns_added))
+}
+
+/// Remove the EDNS(0) padding option from the DNS message
+pub fn strip_padding(msg: &mut Message) {
+  if let Some(edns) = msg.extensions_mut() {
+    edns.options_mut().remove(EdnsCode::Padding);
+  }
+}
+
 /// Build a DNS query message for A record
 pub fn build_query_a(fqdn: &str) -> anyhow::Result<Message> {
-  let qname: Name = Name::from_ascii(fqdn).unwrap();
-  let mut query = Query::query(qname, RecordType::A);
+  build_query(fqdn, RecordType::A)
+}
+
+/// Build a DNS query message for the given record type
+pub fn build_query(fqdn: &str, query_type: RecordType) -> anyhow::Result<Message> {
+  let qname: Name = Name::from_ascii(fqdn)?;
+  let mut query = Query::query(qname, query_type);
   query.set_query_class(DNSClass::IN);
 
   let options = DnsRequestOptions::default();
@@ -143,3 +191,43 @@ pub fn build_response_given_ipaddr(
   }
   Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn padding_works() {
+    for fqdn in [
+      "a.example.",
+      "www.example.com.",
+      "a.long.name.of.some.subdomain.under.example.org.",
+    ] {
+      // edns is added to the message without edns
+      let mut msg = build_query_a(fqdn).unwrap();
+      let unpadded_len = encode(&msg).unwrap().len();
+      let (padded, edns_added) = pad(&mut msg, 128).unwrap();
+      assert!(edns_added);
+      assert_eq!(padded.len(), 128);
+
+      // existing padding is replaced
+      let mut decoded = decode(&padded).unwrap();
+      assert!(decoded
+        .extensions()
+        .as_ref()
+        .unwrap()
+        .option(EdnsCode::Padding)
+        .is_some());
+      let (repadded, edns_added) = pad(&mut decoded, 128).unwrap();
+      assert!(!edns_added);
+      assert_eq!(repadded, padded);
+
+      // only padding is stripped, keeping edns
+      strip_padding(&mut decoded);
+      let edns = decoded.extensions().as_ref().unwrap();
+      assert!(edns.option(EdnsCode::Padding).is_none());
+      // 11 bytes of opt record
+      assert_eq!(encode(&decoded).unwrap().len(), unpadded_len + 11);
+    }
+  }
+}
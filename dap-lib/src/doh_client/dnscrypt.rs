@@ -0,0 +1,561 @@
+use super::{dns_message, path_manage::DoHPath};
+use crate::{
+  constants::{DNSCRYPT_CERT_REFRESH_SEC, DNSCRYPT_MIN_QUERY_LEN},
+  dns_stamp::{DnsCryptStamp, DnsStamp},
+  error::*,
+  globals::{DnsCryptConfig, SocketBindingConfig},
+  log::*,
+  outbound_socket::{bind_udp, connect_tcp},
+};
+use crypto_box::{
+  aead::{generic_array::GenericArray, AeadInPlace},
+  ChaChaBox, PublicKey, SecretKey,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hickory_proto::rr::{RData, RecordType};
+use rustc_hash::FxHashMap as HashMap;
+use std::{
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  sync::{Mutex, RwLock},
+  time::{timeout, Duration, Instant},
+};
+use url::Url;
+
+/// Magic of certificates
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+/// Encryption system of certificates, where only X25519-XChaCha20Poly1305 is supported
+const ES_VERSION_XCHACHA20POLY1305: u16 = 0x0002;
+/// Length of certificates without extensions
+const CERT_LEN: usize = 124;
+/// Offset of the signed part of certificates
+const CERT_SIGNED_OFFSET: usize = 72;
+/// Magic of responses from servers
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+/// Magic prepended to queries sent via Anonymized DNSCrypt relays
+const ANON_MAGIC: [u8; 12] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00];
+/// Length of the half nonce chosen by each of the client and the server
+const HALF_NONCE_LEN: usize = 12;
+/// Length of the poly1305 tag
+const TAG_LEN: usize = 16;
+/// Queries and responses are padded to the multiple of this size
+const PADDING_BLOCK_SIZE: usize = 64;
+/// Buffer size of UDP responses, which never exceed the query sizes
+const UDP_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Certificate of a DNSCrypt server, whose signature has been verified with the provider public key
+pub(super) struct DnsCryptCert {
+  /// short-term public key of the server
+  resolver_public_key: [u8; 32],
+  /// magic to be prepended to queries encrypted with this certificate
+  client_magic: [u8; 8],
+  /// serial, the higher one is preferred
+  serial: u32,
+  /// validity period in unix time secs
+  ts_start: u32,
+  ts_end: u32,
+}
+
+impl DnsCryptCert {
+  /// Parse a certificate and verify its signature
+  fn try_new(bytes: &[u8], provider_public_key: &VerifyingKey) -> Result<Self> {
+    let invalid = |e: &str| DapError::DnsCryptCertFetchError(e.to_string());
+    if bytes.len() < CERT_LEN || &bytes[..4] != CERT_MAGIC {
+      return Err(invalid("not a dnscrypt certificate"));
+    }
+    if u16::from_be_bytes([bytes[4], bytes[5]]) != ES_VERSION_XCHACHA20POLY1305 {
+      return Err(invalid("unsupported encryption system"));
+    }
+    let signature = Signature::from_slice(&bytes[8..CERT_SIGNED_OFFSET]).map_err(|_| invalid("malformed signature"))?;
+    provider_public_key
+      .verify(&bytes[CERT_SIGNED_OFFSET..], &signature)
+      .map_err(|_| invalid("invalid signature"))?;
+
+    let signed = &bytes[CERT_SIGNED_OFFSET..];
+    let u32_at = |idx: usize| u32::from_be_bytes(signed[idx..idx + 4].try_into().unwrap());
+    Ok(Self {
+      resolver_public_key: signed[..32].try_into().unwrap(),
+      client_magic: signed[32..40].try_into().unwrap(),
+      serial: u32_at(40),
+      ts_start: u32_at(44),
+      ts_end: u32_at(48),
+    })
+  }
+
+  /// check if the certificate is valid at the given unix time in secs
+  fn is_valid_at(&self, now: u32) -> bool {
+    self.ts_start <= now && now <= self.ts_end
+  }
+
+  /// Encrypt the query with a fresh ephemeral key, so that queries are not linked by the key.
+  /// Returns the encrypted query and the state to decrypt its response.
+  fn encrypt_query(&self, packet_buf: &[u8], min_len: usize) -> Result<(Vec<u8>, QueryState)> {
+    let secret_key = SecretKey::generate(&mut rand::rngs::OsRng);
+    let public_key = secret_key.public_key();
+    let crypto_box = ChaChaBox::new(&PublicKey::from(self.resolver_public_key), &secret_key);
+    let client_nonce: [u8; HALF_NONCE_LEN] = rand::random();
+
+    let mut buf = pad(packet_buf, min_len);
+    let tag = crypto_box
+      .encrypt_in_place_detached(&full_nonce(&client_nonce, &[0u8; HALF_NONCE_LEN]), b"", &mut buf)
+      .map_err(|e| DapError::DnsCryptError(e.to_string()))?;
+
+    let mut query = Vec::with_capacity(8 + 32 + HALF_NONCE_LEN + TAG_LEN + buf.len());
+    query.extend_from_slice(&self.client_magic);
+    query.extend_from_slice(public_key.as_bytes());
+    query.extend_from_slice(&client_nonce);
+    query.extend_from_slice(&tag);
+    query.extend_from_slice(&buf);
+    Ok((
+      query,
+      QueryState {
+        crypto_box,
+        client_nonce,
+      },
+    ))
+  }
+}
+
+/// State kept by the client to decrypt the response of a query
+pub(super) struct QueryState {
+  /// box with the shared key of the ephemeral client key and the server key
+  crypto_box: ChaChaBox,
+  /// half nonce chosen by the client, which must be echoed back in the response
+  client_nonce: [u8; HALF_NONCE_LEN],
+}
+
+impl QueryState {
+  /// Decrypt the response to the query
+  fn decrypt_response(&self, response: &[u8]) -> Result<Vec<u8>> {
+    let invalid = |e: &str| DapError::DnsCryptError(e.to_string());
+    let header_len = RESOLVER_MAGIC.len() + 2 * HALF_NONCE_LEN + TAG_LEN;
+    if response.len() < header_len || &response[..RESOLVER_MAGIC.len()] != RESOLVER_MAGIC {
+      return Err(invalid("not a dnscrypt response"));
+    }
+    let nonce = &response[RESOLVER_MAGIC.len()..RESOLVER_MAGIC.len() + 2 * HALF_NONCE_LEN];
+    if nonce[..HALF_NONCE_LEN] != self.client_nonce {
+      return Err(invalid("nonce mismatch"));
+    }
+    let tag = &response[header_len - TAG_LEN..header_len];
+    let mut buf = response[header_len..].to_vec();
+    self
+      .crypto_box
+      .decrypt_in_place_detached(
+        GenericArray::from_slice(nonce),
+        b"",
+        &mut buf,
+        GenericArray::from_slice(tag),
+      )
+      .map_err(|_| invalid("failed to decrypt response"))?;
+    unpad(buf)
+  }
+}
+
+/// Build the full nonce from the halves of the client and the server
+fn full_nonce(
+  client: &[u8; HALF_NONCE_LEN],
+  server: &[u8; HALF_NONCE_LEN],
+) -> GenericArray<u8, crypto_box::aead::consts::U24> {
+  let mut nonce = GenericArray::default();
+  nonce[..HALF_NONCE_LEN].copy_from_slice(client);
+  nonce[HALF_NONCE_LEN..].copy_from_slice(server);
+  nonce
+}
+
+/// Pad the message with 0x80 and zeros to the multiple of the block size, at least to the minimum length
+fn pad(msg: &[u8], min_len: usize) -> Vec<u8> {
+  let len = (msg.len() + 1).div_ceil(PADDING_BLOCK_SIZE) * PADDING_BLOCK_SIZE;
+  let mut buf = Vec::with_capacity(len.max(min_len));
+  buf.extend_from_slice(msg);
+  buf.push(0x80);
+  buf.resize(len.max(min_len), 0);
+  buf
+}
+
+/// Remove the padding of 0x80 and zeros
+fn unpad(mut buf: Vec<u8>) -> Result<Vec<u8>> {
+  let Some(idx) = buf.iter().rposition(|v| *v != 0) else {
+    return Err(DapError::DnsCryptError("invalid padding".to_string()));
+  };
+  if buf[idx] != 0x80 {
+    return Err(DapError::DnsCryptError("invalid padding".to_string()));
+  }
+  buf.truncate(idx);
+  Ok(buf)
+}
+
+/// Select the valid certificate with the highest serial among those given in TXT records
+fn select_cert(certs: &[Vec<u8>], provider_public_key: &[u8; 32], now: u32) -> Result<DnsCryptCert> {
+  let provider_public_key = VerifyingKey::from_bytes(provider_public_key)
+    .map_err(|_| DapError::DnsCryptCertFetchError("invalid provider public key".to_string()))?;
+  certs
+    .iter()
+    .filter_map(|bytes| match DnsCryptCert::try_new(bytes, &provider_public_key) {
+      Ok(cert) => Some(cert),
+      Err(e) => {
+        debug!("Skip DNSCrypt certificate: {e}");
+        None
+      }
+    })
+    .filter(|cert| cert.is_valid_at(now))
+    .max_by_key(|cert| cert.serial)
+    .ok_or(DapError::DnsCryptNoValidCert)
+}
+
+/// Current unix time in secs
+fn unix_now() -> u32 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|v| v.as_secs() as u32)
+    .unwrap_or_default()
+}
+
+/// Build the payload sent to the first hop, i.e., the server or the Anonymized DNSCrypt relay forwarding to the server
+fn first_hop(server: SocketAddr, relay: Option<SocketAddr>, packet: &[u8]) -> (SocketAddr, Vec<u8>) {
+  let Some(relay) = relay else {
+    return (server, packet.to_vec());
+  };
+  let ip = match server.ip() {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+    IpAddr::V6(ip) => ip,
+  };
+  let mut buf = Vec::with_capacity(ANON_MAGIC.len() + 16 + 2 + packet.len());
+  buf.extend_from_slice(&ANON_MAGIC);
+  buf.extend_from_slice(&ip.octets());
+  buf.extend_from_slice(&server.port().to_be_bytes());
+  buf.extend_from_slice(packet);
+  (relay, buf)
+}
+
+/// Check if the TC flag is set in the DNS message
+fn is_truncated(packet_buf: &[u8]) -> bool {
+  packet_buf.len() > 2 && packet_buf[2] & 0x02 != 0
+}
+
+#[derive(Clone, Copy)]
+/// Transport protocol to exchange messages with the first hop
+enum Transport {
+  Udp,
+  Tcp,
+}
+
+/// Send the packet and receive the response over the transport, where the socket is bound as given
+async fn exchange(
+  transport: Transport,
+  binding: &SocketBindingConfig,
+  dest: SocketAddr,
+  packet: &[u8],
+) -> Result<Vec<u8>> {
+  match transport {
+    Transport::Udp => {
+      let socket = bind_udp(binding, dest)?;
+      socket.connect(dest).await?;
+      socket.send(packet).await?;
+      let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+      let len = socket.recv(&mut buf).await?;
+      buf.truncate(len);
+      Ok(buf)
+    }
+    Transport::Tcp => {
+      let mut stream = connect_tcp(binding, dest).await?;
+      let mut buf = Vec::with_capacity(2 + packet.len());
+      buf.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+      buf.extend_from_slice(packet);
+      stream.write_all(&buf).await?;
+      let len = stream.read_u16().await? as usize;
+      let mut buf = vec![0u8; len];
+      stream.read_exact(&mut buf).await?;
+      Ok(buf)
+    }
+  }
+}
+
+/// Key of certificates, i.e., the server address and the provider name
+type CertKey = (SocketAddr, String);
+
+/// Certificate cached with the time when it was fetched
+struct CertEntry {
+  cert: Arc<DnsCryptCert>,
+  fetched_at: Instant,
+}
+
+/// DNSCrypt client sending encrypted queries to servers directly or via Anonymized DNSCrypt relays.
+/// Certificates of servers are fetched on demand via the same path as queries, and refreshed when they get stale.
+pub(super) struct DnsCryptClient {
+  /// certificates keyed by the server address and the provider name
+  certs: RwLock<HashMap<CertKey, CertEntry>>,
+  /// locks keyed as certificates, serializing fetches so that concurrent queries to the same server are coalesced
+  /// while those to other servers are not blocked
+  fetch_locks: RwLock<HashMap<CertKey, Arc<Mutex<()>>>>,
+  /// timeout of a query including the fallback to tcp
+  timeout_sec: Duration,
+  /// source address, interface and fwmark of sockets to servers and relays
+  binding: SocketBindingConfig,
+}
+
+impl DnsCryptClient {
+  /// Create a new client after checking targets and relays are given in stamps of dnscrypt servers and relays
+  pub fn try_new(
+    target_urls: &[Url],
+    dnscrypt_config: &DnsCryptConfig,
+    timeout_sec: Duration,
+    binding: &SocketBindingConfig,
+  ) -> Result<Self> {
+    if target_urls.is_empty() {
+      return Err(DapError::InvalidDnsStamp("no dnscrypt server is given".to_string()));
+    }
+    for url in target_urls {
+      let DnsStamp::DnsCrypt(_) = DnsStamp::try_from(url)? else {
+        return Err(DapError::InvalidDnsStamp(format!("{url} is not a dnscrypt server")));
+      };
+    }
+    for url in dnscrypt_config.relay_urls.iter() {
+      let DnsStamp::DnsCryptRelay(_) = DnsStamp::try_from(url)? else {
+        return Err(DapError::InvalidDnsStamp(format!("{url} is not a dnscrypt relay")));
+      };
+    }
+    Ok(Self {
+      certs: RwLock::new(HashMap::default()),
+      fetch_locks: RwLock::new(HashMap::default()),
+      timeout_sec,
+      binding: binding.clone(),
+    })
+  }
+
+  /// Make a query along the path, where the target is a DNSCrypt server and the relay is an Anonymized DNSCrypt relay
+  pub async fn query(&self, packet_buf: &[u8], path: &DoHPath) -> Result<Vec<u8>> {
+    let DnsStamp::DnsCrypt(server) = DnsStamp::try_from(&path.target().url())? else {
+      return Err(DapError::InvalidDnsStamp("target is not a dnscrypt server".to_string()));
+    };
+    let relay = match path.nexthop_url().map(|url| DnsStamp::try_from(&url)).transpose()? {
+      Some(DnsStamp::DnsCryptRelay(addr)) => Some(addr),
+      Some(_) => return Err(DapError::InvalidDnsStamp("relay is not a dnscrypt relay".to_string())),
+      None => None,
+    };
+    timeout(self.timeout_sec, self.query_inner(packet_buf, &server, relay))
+      .await
+      .map_err(|_| {
+        error!("DNSCrypt query timed out");
+        DapError::DoHQueryError
+      })?
+  }
+
+  /// Make a query over udp, falling back to tcp if the response is truncated
+  async fn query_inner(&self, packet_buf: &[u8], server: &DnsCryptStamp, relay: Option<SocketAddr>) -> Result<Vec<u8>> {
+    let cert = self.cert(server, relay).await?;
+    for (transport, min_len) in [(Transport::Udp, DNSCRYPT_MIN_QUERY_LEN), (Transport::Tcp, 0)] {
+      let (query, state) = cert.encrypt_query(packet_buf, min_len)?;
+      let (dest, payload) = first_hop(server.addr, relay, &query);
+      let response = exchange(transport, &self.binding, dest, &payload).await?;
+      let response = state.decrypt_response(&response)?;
+      if !is_truncated(&response) {
+        return Ok(response);
+      }
+      debug!("[DNSCrypt] Truncated response, retry over tcp");
+    }
+    Err(DapError::DnsCryptError("truncated response over tcp".to_string()))
+  }
+
+  /// Get a valid certificate of the server, which is fetched if not cached or stale
+  async fn cert(&self, server: &DnsCryptStamp, relay: Option<SocketAddr>) -> Result<Arc<DnsCryptCert>> {
+    let key = (server.addr, server.provider_name.clone());
+    let is_fresh = |entry: &CertEntry| {
+      entry.cert.is_valid_at(unix_now()) && entry.fetched_at.elapsed() < Duration::from_secs(DNSCRYPT_CERT_REFRESH_SEC)
+    };
+    if let Some(entry) = self.certs.read().await.get(&key).filter(|v| is_fresh(v)) {
+      return Ok(entry.cert.clone());
+    }
+
+    let fetch_lock = self.fetch_locks.write().await.entry(key.clone()).or_default().clone();
+    let _lock = fetch_lock.lock().await;
+    // fetched by another query while waiting
+    if let Some(entry) = self.certs.read().await.get(&key).filter(|v| is_fresh(v)) {
+      return Ok(entry.cert.clone());
+    }
+    match self.fetch_cert(server, relay).await {
+      Ok(cert) => {
+        info!(
+          "[DNSCrypt] Update certificate of {} ({}): serial {}",
+          server.provider_name, server.addr, cert.serial
+        );
+        let cert = Arc::new(cert);
+        let entry = CertEntry {
+          cert: cert.clone(),
+          fetched_at: Instant::now(),
+        };
+        self.certs.write().await.insert(key, entry);
+        Ok(cert)
+      }
+      Err(e) => {
+        // keep using the last certificate while it is valid
+        let certs = self.certs.read().await;
+        let Some(entry) = certs.get(&key).filter(|v| v.cert.is_valid_at(unix_now())) else {
+          return Err(e);
+        };
+        warn!(
+          "[DNSCrypt] Failed to refresh certificate of {}, keep the last one: {e}",
+          server.provider_name
+        );
+        Ok(entry.cert.clone())
+      }
+    }
+  }
+
+  /// Fetch certificates in TXT records of the provider name from the server, via the relay if given
+  async fn fetch_cert(&self, server: &DnsCryptStamp, relay: Option<SocketAddr>) -> Result<DnsCryptCert> {
+    debug!(
+      "Fetching DNSCrypt certificate of {} from {}",
+      server.provider_name, server.addr
+    );
+    let fqdn = format!("{}.", server.provider_name.trim_end_matches('.'));
+    let query_msg = dns_message::build_query(&fqdn, RecordType::TXT)?;
+    let packet_buf = dns_message::encode(&query_msg)?;
+    let (dest, payload) = first_hop(server.addr, relay, &packet_buf);
+
+    let mut response = exchange(Transport::Udp, &self.binding, dest, &payload).await?;
+    if is_truncated(&response) {
+      response = exchange(Transport::Tcp, &self.binding, dest, &payload).await?;
+    }
+    let response_msg =
+      dns_message::is_response(&response).map_err(|e| DapError::DnsCryptCertFetchError(e.to_string()))?;
+    if response_msg.id() != query_msg.id() {
+      return Err(DapError::DnsCryptCertFetchError("id mismatch".to_string()));
+    }
+    let certs = response_msg
+      .answers()
+      .iter()
+      .filter_map(|record| match record.data() {
+        Some(RData::TXT(txt)) => Some(txt.txt_data().concat()),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    select_cert(&certs, &server.provider_public_key, unix_now())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ed25519_dalek::{Signer, SigningKey};
+
+  fn cert_bytes(key: &SigningKey, resolver_public_key: &PublicKey, serial: u32, ts: (u32, u32)) -> Vec<u8> {
+    let mut signed = resolver_public_key.as_bytes().to_vec();
+    signed.extend_from_slice(b"clientmg");
+    signed.extend_from_slice(&serial.to_be_bytes());
+    signed.extend_from_slice(&ts.0.to_be_bytes());
+    signed.extend_from_slice(&ts.1.to_be_bytes());
+    let mut bytes = CERT_MAGIC.to_vec();
+    bytes.extend_from_slice(&ES_VERSION_XCHACHA20POLY1305.to_be_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(&key.sign(&signed).to_bytes());
+    bytes.extend_from_slice(&signed);
+    bytes
+  }
+
+  #[test]
+  fn cert_selection_works() {
+    let provider_key = SigningKey::from_bytes(&[7u8; 32]);
+    let provider_public_key = provider_key.verifying_key().to_bytes();
+    let resolver_public_key = SecretKey::from([1u8; 32]).public_key();
+    let now = 1_700_000_000;
+    let certs = vec![
+      cert_bytes(&provider_key, &resolver_public_key, 1, (now - 100, now + 100)),
+      cert_bytes(&provider_key, &resolver_public_key, 2, (now - 100, now + 100)),
+      // expired, not yet valid, and signed by another key
+      cert_bytes(&provider_key, &resolver_public_key, 3, (now - 200, now - 100)),
+      cert_bytes(&provider_key, &resolver_public_key, 4, (now + 100, now + 200)),
+      cert_bytes(
+        &SigningKey::from_bytes(&[8u8; 32]),
+        &resolver_public_key,
+        5,
+        (now - 100, now + 100),
+      ),
+    ];
+    let cert = select_cert(&certs, &provider_public_key, now).unwrap();
+    assert_eq!(cert.serial, 2);
+    assert_eq!(&cert.client_magic, b"clientmg");
+    assert_eq!(&cert.resolver_public_key, resolver_public_key.as_bytes());
+
+    let mut tampered = certs[1].clone();
+    tampered[CERT_LEN - 1] ^= 1;
+    assert!(select_cert(&[tampered], &provider_public_key, now).is_err());
+    assert!(select_cert(&certs[2..], &provider_public_key, now).is_err());
+  }
+
+  #[test]
+  fn query_encryption_works() {
+    let resolver_secret_key = SecretKey::from([1u8; 32]);
+    let cert = DnsCryptCert {
+      resolver_public_key: *resolver_secret_key.public_key().as_bytes(),
+      client_magic: *b"clientmg",
+      serial: 1,
+      ts_start: 0,
+      ts_end: u32::MAX,
+    };
+    let packet_buf = vec![0x12u8; 45];
+    let (query, state) = cert.encrypt_query(&packet_buf, DNSCRYPT_MIN_QUERY_LEN).unwrap();
+    assert_eq!(&query[..8], b"clientmg");
+    assert_eq!(query.len(), 8 + 32 + HALF_NONCE_LEN + TAG_LEN + DNSCRYPT_MIN_QUERY_LEN);
+
+    // decrypt the query and encrypt the response as the server does
+    let client_public_key = PublicKey::from(<[u8; 32]>::try_from(&query[8..40]).unwrap());
+    let server_box = ChaChaBox::new(&client_public_key, &resolver_secret_key);
+    let client_nonce: [u8; HALF_NONCE_LEN] = query[40..52].try_into().unwrap();
+    let mut buf = query[52 + TAG_LEN..].to_vec();
+    server_box
+      .decrypt_in_place_detached(
+        &full_nonce(&client_nonce, &[0u8; HALF_NONCE_LEN]),
+        b"",
+        &mut buf,
+        GenericArray::from_slice(&query[52..52 + TAG_LEN]),
+      )
+      .unwrap();
+    assert_eq!(unpad(buf).unwrap(), packet_buf);
+
+    let response_buf = vec![0x34u8; 100];
+    let server_nonce = [9u8; HALF_NONCE_LEN];
+    let nonce = full_nonce(&client_nonce, &server_nonce);
+    let mut buf = pad(&response_buf, 0);
+    let tag = server_box.encrypt_in_place_detached(&nonce, b"", &mut buf).unwrap();
+    let mut response = RESOLVER_MAGIC.to_vec();
+    response.extend_from_slice(&nonce);
+    response.extend_from_slice(&tag);
+    response.extend_from_slice(&buf);
+    assert_eq!(state.decrypt_response(&response).unwrap(), response_buf);
+
+    // tampered or unrelated responses
+    let mut tampered = response.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(state.decrypt_response(&tampered).is_err());
+    let mut other_nonce = response.clone();
+    other_nonce[RESOLVER_MAGIC.len()] ^= 1;
+    assert!(state.decrypt_response(&other_nonce).is_err());
+  }
+
+  #[test]
+  fn padding_and_anonymization_work() {
+    assert_eq!(pad(&[1u8; 63], 0).len(), 64);
+    assert_eq!(pad(&[1u8; 64], 0).len(), 128);
+    assert_eq!(pad(&[1u8; 64], 256).len(), 256);
+    assert_eq!(unpad(pad(&[1u8, 0x80, 0], 0)).unwrap(), vec![1u8, 0x80, 0]);
+    assert!(unpad(vec![1u8, 0, 0]).is_err());
+
+    let server: SocketAddr = "192.0.2.1:8443".parse().unwrap();
+    let relay: SocketAddr = "198.51.100.1:443".parse().unwrap();
+    assert_eq!(first_hop(server, None, b"query"), (server, b"query".to_vec()));
+    let (dest, payload) = first_hop(server, Some(relay), b"query");
+    assert_eq!(dest, relay);
+    assert_eq!(payload[..12], ANON_MAGIC);
+    assert_eq!(
+      payload[12..28],
+      [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]
+    );
+    assert_eq!(payload[28..30], 8443u16.to_be_bytes());
+    assert_eq!(&payload[30..], b"query");
+  }
+}
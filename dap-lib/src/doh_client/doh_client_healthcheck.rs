@@ -1,18 +1,26 @@
-use super::{dns_message, path_manage::DoHPath, DoHClient};
-use crate::{
-  constants::{HEALTHCHECK_TARGET_ADDR, HEALTHCHECK_TARGET_FQDN},
-  error::*,
-  log::*,
+use super::{
+  dns_message,
+  healthcheck_probe::HealthCheckProbe,
+  healthcheck_schedule::HealthCheckSchedule,
+  path_manage::{DoHPath, HopStatus},
+  DoHClient,
 };
-use futures::future::join_all;
-use hickory_proto::op::response_code::ResponseCode;
+use crate::{constants::HEALTHCHECK_MIN_SLEEP_SEC, error::*, log::*};
+use futures::{
+  future::join_all,
+  stream::{FuturesUnordered, StreamExt},
+};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use std::sync::Arc;
-use tokio::sync::Notify;
+use tokio::{
+  sync::Notify,
+  time::{sleep_until, Duration, Instant},
+};
 
 impl DoHClient {
   /// Start health check service
   pub async fn start_healthcheck_service(&self, term_notify: Option<Arc<Notify>>) -> Result<()> {
-    info!("Start periodic path health check service with cache purge");
+    info!("Start path health check scheduler with periodic cache purge");
     match term_notify {
       Some(term) => {
         tokio::select! {
@@ -32,85 +40,134 @@ impl DoHClient {
     Ok(())
   }
 
-  /// Health check service periodically executes
-  /// - health of every path;
-  /// - purge expired DNS cache
+  /// Health check service executes
+  /// - health check of every direct path and intermediate relay, scheduled with bounded concurrency and jitter,
+  ///   where unhealthy ones are re-probed with exponential backoff. Checks are started as soon as paths fall due and
+  ///   a slot is free, and each result is applied as soon as it completes;
+  /// - periodic purge of expired DNS cache
   async fn healthcheck_service(&self) -> Result<()> {
-    // purge expired DNS cache
+    let policy = &self.healthcheck_schedule_policy;
+    let max_concurrency = policy.max_concurrency.max(1);
+    let mut schedules: HashMap<String, HealthCheckSchedule> = HashMap::default();
+    let mut next_purge = Instant::now();
+    let check = |key: String, path: Arc<DoHPath>| async move {
+      if let Err(e) = self.healthcheck(&path).await {
+        warn!("Healthcheck fails for {key}: {e}");
+      }
+      (key, path)
+    };
+    let mut running = FuturesUnordered::new();
+    let mut running_keys: HashSet<String> = HashSet::default();
+    let mut last_hop_status = HopStatus::default();
+    let mut all_unhealthy = false;
+
     loop {
-      info!("Execute periodic health check");
-      let cache_clone = self.cache.clone();
-      self.runtime_handle.spawn(async move {
-        let purged = cache_clone.purge_expired_entries().await;
-        debug!("Purged {} expired entries from cache", purged);
-      });
+      let now = Instant::now();
 
-      // health check for every path
-      let futures = self
-        .path_manager
-        .paths
-        .iter()
-        .flatten()
-        .flatten()
-        .map(|path| async move {
-          if let Err(e) = self.healthcheck(path).await {
-            warn!("Healthcheck fails for {}: {e}", path.as_url()?)
-          }
-          Ok(()) as Result<()>
+      // purge expired DNS cache
+      if next_purge <= now {
+        let cache_clone = self.cache.clone();
+        self.runtime_handle.spawn(async move {
+          let purged = cache_clone.purge_expired_entries().await;
+          debug!("Purged {} expired entries from cache", purged);
         });
-      let _ = join_all(futures).await;
+        next_purge = now + policy.period;
+      }
+
+      // start checks of paths due to be checked up to the max concurrency, where newly found paths are scheduled
+      let path_manager = self.path_manager();
+      let paths = path_manager.healthcheck_paths();
+      schedules.retain(|key, _| paths.iter().any(|(k, _)| k == key));
+      for (key, path) in paths.into_iter() {
+        if running.len() >= max_concurrency {
+          break;
+        }
+        let schedule = schedules
+          .entry(key.clone())
+          .or_insert_with(|| HealthCheckSchedule::new(now, policy));
+        if running_keys.contains(&key) || !schedule.is_due(now) {
+          continue;
+        }
+        debug!("Execute health check for {key}");
+        running_keys.insert(key.clone());
+        running.push(check(key, path));
+      }
+
+      // wait for the completion of a check, or the next scheduled check or cache purge if a slot is free
+      let next_at = match running.len() < max_concurrency {
+        true => schedules
+          .iter()
+          .filter(|(key, _)| !running_keys.contains(*key))
+          .map(|(_, schedule)| schedule.next_at())
+          .min()
+          .unwrap_or(next_purge)
+          .min(next_purge),
+        false => next_purge,
+      };
+      let next_at = next_at.max(now + Duration::from_secs(HEALTHCHECK_MIN_SLEEP_SEC));
+      let (key, path) = tokio::select! {
+        Some(result) = running.next(), if !running.is_empty() => result,
+        _ = sleep_until(next_at) => continue,
+      };
 
-      if !self
-        .path_manager
-        .paths
-        .iter()
-        .flatten()
-        .flatten()
-        .any(|v| v.is_healthy())
-      {
-        error!("All possible paths are unhealthy. Should check the Internet connection");
+      // apply the result, and localize faulty targets and relays
+      running_keys.remove(&key);
+      if let Some(schedule) = schedules.get_mut(&key) {
+        schedule.reschedule(path.is_healthy(), Instant::now(), policy);
       }
-      tokio::time::sleep(self.healthcheck_period_sec).await;
+      let hop_status = path_manager.update_hop_status(&[path]);
+      if hop_status.has_suspects() && hop_status != last_hop_status {
+        warn!("Hops suspected to be down: {hop_status}");
+      }
+      last_hop_status = hop_status;
+      let has_healthy_path = path_manager.has_healthy_path();
+      match (has_healthy_path, all_unhealthy) {
+        (false, false) => error!("All possible paths are unhealthy. Should check the Internet connection"),
+        (true, true) => info!("Some paths are healthy again"),
+        _ => (),
+      }
+      all_unhealthy = !has_healthy_path;
     }
   }
 
+  /// Get the health check probe applied to the given path.
+  /// The first probe explicitly covering the target is preferred to the default one.
+  fn healthcheck_probe(&self, path: &Arc<DoHPath>) -> Option<&HealthCheckProbe> {
+    let target = path.target();
+    self
+      .healthcheck_probes
+      .iter()
+      .find(|probe| !probe.is_default() && probe.applies_to(target))
+      .or_else(|| self.healthcheck_probes.iter().find(|probe| probe.is_default()))
+  }
+
   /// Check health for a given path, and update health status for the path.
   async fn healthcheck(&self, path: &Arc<DoHPath>) -> Result<()> {
-    let q_msg = dns_message::build_query_a(HEALTHCHECK_TARGET_FQDN)?;
-    let packet_buf = dns_message::encode(&q_msg)?;
-
-    let Ok((_, res_msg)) = self.make_doh_query_inner(&packet_buf, path).await else {
-      path.make_unhealthy();
-      warn!(
-        "Failed to query or invalid response. Path {} is unhealthy",
-        path.as_url()?
-      );
+    let Some(probe) = self.healthcheck_probe(path) else {
+      debug!("No health check probe is defined for {}", path.as_url()?);
       return Ok(());
     };
 
-    if res_msg.header().response_code() != ResponseCode::NoError {
-      path.make_unhealthy();
-      warn!("Response is not Ok. Path {} is unhealthy", path.as_url()?);
-      return Ok(());
-    }
-
-    let answers = res_msg.answers();
-    if answers.is_empty() {
-      path.make_unhealthy();
-      warn!("Response has no answer. Path {} is unhealthy", path.as_url()?);
-
-      return Ok(());
-    }
-
-    let target_addr_contains = answers
-      .iter()
-      .filter_map(|answer| answer.data())
-      .any(|v| v.to_string() == HEALTHCHECK_TARGET_ADDR);
+    let futures = (0..probe.probes).map(|_| async {
+      let q_msg = probe.build_query()?;
+      let packet_buf = dns_message::encode(&q_msg)?;
+      let (_, res_msg) = self.make_doh_query_inner(&packet_buf, path).await?;
+      probe.evaluate(&res_msg).map_err(|e| anyhow!(e))?;
+      Ok(()) as Result<()>
+    });
+    let results = join_all(futures).await;
+    let successes = results.iter().filter(|res| res.is_ok()).count();
 
-    if !target_addr_contains {
+    if successes < probe.success_threshold {
       path.make_unhealthy();
+      let reason = results
+        .into_iter()
+        .find_map(|res| res.err())
+        .map(|e| e.to_string())
+        .unwrap_or_default();
       warn!(
-        "Response has no or wrong rdata. Maybe suspicious and polluted target. Path {} is unhealthy.",
+        "{successes}/{} probes succeeded ({reason}). Path {} is unhealthy",
+        probe.probes,
         path.as_url()?
       );
       return Ok(());
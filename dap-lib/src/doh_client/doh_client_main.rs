@@ -1,16 +1,24 @@
 use super::{
   cache::Cache,
+  directory::DirectoryWatcher,
   dns_message::{self, Request},
+  dnscrypt::DnsCryptClient,
+  healthcheck_probe::HealthCheckProbe,
+  healthcheck_schedule::HealthCheckSchedulePolicy,
+  inflight::{InflightQueries, InflightRole},
   manipulation::{QueryManipulationResult, QueryManipulators},
-  odoh_config_store::ODoHConfigStore,
-  path_manage::{DoHPath, DoHPathManager},
+  oblivious_http::{self, OHttpKeyConfigs},
+  odoh_config_store::{ODoHConfigStore, ResolveRecords},
+  path_manage::{DoHPath, DoHPathManager, HopStatus, PathSetUpdate},
+  path_update::PathSetUpdater,
   DoHMethod, DoHType,
 };
 use crate::{
   auth::Authenticator,
+  constants::EDNS_PADDING_QUERY_BLOCK_SIZE,
   error::*,
-  globals::Globals,
-  http_client::HttpClientInner,
+  globals::{Globals, ODoHPaddingPolicy},
+  http_client::{HttpClient, HttpClientInner},
   log::*,
   trait_resolve_ips::{ResolveIpResponse, ResolveIps},
 };
@@ -18,22 +26,38 @@ use async_trait::async_trait;
 use data_encoding::BASE64URL_NOPAD;
 use hickory_proto::op::Message;
 use reqwest::header::{self, HeaderMap};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::RwLock;
+use std::{
+  net::SocketAddr,
+  sync::{Arc, Weak},
+};
+use tokio::{
+  sync::RwLock,
+  time::{timeout_at, Duration, Instant},
+};
 use url::Url;
 
-/// DoH, ODoH, MODoH client
+/// DoH, ODoH, MODoH, OHTTP and DNSCrypt client
 pub struct DoHClient {
   /// http client to make doh query
   http_client: Arc<RwLock<HttpClientInner>>,
   /// auth_client to retrieve id token
   auth_client: Option<Arc<Authenticator>>,
   /// path candidates with health flags
-  pub(super) path_manager: Arc<DoHPathManager>,
+  path_manager: Arc<DoHPathManager>,
+  /// updater of the path set at runtime
+  path_updater: Arc<PathSetUpdater>,
   /// odoh config store
   odoh_configs: Option<Arc<ODoHConfigStore>>,
+  /// padding policy of odoh plaintext queries
+  odoh_padding: ODoHPaddingPolicy,
+  /// key configs of the ohttp gateway
+  ohttp_key_configs: Option<Arc<OHttpKeyConfigs>>,
+  /// dnscrypt client with certificates of servers
+  dnscrypt_client: Option<DnsCryptClient>,
   /// DNS cache
   pub(super) cache: Arc<Cache>,
+  /// in-flight upstream queries to coalesce concurrent cache misses
+  inflight: InflightQueries,
   /// DoH type
   doh_type: DoHType,
   /// DoH method
@@ -42,21 +66,27 @@ pub struct DoHClient {
   headers: header::HeaderMap,
   /// runtime handle
   pub(super) runtime_handle: tokio::runtime::Handle,
-  /// health check interval
-  pub(super) healthcheck_period_sec: tokio::time::Duration,
+  /// deadline of a query, within which an ODoH query is resent after key rotation
+  http_timeout_sec: Duration,
+  /// health check scheduling policy
+  pub(super) healthcheck_schedule_policy: HealthCheckSchedulePolicy,
+  /// health check probes
+  pub(super) healthcheck_probes: Vec<HealthCheckProbe>,
   /// Query manipulation pulugins
   query_manipulators: Option<QueryManipulators>,
 }
 
 impl DoHClient {
-  /// Create a new DoH client
+  /// Create a new DoH client, which is shared with the ODoH config store to resolve HTTPS records
   pub async fn new(
     globals: Arc<Globals>,
-    http_client: Arc<RwLock<HttpClientInner>>,
+    http_client_base: Arc<HttpClient>,
     auth_client: Option<Arc<Authenticator>>,
-  ) -> Result<Self> {
+  ) -> Result<Arc<Self>> {
+    let http_client = http_client_base.inner();
+
     // 1. build all path candidates from globals
-    let path_manager = Arc::new(DoHPathManager::new(&globals)?);
+    let path_manager = Arc::new(DoHPathManager::new(&globals.proxy_config)?);
 
     // 2. spawn odoh config service if odoh or modoh are enabled
     let odoh_configs = match &globals.proxy_config.nexthop_relay_config {
@@ -64,7 +94,14 @@ impl DoHClient {
         if nexthop_relay_config.odoh_relay_urls.is_empty() {
           return Err(DapError::ODoHNoRelayUrl);
         }
-        let odoh_configs = Arc::new(ODoHConfigStore::new(http_client.clone(), &path_manager.targets()).await?);
+        let odoh_configs = Arc::new(
+          ODoHConfigStore::new(
+            http_client.clone(),
+            &path_manager.targets(),
+            nexthop_relay_config.odoh_config_via_dns,
+          )
+          .await?,
+        );
         let odoh_config_clone = odoh_configs.clone();
         let term_notify = globals.term_notify.clone();
         globals
@@ -74,6 +111,64 @@ impl DoHClient {
       }
       None => None,
     };
+    // spawn ohttp key config service if oblivious http is enabled
+    let ohttp_key_configs = match &globals.proxy_config.oblivious_http_config {
+      Some(oblivious_http_config) => {
+        if oblivious_http_config.relay_urls.is_empty() {
+          return Err(DapError::ODoHNoRelayUrl);
+        }
+        let ohttp_key_configs =
+          Arc::new(OHttpKeyConfigs::new(http_client.clone(), &oblivious_http_config.gateway_key_url).await?);
+        let ohttp_key_configs_clone = ohttp_key_configs.clone();
+        let term_notify = globals.term_notify.clone();
+        globals
+          .runtime_handle
+          .spawn(async move { ohttp_key_configs_clone.start_service(term_notify).await });
+        Some(ohttp_key_configs)
+      }
+      None => None,
+    };
+    // dnscrypt client if dnscrypt is enabled, where targets and relays are given in stamps
+    let dnscrypt_client = globals
+      .proxy_config
+      .dnscrypt_config
+      .as_ref()
+      .map(|dnscrypt_config| {
+        DnsCryptClient::try_new(
+          &globals.proxy_config.target_config.doh_target_urls,
+          dnscrypt_config,
+          globals.proxy_config.http_timeout_sec,
+          &globals.proxy_config.upstream_binding,
+        )
+      })
+      .transpose()?;
+    let path_updater = Arc::new(PathSetUpdater::new(
+      path_manager.clone(),
+      odoh_configs.clone(),
+      http_client_base,
+    ));
+
+    // 3. spawn directory watch service if the signed directory is given
+    if let Some(directory_config) = &globals.proxy_config.directory_config {
+      let directory_watcher = DirectoryWatcher::new(
+        directory_config,
+        &globals.proxy_config,
+        http_client.clone(),
+        path_manager.clone(),
+        path_updater.clone(),
+      )?;
+      let term_notify = globals.term_notify.clone();
+      globals
+        .runtime_handle
+        .spawn(async move { directory_watcher.start_service(term_notify).await });
+    }
+
+    let odoh_padding = globals
+      .proxy_config
+      .nexthop_relay_config
+      .as_ref()
+      .map(|v| v.odoh_padding.clone())
+      .unwrap_or(ODoHPaddingPolicy::None);
 
     // doh type
     let doh_type = match &globals.proxy_config.nexthop_relay_config {
@@ -84,14 +179,16 @@ impl DoHClient {
           DoHType::Oblivious
         }
       }
+      None if globals.proxy_config.oblivious_http_config.is_some() => DoHType::ObliviousHttp,
+      None if globals.proxy_config.dnscrypt_config.is_some() => DoHType::DnsCrypt,
       None => DoHType::Standard,
     };
     // base headers except for authorization
     let mut headers = header::HeaderMap::new();
     let ct = doh_type.as_str();
-    headers.insert("Accept", header::HeaderValue::from_str(&ct).unwrap());
+    headers.insert("Accept", header::HeaderValue::from_str(&doh_type.accept_str()).unwrap());
     headers.insert("Content-Type", header::HeaderValue::from_str(&ct).unwrap());
-    if let DoHType::Oblivious = doh_type {
+    if let DoHType::Oblivious | DoHType::ObliviousHttp = doh_type {
       headers.insert(
         "Cache-Control",
         header::HeaderValue::from_str("no-cache, no-store").unwrap(),
@@ -107,17 +204,30 @@ impl DoHClient {
           DoHMethod::Post
         }
       }
-      DoHType::Oblivious => DoHMethod::Post,
+      DoHType::Oblivious | DoHType::ObliviousHttp | DoHType::DnsCrypt => DoHMethod::Post,
     };
 
     // cache
     let cache = Arc::new(Cache::new(globals.proxy_config.max_cache_size));
+    let inflight = InflightQueries::new();
 
     // runtime handle
     let runtime_handle = globals.runtime_handle.clone();
 
-    // health check period
-    let healthcheck_period_sec = globals.proxy_config.healthcheck_period_sec;
+    // health check scheduling policy
+    let healthcheck_schedule_policy = HealthCheckSchedulePolicy {
+      period: globals.proxy_config.healthcheck_period_sec,
+      initial_backoff: globals.proxy_config.healthcheck_initial_backoff_sec,
+      max_concurrency: globals.proxy_config.healthcheck_max_concurrency,
+    };
+
+    // health check probes
+    let healthcheck_probes = globals
+      .proxy_config
+      .healthcheck_configs
+      .iter()
+      .map(HealthCheckProbe::try_from)
+      .collect::<Result<Vec<_>>>()?;
 
     // query manipulators
     let query_manipulators: Option<QueryManipulators> = if let Some(q) = &globals.proxy_config.query_manipulation_config
@@ -127,19 +237,31 @@ impl DoHClient {
       None
     };
 
-    Ok(Self {
-      http_client,
-      auth_client,
-      path_manager,
-      odoh_configs,
-      cache,
-      doh_type,
-      doh_method,
-      headers,
-      runtime_handle,
-      healthcheck_period_sec,
-      query_manipulators,
-    })
+    Ok(Arc::new_cyclic(|weak: &Weak<Self>| {
+      if let Some(odoh_configs) = &odoh_configs {
+        odoh_configs.set_record_resolver(weak.clone());
+      }
+      Self {
+        http_client,
+        auth_client,
+        path_manager,
+        path_updater,
+        odoh_configs,
+        odoh_padding,
+        ohttp_key_configs,
+        dnscrypt_client,
+        cache,
+        inflight,
+        doh_type,
+        doh_method,
+        headers,
+        runtime_handle,
+        http_timeout_sec: globals.proxy_config.http_timeout_sec,
+        healthcheck_schedule_policy,
+        healthcheck_probes,
+        query_manipulators,
+      }
+    }))
   }
 
   /// Make DoH query with intended automatic path selection.
@@ -179,8 +301,21 @@ impl DoHClient {
       }
     }
 
+    // Coalesce with an identical in-flight upstream query if exists
+    let leader = match self.inflight.join(&req) {
+      InflightRole::Leader(leader) => leader,
+      InflightRole::Follower(follower) => {
+        debug!("Coalesced with in-flight query: {:?}", req.0[0].query_name);
+        let Some(response_buf) = follower.wait().await else {
+          return Err(DapError::CoalescedQueryFailed);
+        };
+        return Ok(dns_message::replace_id(&response_buf, query_id)?);
+      }
+    };
+
     // choose path
-    let Some(path) = self.path_manager.get_path() else {
+    // note that followers are released with failure when the leader is dropped on error
+    let Some(path) = self.path_manager().get_path() else {
       return Err(DapError::NoPathAvailable);
     };
 
@@ -192,10 +327,29 @@ impl DoHClient {
       error!("Failed to cache a DNS response");
     };
 
+    // fan out the response to coalesced queries
+    leader.complete(&response_buf);
+
     // should rebuild buffer from decoded dns response_msg? -> no need to do that.
     Ok(response_buf)
   }
 
+  /// Get status of hops (targets and relays) inferred from the health of paths
+  pub fn hop_status(&self) -> HopStatus {
+    self.path_manager().hop_status()
+  }
+
+  /// Get the path manager
+  pub(super) fn path_manager(&self) -> Arc<DoHPathManager> {
+    self.path_manager.clone()
+  }
+
+  /// Add or remove targets, next-hop relays and intermediate relays at runtime.
+  /// The path set is swapped atomically, and ODoH configs and endpoint ip addresses of new hops are fetched.
+  pub async fn update_path_set(&self, update: &PathSetUpdate) -> Result<()> {
+    self.path_updater.apply(update).await
+  }
+
   /// Make DoH query with a specifically given path.
   /// Note cache and plugins are disabled to be used for health check
   pub(super) async fn make_doh_query_inner(
@@ -204,35 +358,64 @@ impl DoHClient {
     path: &Arc<DoHPath>,
   ) -> Result<(Vec<u8>, Message)> {
     let headers = self.build_headers().await?;
+    let mut edns_added = false;
     let response_buf = match self.doh_type {
-      DoHType::Standard => self.serve_doh_query(packet_buf, path, headers).await,
-      DoHType::Oblivious => self.serve_oblivious_doh_query(packet_buf, path, headers).await,
+      DoHType::Standard => {
+        // pad the query to hide its length from on-path observers, which is done by the odoh padding policy for odoh
+        let mut query_msg = dns_message::decode(packet_buf).map_err(|e| {
+          error!("{e}");
+          DapError::InvalidDnsQuery
+        })?;
+        let (padded_buf, added) = dns_message::pad(&mut query_msg, EDNS_PADDING_QUERY_BLOCK_SIZE)?;
+        edns_added = added;
+        self.serve_doh_query(&padded_buf, path, headers).await
+      }
+      DoHType::Oblivious => {
+        // learn incompatible pairs of relays and targets from persistent rejections
+        let res = self.serve_oblivious_doh_query(packet_buf, path, headers).await;
+        match &res {
+          Ok(_) => self.path_manager().report_acceptance(path),
+          Err(DapError::ODoHQueryRejected(_)) => self.path_manager().report_rejection(path),
+          Err(_) => (),
+        }
+        res
+      }
+      DoHType::ObliviousHttp => self.serve_oblivious_http_query(packet_buf, path, headers).await,
+      DoHType::DnsCrypt => {
+        let Some(dnscrypt_client) = self.dnscrypt_client.as_ref() else {
+          return Err(DapError::DnsCryptNoValidCert);
+        };
+        dnscrypt_client.query(packet_buf, path).await
+      }
     }?;
     // Check if the returned packet buffer is consistent as a DNS response
     // TODO: If error, should we build and return a synthetic reject response message?
-    let response_message = dns_message::is_response(&response_buf).map_err(|e| {
+    let mut response_message = dns_message::is_response(&response_buf).map_err(|e| {
       error!("{e}");
       DapError::InvalidDnsResponse
     })?;
+    // remove edns from the response if it is not requested by the client
+    if edns_added {
+      *response_message.extensions_mut() = None;
+      let response_buf = dns_message::encode(&response_message)?;
+      return Ok((response_buf, response_message));
+    }
     Ok((response_buf, response_message))
   }
 
   //// build headers for doh and odoh query with authorization if needed
   async fn build_headers(&self) -> Result<header::HeaderMap> {
     let mut headers = self.headers.clone();
-    match &self.auth_client {
-      Some(auth) => {
-        debug!("build headers with http authorization header");
-        let token = auth.id_token().await?;
-        let token_str = format!("Bearer {}", &token);
-        headers.insert(
-          header::AUTHORIZATION,
-          header::HeaderValue::from_str(&token_str).unwrap(),
-        );
-        Ok(headers)
-      }
-      None => Ok(headers),
+    if let Some(auth) = &self.auth_client {
+      debug!("build headers with http authorization header");
+      let token = auth.id_token().await?;
+      let token_str = format!("Bearer {}", &token);
+      headers.insert(
+        header::AUTHORIZATION,
+        header::HeaderValue::from_str(&token_str).unwrap(),
+      );
     }
+    Ok(headers)
   }
 
   /// serve doh query
@@ -280,58 +463,184 @@ impl DoHClient {
     debug!("[ODoH] target url: {}", path_url.as_str());
 
     // odoh config
-    if self.odoh_configs.is_none() {
-      return Err(DapError::ODoHNoClientConfig);
-    }
-    let Some(odoh_config) = self.odoh_configs.as_ref().unwrap().get(target_obj).await else {
+    let Some(odoh_configs) = self.odoh_configs.as_ref() else {
       return Err(DapError::ODoHNoClientConfig);
     };
-    let Some(odoh_config) = odoh_config.as_ref() else {
-      return Err(DapError::ODoHNoClientConfig);
+    if let DoHMethod::Get = self.doh_method {
+      return Err(DapError::ODoHGetNotAllowed);
+    }
+
+    // the query is re-encrypted and resent once if the key is rotated, within the deadline
+    let deadline = Instant::now() + self.http_timeout_sec;
+    let mut is_resent = false;
+    loop {
+      let Some(odoh_config) = odoh_configs.get(target_obj).await else {
+        return Err(DapError::ODoHNoClientConfig);
+      };
+
+      // encrypt query
+      let query = odoh_config.encrypt_query(packet_buf, &self.odoh_padding)?;
+
+      let sent_at = Instant::now();
+      let lock = self.http_client.read().await;
+      let response = timeout_at(
+        deadline,
+        lock
+          .post(path_url.clone())
+          .headers(headers.clone())
+          .body(query.body)
+          .send(),
+      )
+      .await
+      .map_err(|_| {
+        error!("ODoH query timed out");
+        DapError::DoHQueryError
+      })??;
+      drop(lock);
+
+      // 401 or len=0 when 200, update doh client with renewed public key
+      let Some(content_length) = response.content_length() else {
+        return Err(DapError::ODoHInvalidContentLength);
+      };
+      if response.status() == reqwest::StatusCode::UNAUTHORIZED
+        || (response.status() == reqwest::StatusCode::OK && content_length == 0)
+      {
+        warn!("ODoH public key of {} is expired. Refetch.", target_obj.authority());
+        odoh_config.reject_key(&query.key_id);
+        if is_resent {
+          error!("DoH query error!: {:?}", response.status());
+          return Err(DapError::DoHQueryError);
+        }
+        // spawned so that the coalesced refetch is not cancelled with this query
+        let (odoh_configs, target) = (odoh_configs.clone(), target_obj.clone());
+        let refetch = self
+          .runtime_handle
+          .spawn(async move { odoh_configs.refetch(&target, sent_at).await });
+        match timeout_at(deadline, refetch).await {
+          Ok(Ok(Ok(_))) => (),
+          Ok(Ok(Err(e))) => {
+            error!("Failed to refetch ODoH config: {e}");
+            return Err(e);
+          }
+          Ok(Err(e)) => {
+            error!("ODoH config refetch task failed: {e}");
+            return Err(DapError::DoHQueryError);
+          }
+          Err(_) => {
+            error!("ODoH config refetch timed out");
+            return Err(DapError::DoHQueryError);
+          }
+        }
+        debug!("[ODoH] Resend the query encrypted with the renewed key");
+        is_resent = true;
+        continue;
+      }
+      if is_rejection(response.status()) {
+        error!("ODoH query rejected!: {:?}", response.status());
+        return Err(DapError::ODoHQueryRejected(response.status()));
+      }
+      if response.status() != reqwest::StatusCode::OK {
+        error!("DoH query error!: {:?}", response.status());
+        return Err(DapError::DoHQueryError);
+      }
+
+      let body = response.bytes().await?;
+      let dec_bytes = odoh_config.decrypt_response(&query.plaintext, &body, query.secret)?;
+
+      return Ok(dec_bytes.to_vec());
+    }
+  }
+
+  /// serve doh query encapsulated in oblivious http via the relay
+  async fn serve_oblivious_http_query(
+    &self,
+    packet_buf: &[u8],
+    ohttp_path: &Arc<DoHPath>,
+    headers: HeaderMap,
+  ) -> Result<Vec<u8>> {
+    let relay_url = ohttp_path.as_url()?;
+    debug!("[OHTTP] relay url: {}", relay_url.as_str());
+    let Some(ohttp_key_configs) = self.ohttp_key_configs.as_ref() else {
+      return Err(DapError::OHttpNoKeyConfig);
     };
 
-    // encrypt query
-    let (odoh_plaintext_query, encrypted_query_body, secret) = odoh_config.encrypt_query(packet_buf)?;
+    // encapsulate binary http request to the target
+    let request = oblivious_http::build_request(packet_buf, ohttp_path.target())?;
 
-    let response = match &self.doh_method {
-      DoHMethod::Get => {
-        return Err(DapError::ODoHGetNotAllowed);
-      }
-      DoHMethod::Post => {
-        let lock = self.http_client.read().await;
+    // the request is re-encapsulated and resent once if the key is rotated, within the deadline
+    let deadline = Instant::now() + self.http_timeout_sec;
+    let mut is_resent = false;
+    loop {
+      let (request_enc, client_response) = ohttp_key_configs.encapsulate(&request).await?;
+
+      let sent_at = Instant::now();
+      let lock = self.http_client.read().await;
+      let response = timeout_at(
+        deadline,
         lock
-          .post(path_url)
-          .headers(headers)
-          .body(encrypted_query_body)
-          .send()
-          .await?
+          .post(relay_url.clone())
+          .headers(headers.clone())
+          .body(request_enc)
+          .send(),
+      )
+      .await
+      .map_err(|_| {
+        error!("OHTTP query timed out");
+        DapError::DoHQueryError
+      })??;
+      drop(lock);
+
+      // 400 from the gateway with the unknown key, update key configs and resend
+      if response.status() == reqwest::StatusCode::BAD_REQUEST && !is_resent {
+        warn!("OHTTP key config of the gateway may be rotated. Refetch.");
+        match timeout_at(deadline, ohttp_key_configs.refetch(sent_at)).await {
+          Ok(Ok(_)) => (),
+          Ok(Err(e)) => {
+            error!("Failed to refetch OHTTP key configs: {e}");
+            return Err(e);
+          }
+          Err(_) => {
+            error!("OHTTP key config refetch timed out");
+            return Err(DapError::DoHQueryError);
+          }
+        }
+        debug!("[OHTTP] Resend the query encapsulated with the renewed key");
+        is_resent = true;
+        continue;
+      }
+      if response.status() != reqwest::StatusCode::OK {
+        error!("OHTTP query error!: {:?}", response.status());
+        return Err(DapError::DoHQueryError);
       }
-    };
 
-    // 401 or len=0 when 200, update doh client with renewed public key
-    let Some(content_length) = response.content_length() else {
-      return Err(DapError::ODoHInvalidContentLength);
-    };
-    if response.status() == reqwest::StatusCode::UNAUTHORIZED
-      || (response.status() == reqwest::StatusCode::OK && content_length == 0)
-    {
-      warn!("ODoH public key is expired. Refetch.");
-      self
-        .odoh_configs
-        .as_ref()
-        .unwrap()
-        .update_odoh_config_from_well_known()
-        .await?;
-    }
-    if response.status() != reqwest::StatusCode::OK {
-      error!("DoH query error!: {:?}", response.status());
-      return Err(DapError::DoHQueryError);
+      let body = response.bytes().await?;
+      let response_dec = client_response.decapsulate(&body)?;
+      return oblivious_http::parse_response(&response_dec);
     }
+  }
+}
 
-    let body = response.bytes().await?;
-    let dec_bytes = odoh_config.decrypt_response(&odoh_plaintext_query, &body, secret)?;
+/// Check if the status code means the rejection of the path by relays, e.g., 403 for a target not allowed.
+/// Client errors caused by authorization, timeout and rate limiting are not regarded as rejection.
+fn is_rejection(status: reqwest::StatusCode) -> bool {
+  status.is_client_error()
+    && !matches!(
+      status,
+      reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::REQUEST_TIMEOUT | reqwest::StatusCode::TOO_MANY_REQUESTS
+    )
+}
 
-    Ok(dec_bytes.to_vec())
+// ResolveRecords for DoHClient
+#[async_trait]
+impl ResolveRecords for DoHClient {
+  /// Resolve records of the given query through the own path
+  async fn resolve_records(&self, query_msg: &Message) -> Result<Message> {
+    let packet_buf = dns_message::encode(query_msg)?;
+    let res = self.make_doh_query(&packet_buf).await?;
+    dns_message::is_response(&res).map_err(|e| {
+      error!("{e}");
+      DapError::InvalidDnsResponse
+    })
   }
 }
 
@@ -0,0 +1,227 @@
+use super::{dns_message, path_manage::DoHTarget};
+use crate::{error::*, HealthCheckAnswer, HealthCheckConfig};
+use hickory_proto::{
+  op::{Message, ResponseCode},
+  rr::RecordType,
+};
+use std::{net::IpAddr, str::FromStr};
+use url::Url;
+
+#[derive(Debug, Clone)]
+/// Health check probe converted from HealthCheckConfig
+pub struct HealthCheckProbe {
+  /// target urls to which this probe applies, `None` means default
+  target_urls: Option<Vec<Url>>,
+  /// query name
+  query_name: String,
+  /// query type
+  query_type: RecordType,
+  /// expected response code
+  expected_rcode: ResponseCode,
+  /// expected answer
+  expected_answer: Option<HealthCheckAnswer>,
+  /// number of probes
+  pub probes: usize,
+  /// success threshold
+  pub success_threshold: usize,
+}
+
+impl TryFrom<&HealthCheckConfig> for HealthCheckProbe {
+  type Error = DapError;
+  fn try_from(config: &HealthCheckConfig) -> Result<Self> {
+    let query_type = RecordType::from_str(&config.query_type.to_ascii_uppercase())
+      .map_err(|e| DapError::InvalidHealthCheckConfig(format!("query type {}: {e}", config.query_type)))?;
+    let expected_rcode = parse_rcode(&config.expected_rcode)?;
+    if config.probes == 0 || config.success_threshold == 0 || config.success_threshold > config.probes {
+      return Err(DapError::InvalidHealthCheckConfig(
+        "success_threshold must be between 1 and the number of probes".to_string(),
+      ));
+    }
+    let mut query_name = config.query_name.clone();
+    if !query_name.ends_with('.') {
+      query_name.push('.');
+    }
+    // check if the query name is valid in advance
+    dns_message::build_query(&query_name, query_type)
+      .map_err(|e| DapError::InvalidHealthCheckConfig(format!("query name {}: {e}", config.query_name)))?;
+
+    Ok(Self {
+      target_urls: config.target_urls.clone(),
+      query_name,
+      query_type,
+      expected_rcode,
+      expected_answer: config.expected_answer.clone(),
+      probes: config.probes,
+      success_threshold: config.success_threshold,
+    })
+  }
+}
+
+impl HealthCheckConfig {
+  /// Validate the definition in the same way as it is converted to the probe, e.g., when the config is loaded
+  pub fn validate(&self) -> Result<()> {
+    HealthCheckProbe::try_from(self).map(|_| ())
+  }
+}
+
+impl HealthCheckProbe {
+  /// Check if this probe applies to the given target
+  pub fn applies_to(&self, target: &DoHTarget) -> bool {
+    match &self.target_urls {
+      None => true,
+      Some(urls) => urls
+        .iter()
+        .any(|url| url.authority() == target.authority() && url.path() == target.path()),
+    }
+  }
+
+  /// Check if this probe is the default one applied to targets not covered by others
+  pub fn is_default(&self) -> bool {
+    self.target_urls.is_none()
+  }
+
+  /// Build a query message for the probe
+  pub fn build_query(&self) -> anyhow::Result<Message> {
+    dns_message::build_query(&self.query_name, self.query_type)
+  }
+
+  /// Evaluate the response message, and returns the reason if it does not satisfy the expectation.
+  pub fn evaluate(&self, res_msg: &Message) -> std::result::Result<(), String> {
+    if res_msg.response_code() != self.expected_rcode {
+      return Err(format!(
+        "Response code is {}, expected {}",
+        res_msg.response_code(),
+        self.expected_rcode
+      ));
+    }
+    let answers = res_msg.answers();
+    match &self.expected_answer {
+      None => Ok(()),
+      Some(HealthCheckAnswer::Any) => {
+        if answers.is_empty() {
+          return Err("Response has no answer".to_string());
+        }
+        Ok(())
+      }
+      Some(HealthCheckAnswer::OneOf(expected)) => {
+        if answers.is_empty() {
+          return Err("Response has no answer".to_string());
+        }
+        let contains = answers
+          .iter()
+          .filter_map(|answer| answer.data())
+          .any(|v| expected.iter().any(|e| rdata_matches(&v.to_string(), e)));
+        if !contains {
+          return Err("Response has no or wrong rdata. Maybe suspicious and polluted target".to_string());
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+/// Compare rdata strings, where ip addresses and domain names are normalized
+fn rdata_matches(rdata: &str, expected: &str) -> bool {
+  if let (Ok(a), Ok(b)) = (rdata.parse::<IpAddr>(), expected.parse::<IpAddr>()) {
+    return a == b;
+  }
+  rdata
+    .trim_end_matches('.')
+    .eq_ignore_ascii_case(expected.trim_end_matches('.'))
+}
+
+/// Parse response code like "NOERROR" or its numeric value
+fn parse_rcode(rcode: &str) -> Result<ResponseCode> {
+  let rcode = match rcode.to_ascii_uppercase().as_str() {
+    "NOERROR" => ResponseCode::NoError,
+    "FORMERR" => ResponseCode::FormErr,
+    "SERVFAIL" => ResponseCode::ServFail,
+    "NXDOMAIN" => ResponseCode::NXDomain,
+    "NOTIMP" => ResponseCode::NotImp,
+    "REFUSED" => ResponseCode::Refused,
+    other => other
+      .parse::<u16>()
+      .map(|v| -> ResponseCode { v.into() })
+      .map_err(|_| DapError::InvalidHealthCheckConfig(format!("response code {rcode}")))?,
+  };
+  Ok(rcode)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hickory_proto::rr::{rdata::A, RData, Record};
+  use std::net::Ipv4Addr;
+
+  fn response(rcode: ResponseCode, addrs: &[Ipv4Addr]) -> Message {
+    let mut msg = dns_message::build_query_a("dns.google.").unwrap();
+    msg.set_message_type(hickory_proto::op::MessageType::Response);
+    msg.set_response_code(rcode);
+    let name = hickory_proto::rr::Name::from_ascii("dns.google.").unwrap();
+    msg.insert_answers(
+      addrs
+        .iter()
+        .map(|a| Record::from_rdata(name.clone(), 60, RData::A(A(*a))))
+        .collect(),
+    );
+    msg
+  }
+
+  #[tokio::test]
+  async fn probe_evaluation_works() {
+    let probe: HealthCheckProbe = (&HealthCheckConfig::default()).try_into().unwrap();
+    assert!(probe
+      .evaluate(&response(ResponseCode::NoError, &[Ipv4Addr::new(8, 8, 8, 8)]))
+      .is_ok());
+    assert!(probe
+      .evaluate(&response(ResponseCode::NoError, &[Ipv4Addr::new(1, 2, 3, 4)]))
+      .is_err());
+    assert!(probe.evaluate(&response(ResponseCode::NoError, &[])).is_err());
+    assert!(probe
+      .evaluate(&response(ResponseCode::ServFail, &[Ipv4Addr::new(8, 8, 8, 8)]))
+      .is_err());
+
+    let config = HealthCheckConfig {
+      query_name: "internal.example".to_string(),
+      expected_answer: Some(HealthCheckAnswer::Any),
+      ..Default::default()
+    };
+    let probe: HealthCheckProbe = (&config).try_into().unwrap();
+    assert!(probe
+      .evaluate(&response(ResponseCode::NoError, &[Ipv4Addr::new(1, 2, 3, 4)]))
+      .is_ok());
+    assert!(probe.evaluate(&response(ResponseCode::NoError, &[])).is_err());
+
+    let config = HealthCheckConfig {
+      query_name: "nonexistent.example".to_string(),
+      expected_rcode: "nxdomain".to_string(),
+      expected_answer: None,
+      ..Default::default()
+    };
+    let probe: HealthCheckProbe = (&config).try_into().unwrap();
+    assert!(probe.evaluate(&response(ResponseCode::NXDomain, &[])).is_ok());
+    assert!(probe.evaluate(&response(ResponseCode::NoError, &[])).is_err());
+  }
+
+  #[tokio::test]
+  async fn invalid_probe_config_is_rejected() {
+    let config = HealthCheckConfig {
+      query_type: "NOTATYPE".to_string(),
+      ..Default::default()
+    };
+    assert!(HealthCheckProbe::try_from(&config).is_err());
+
+    let config = HealthCheckConfig {
+      expected_rcode: "NOTANRCODE".to_string(),
+      ..Default::default()
+    };
+    assert!(HealthCheckProbe::try_from(&config).is_err());
+
+    let config = HealthCheckConfig {
+      probes: 2,
+      success_threshold: 3,
+      ..Default::default()
+    };
+    assert!(HealthCheckProbe::try_from(&config).is_err());
+  }
+}
@@ -0,0 +1,100 @@
+use crate::constants::HEALTHCHECK_JITTER_RATIO;
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+/// Policy of health check scheduling
+pub struct HealthCheckSchedulePolicy {
+  /// interval of checks for healthy paths
+  pub period: Duration,
+  /// first interval of re-probing an unhealthy path, doubled on every failure up to `period`
+  pub initial_backoff: Duration,
+  /// max number of concurrent health checks
+  pub max_concurrency: usize,
+}
+
+#[derive(Debug, Clone)]
+/// Health check schedule of a path
+pub struct HealthCheckSchedule {
+  /// next time to check
+  next_at: Instant,
+  /// current backoff for an unhealthy path, `None` if healthy
+  backoff: Option<Duration>,
+}
+
+impl HealthCheckSchedule {
+  /// Schedule the first check within the initial backoff, so that initial checks are spread
+  pub fn new(now: Instant, policy: &HealthCheckSchedulePolicy) -> Self {
+    let spread = rand::thread_rng().gen_range(Duration::ZERO..=policy.initial_backoff);
+    Self {
+      next_at: now + spread,
+      backoff: None,
+    }
+  }
+
+  /// Check if the path is due to be checked
+  pub fn is_due(&self, now: Instant) -> bool {
+    self.next_at <= now
+  }
+
+  /// Get the next time to check
+  pub fn next_at(&self) -> Instant {
+    self.next_at
+  }
+
+  /// Reschedule according to the result of the check.
+  /// Healthy paths are checked every period, and unhealthy ones are re-probed with exponential backoff.
+  pub fn reschedule(&mut self, healthy: bool, now: Instant, policy: &HealthCheckSchedulePolicy) {
+    let interval = if healthy {
+      self.backoff = None;
+      policy.period
+    } else {
+      let backoff = match self.backoff {
+        None => policy.initial_backoff,
+        Some(prev) => (prev * 2).min(policy.period),
+      };
+      self.backoff = Some(backoff);
+      backoff
+    };
+    self.next_at = now + interval + jitter(interval);
+  }
+}
+
+/// Randomized jitter up to the ratio of the given interval
+fn jitter(interval: Duration) -> Duration {
+  interval.mul_f64(rand::thread_rng().gen_range(0.0..=HEALTHCHECK_JITTER_RATIO))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn backoff_works() {
+    let policy = HealthCheckSchedulePolicy {
+      period: Duration::from_secs(600),
+      initial_backoff: Duration::from_secs(5),
+      max_concurrency: 4,
+    };
+    let now = Instant::now();
+    let mut schedule = HealthCheckSchedule::new(now, &policy);
+    assert!(schedule.next_at() <= now + policy.initial_backoff);
+    assert!(schedule.is_due(now + policy.initial_backoff));
+
+    let max_jitter = |d: Duration| d.mul_f64(HEALTHCHECK_JITTER_RATIO);
+    let mut expected = policy.initial_backoff;
+    for _ in 0..10 {
+      schedule.reschedule(false, now, &policy);
+      assert!(schedule.next_at() >= now + expected);
+      assert!(schedule.next_at() <= now + expected + max_jitter(expected));
+      expected = (expected * 2).min(policy.period);
+    }
+    assert_eq!(schedule.backoff, Some(policy.period));
+
+    schedule.reschedule(true, now, &policy);
+    assert!(schedule.next_at() >= now + policy.period);
+    assert!(schedule.backoff.is_none());
+    schedule.reschedule(false, now, &policy);
+    assert_eq!(schedule.backoff, Some(policy.initial_backoff));
+  }
+}
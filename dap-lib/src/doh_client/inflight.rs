@@ -0,0 +1,134 @@
+use super::dns_message::Request;
+use rustc_hash::FxHashMap as HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Response buffer shared among coalesced queries, `None` until the leader completes
+type SharedResponse = Option<Arc<Vec<u8>>>;
+
+/// In-flight upstream queries, keyed by the request.
+/// Concurrent cache misses with the same key are coalesced into a single upstream query.
+#[derive(Default)]
+pub struct InflightQueries {
+  inner: Arc<Mutex<HashMap<Request, watch::Receiver<SharedResponse>>>>,
+}
+
+/// Role of a query joining the in-flight table
+pub enum InflightRole {
+  /// The first query for the key, which must make the upstream query and complete it
+  Leader(InflightLeader),
+  /// Subsequent queries waiting for the leader's response
+  Follower(InflightFollower),
+}
+
+impl InflightQueries {
+  /// Create a new in-flight query table
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Join the in-flight query for the given key, or become the leader if there is none.
+  pub fn join(&self, key: &Request) -> InflightRole {
+    let mut lock = self.inner.lock().unwrap();
+    if let Some(rx) = lock.get(key) {
+      return InflightRole::Follower(InflightFollower { rx: rx.clone() });
+    }
+    let (tx, rx) = watch::channel(None);
+    lock.insert(key.clone(), rx);
+    InflightRole::Leader(InflightLeader {
+      key: key.clone(),
+      tx,
+      table: self.inner.clone(),
+    })
+  }
+
+  /// Get the number of in-flight upstream queries
+  #[allow(dead_code)]
+  pub fn len(&self) -> usize {
+    self.inner.lock().unwrap().len()
+  }
+}
+
+/// Leader of coalesced queries. The entry is removed from the table when dropped,
+/// so that followers are released even if the leader is cancelled, e.g., by timeout.
+pub struct InflightLeader {
+  key: Request,
+  tx: watch::Sender<SharedResponse>,
+  table: Arc<Mutex<HashMap<Request, watch::Receiver<SharedResponse>>>>,
+}
+
+impl InflightLeader {
+  /// Fan out the response buffer to followers
+  pub fn complete(self, response_buf: &[u8]) {
+    let _ = self.tx.send(Some(Arc::new(response_buf.to_vec())));
+  }
+}
+
+impl Drop for InflightLeader {
+  fn drop(&mut self) {
+    self.table.lock().unwrap().remove(&self.key);
+  }
+}
+
+/// Follower of coalesced queries
+pub struct InflightFollower {
+  rx: watch::Receiver<SharedResponse>,
+}
+
+impl InflightFollower {
+  /// Wait for the leader's response buffer. Returns `None` if the leader failed or was dropped.
+  pub async fn wait(mut self) -> Option<Arc<Vec<u8>>> {
+    loop {
+      if let Some(res) = self.rx.borrow().as_ref() {
+        return Some(res.clone());
+      }
+      self.rx.changed().await.ok()?;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::doh_client::dns_message::build_query_a;
+
+  #[tokio::test]
+  async fn coalescing_works() {
+    let key = Request::try_from(&build_query_a("www.google.com.").unwrap()).unwrap();
+    let inflight = InflightQueries::new();
+
+    let InflightRole::Leader(leader) = inflight.join(&key) else {
+      panic!("first query must be the leader");
+    };
+    let InflightRole::Follower(follower1) = inflight.join(&key) else {
+      panic!("second query must be a follower");
+    };
+    let InflightRole::Follower(follower2) = inflight.join(&key) else {
+      panic!("third query must be a follower");
+    };
+    assert_eq!(inflight.len(), 1);
+
+    let waiting = tokio::spawn(follower1.wait());
+    leader.complete(&[0u8, 1, 2, 3]);
+    assert_eq!(waiting.await.unwrap().unwrap().as_slice(), &[0u8, 1, 2, 3]);
+    assert_eq!(follower2.wait().await.unwrap().as_slice(), &[0u8, 1, 2, 3]);
+    assert_eq!(inflight.len(), 0);
+
+    // new leader after completion
+    assert!(matches!(inflight.join(&key), InflightRole::Leader(_)));
+  }
+
+  #[tokio::test]
+  async fn dropped_leader_releases_followers() {
+    let key = Request::try_from(&build_query_a("www.google.com.").unwrap()).unwrap();
+    let inflight = InflightQueries::new();
+
+    let leader = inflight.join(&key);
+    let InflightRole::Follower(follower) = inflight.join(&key) else {
+      panic!("second query must be a follower");
+    };
+    drop(leader);
+    assert!(follower.wait().await.is_none());
+    assert_eq!(inflight.len(), 0);
+  }
+}
@@ -1,13 +1,23 @@
 mod cache;
+mod directory;
 mod dns_message;
+mod dnscrypt;
 mod doh_client_healthcheck;
 mod doh_client_main;
+mod healthcheck_probe;
+mod healthcheck_schedule;
+mod inflight;
 mod manipulation;
+mod oblivious_http;
 mod odoh;
 mod odoh_config_store;
 mod path_manage;
+mod path_update;
+mod proxy_handle;
 
 pub use doh_client_main::DoHClient;
+pub use path_manage::{HopStatus, HopUrls, PathSetUpdate};
+pub use proxy_handle::ProxyHandle;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 /// DoH method, GET or POST
@@ -17,17 +27,29 @@ pub enum DoHMethod {
 }
 
 #[derive(Debug, Clone)]
-/// DoH type, Standard or Oblivious
+/// DoH type, Standard, Oblivious, Oblivious HTTP or DNSCrypt
 pub(super) enum DoHType {
   Standard,
   Oblivious,
+  ObliviousHttp,
+  /// DNSCrypt v2, relayed by Anonymized DNSCrypt relays if the path has a relay
+  DnsCrypt,
 }
 
 impl DoHType {
+  /// media type of requests, where dnscrypt messages are not sent over http
   fn as_str(&self) -> String {
     match self {
-      DoHType::Standard => String::from("application/dns-message"),
+      DoHType::Standard | DoHType::DnsCrypt => String::from("application/dns-message"),
       DoHType::Oblivious => String::from("application/oblivious-dns-message"),
+      DoHType::ObliviousHttp => String::from("message/ohttp-req"),
+    }
+  }
+  /// media type of responses
+  fn accept_str(&self) -> String {
+    match self {
+      DoHType::ObliviousHttp => String::from("message/ohttp-res"),
+      _ => self.as_str(),
     }
   }
 }
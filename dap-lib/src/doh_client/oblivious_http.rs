@@ -0,0 +1,221 @@
+use super::{odoh_config_store::max_age, path_manage::DoHTarget};
+use crate::{
+  constants::{OHTTP_KEY_CONFIG_RETRY_SEC, OHTTP_KEY_CONFIG_WATCH_DELAY},
+  error::*,
+  http_client::HttpClientInner,
+  log::*,
+};
+use bhttp::{Message, Mode, StatusCode};
+use ohttp::{ClientRequest, ClientResponse};
+use reqwest::header;
+use std::sync::Arc;
+use tokio::{
+  sync::{Mutex, Notify, RwLock},
+  time::{sleep, Duration, Instant},
+};
+use url::Url;
+
+/// Media type of the key configs of the gateway (RFC 9540)
+const OHTTP_KEYS_MEDIA_TYPE: &str = "application/ohttp-keys";
+/// Media type of DNS messages in the encapsulated request and response
+const DNS_MESSAGE_MEDIA_TYPE: &str = "application/dns-message";
+
+/// Key configs of the OHTTP gateway, fetched directly from the gateway and refreshed per max-age.
+/// The last fetched configs are kept on fetch failures.
+pub(super) struct OHttpKeyConfigs {
+  /// url serving the key configs
+  key_url: Url,
+  /// encoded key configs in the order listed by the gateway
+  configs: RwLock<Vec<Vec<u8>>>,
+  /// http client to fetch the key configs
+  http_client: Arc<RwLock<HttpClientInner>>,
+  /// lock to coalesce refetches after the gateway rejects the key
+  refetch_lock: Mutex<()>,
+  /// time when the last fetch started
+  fetch_started_at: RwLock<Option<Instant>>,
+}
+
+impl OHttpKeyConfigs {
+  /// Create a new store with the key configs fetched from the gateway
+  pub async fn new(http_client: Arc<RwLock<HttpClientInner>>, key_url: &Url) -> Result<Self> {
+    let key_configs = Self {
+      key_url: key_url.clone(),
+      configs: RwLock::new(vec![]),
+      http_client,
+      refetch_lock: Mutex::new(()),
+      fetch_started_at: RwLock::new(None),
+    };
+    if let Err(e) = key_configs.fetch().await {
+      warn!("[OHTTP] Failed to fetch key configs of the gateway, retry later: {e}");
+    }
+    Ok(key_configs)
+  }
+
+  /// Encapsulate the request with the first key config supported
+  pub async fn encapsulate(&self, request: &[u8]) -> Result<(Vec<u8>, ClientResponse)> {
+    let configs = self.configs.read().await;
+    let mut errors = vec![];
+    let client_request = configs.iter().find_map(|config| {
+      ClientRequest::from_encoded_config(config)
+        .map_err(|e| errors.push(e))
+        .ok()
+    });
+    drop(configs);
+    let Some(client_request) = client_request else {
+      for e in errors.iter() {
+        warn!("[OHTTP] Unusable key config of the gateway: {e}");
+      }
+      return Err(DapError::OHttpNoKeyConfig);
+    };
+    Ok(client_request.encapsulate(request)?)
+  }
+
+  /// Refetch the key configs after the gateway rejects the key, where concurrent refetches are coalesced.
+  /// The fetch is skipped if another one started after the rejected query was sent, since it reflects the rotation.
+  pub async fn refetch(&self, sent_at: Instant) -> Result<()> {
+    let _lock = self.refetch_lock.lock().await;
+    if self.fetch_started_at.read().await.is_some_and(|v| v >= sent_at) {
+      debug!("[OHTTP] Key configs are already refetched");
+      return Ok(());
+    }
+    self.fetch().await.map(|_| ())
+  }
+
+  /// Fetch the key configs and get the time to the next refresh
+  async fn fetch(&self) -> Result<Duration> {
+    debug!("Fetching OHTTP key configs from {}", self.key_url);
+    *self.fetch_started_at.write().await = Some(Instant::now());
+    let lock = self.http_client.read().await;
+    let response = lock
+      .get(self.key_url.clone())
+      .header(header::ACCEPT, OHTTP_KEYS_MEDIA_TYPE)
+      .send()
+      .await?;
+    drop(lock);
+    if response.status() != reqwest::StatusCode::OK {
+      return Err(DapError::OHttpKeyConfigFetchError(format!(
+        "status {}",
+        response.status()
+      )));
+    }
+    let max_age = max_age(response.headers()).unwrap_or(Duration::from_secs(OHTTP_KEY_CONFIG_WATCH_DELAY));
+    let body = response.bytes().await?;
+    let configs = key_configs(&body)?;
+    info!(
+      "[OHTTP] Update key configs of the gateway: {} ({} keys)",
+      self.key_url,
+      configs.len()
+    );
+    *self.configs.write().await = configs;
+    Ok(max_age)
+  }
+
+  /// start key config watch service
+  pub(super) async fn start_service(&self, term_notify: Option<Arc<Notify>>) -> Result<()> {
+    info!("Start periodic ohttp key config watch service");
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          _ = self.watch_service() => {
+            warn!("OHTTP key config watch service is down");
+          }
+          _ = term.notified() => {
+            info!("OHTTP key config watch service receives term signal");
+          }
+        }
+      }
+      None => {
+        self.watch_service().await?;
+        warn!("OHTTP key config watch service is down.");
+      }
+    }
+    Ok(())
+  }
+
+  /// watch service, which fetches the key configs when they get stale or on failures
+  async fn watch_service(&self) -> Result<()> {
+    let mut next_fetch = match self.configs.read().await.is_empty() {
+      true => Duration::from_secs(OHTTP_KEY_CONFIG_RETRY_SEC),
+      false => Duration::from_secs(OHTTP_KEY_CONFIG_WATCH_DELAY),
+    };
+    loop {
+      sleep(next_fetch).await;
+      next_fetch = match self.fetch().await {
+        Ok(max_age) => max_age,
+        Err(e) => {
+          warn!("[OHTTP] Failed to fetch key configs of the gateway, keep the last ones: {e}");
+          Duration::from_secs(OHTTP_KEY_CONFIG_RETRY_SEC)
+        }
+      };
+    }
+  }
+}
+
+/// Parse key configs in application/ohttp-keys, i.e., a list of configs prefixed by their 2-byte lengths (RFC 9540).
+/// A single config without the length prefix is also accepted as served by some gateways.
+fn key_configs(body: &[u8]) -> Result<Vec<Vec<u8>>> {
+  let mut configs = vec![];
+  let mut rest = body;
+  while rest.len() >= 2 {
+    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    if len == 0 || rest.len() < 2 + len {
+      break;
+    }
+    configs.push(rest[2..2 + len].to_vec());
+    rest = &rest[2 + len..];
+  }
+  if !rest.is_empty() || configs.is_empty() {
+    if body.is_empty() {
+      return Err(DapError::OHttpNoKeyConfig);
+    }
+    return Ok(vec![body.to_vec()]);
+  }
+  Ok(configs)
+}
+
+/// Build the binary http request of the DNS query to the target
+pub(super) fn build_request(packet_buf: &[u8], target: &DoHTarget) -> Result<Vec<u8>> {
+  let mut request = Message::request(
+    b"POST".to_vec(),
+    target.scheme().as_bytes().to_vec(),
+    target.authority().as_bytes().to_vec(),
+    target.path().as_bytes().to_vec(),
+  );
+  request.put_header("accept", DNS_MESSAGE_MEDIA_TYPE);
+  request.put_header("content-type", DNS_MESSAGE_MEDIA_TYPE);
+  request.write_content(packet_buf);
+  let mut buf = vec![];
+  request.write_bhttp(Mode::KnownLength, &mut buf)?;
+  Ok(buf)
+}
+
+/// Get the DNS response from the binary http response of the target
+pub(super) fn parse_response(response_buf: &[u8]) -> Result<Vec<u8>> {
+  let response = Message::read_bhttp(&mut std::io::Cursor::new(response_buf))?;
+  if response.control().status() != Some(StatusCode::OK) {
+    error!("OHTTP query error!: {:?}", response.control().status());
+    return Err(DapError::DoHQueryError);
+  }
+  Ok(response.content().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn key_configs_work() {
+    let config_a = vec![1u8; 41];
+    let config_b = vec![2u8; 45];
+    let mut list = vec![];
+    for config in [&config_a, &config_b] {
+      list.extend_from_slice(&(config.len() as u16).to_be_bytes());
+      list.extend_from_slice(config);
+    }
+    assert_eq!(key_configs(&list).unwrap(), vec![config_a.clone(), config_b]);
+
+    // single config without the length prefix, starting with the key id
+    assert_eq!(key_configs(&config_a).unwrap(), vec![config_a]);
+    assert!(key_configs(&[]).is_err());
+  }
+}
@@ -1,53 +1,135 @@
 // Based on https://github.com/DNSCrypt/doh-server/blob/master/src/libdoh/src/odoh.rs
-use crate::{error::*, log::*};
+use crate::{error::*, globals::ODoHPaddingPolicy, log::*};
 use bytes::Bytes;
 use odoh_rs::{
-  parse, ObliviousDoHConfigContents, ObliviousDoHConfigs, ObliviousDoHMessage, ObliviousDoHMessagePlaintext, OdohSecret,
+  compose, parse, ObliviousDoHConfigContents, ObliviousDoHConfigs, ObliviousDoHMessage, ObliviousDoHMessagePlaintext,
+  OdohSecret,
 };
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{cmp::Reverse, sync::Mutex};
+
+/// HPKE suites supported by odoh-rs as (KEM, KDF, AEAD) ids in the order of preference,
+/// i.e., DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and AES-128-GCM
+const SUPPORTED_SUITES: &[(u16, u16, u16)] = &[(0x0020, 0x0001, 0x0001)];
+/// Length of the response nonce carried in the key id field of responses, i.e., max(Nn, Nk) of AES-128-GCM
+const RESPONSE_NONCE_LEN: usize = 16;
+/// Length of the length fields of the DNS message and padding in the serialized plaintext
+const PLAINTEXT_LEN_FIELDS: usize = 4;
 
 #[derive(Debug, Clone)]
-/// ODoH config
+/// Public key of a target listed in ObliviousDoHConfigs
+struct ODoHKey {
+  /// key id derived from the config contents
+  id: Vec<u8>,
+  /// config contents
+  contents: ObliviousDoHConfigContents,
+}
+
+#[derive(Debug)]
+/// ODoH config, holding all keys with supported versions and suites in the order of preference.
+/// Multiple keys are listed while the target rotates its key, where the next one is used if the preferred one is rejected.
 pub struct ODoHConfig {
-  #[allow(dead_code)]
   authority: String,
-  inner: ObliviousDoHConfigContents,
+  keys: Vec<ODoHKey>,
+  /// ids of keys rejected by the target
+  rejected_key_ids: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Encrypted ODoH query with the state to decrypt its response
+pub struct ODoHQuery {
+  /// plaintext query
+  pub plaintext: ObliviousDoHMessagePlaintext,
+  /// encrypted query body
+  pub body: Bytes,
+  /// client secret
+  pub secret: OdohSecret,
+  /// id of the key used for encryption
+  pub key_id: Vec<u8>,
 }
 
 impl ODoHConfig {
-  /// Create a new ODoHConfig
+  /// Create a new ODoHConfig from the serialized ObliviousDoHConfigs
   pub fn new(authority: &str, configs_vec: &[u8]) -> Result<Self> {
     let odoh_configs: ObliviousDoHConfigs = parse(&mut (<&[u8]>::clone(&configs_vec)))?;
-    info!("[ODoH] Update ODoH configs: {authority}");
-    let client_config = match odoh_configs.into_iter().next() {
-      Some(t) => t,
-      None => return Err(DapError::ODoHNoClientConfig),
-    };
-    let inner: ObliviousDoHConfigContents = client_config.into();
+    let mut ranked = odoh_configs
+      .into_iter()
+      .filter_map(|config| {
+        // version and suite ids are at the head of the serialized config
+        let serialized = compose(&config).ok()?;
+        let id_at = |i: usize| u16::from_be_bytes([serialized[i], serialized[i + 1]]);
+        if serialized.len() < 10 {
+          return None;
+        }
+        let version = id_at(0);
+        let suite = (id_at(4), id_at(6), id_at(8));
+        let Some(suite_rank) = SUPPORTED_SUITES.iter().position(|v| *v == suite) else {
+          debug!("[ODoH] Skip unsupported suite {suite:?} of {authority}");
+          return None;
+        };
+        let contents: ObliviousDoHConfigContents = config.into();
+        let id = contents.identifier().ok()?;
+        Some(((Reverse(version), suite_rank), ODoHKey { id, contents }))
+      })
+      .collect::<Vec<_>>();
+    if ranked.is_empty() {
+      return Err(DapError::ODoHNoClientConfig);
+    }
+    // stable sort to keep the order listed by the target among the same version and suite
+    ranked.sort_by_key(|(rank, _)| *rank);
+    let keys = ranked.into_iter().map(|(_, key)| key).collect::<Vec<_>>();
+    info!("[ODoH] Update ODoH configs: {authority} ({} keys)", keys.len());
 
     Ok(ODoHConfig {
       authority: authority.to_owned(),
-      inner,
+      keys,
+      rejected_key_ids: Mutex::new(vec![]),
     })
   }
 
-  /// Encrypt query
-  pub fn encrypt_query(&self, plaintext_query: &[u8]) -> Result<(ObliviousDoHMessagePlaintext, Bytes, OdohSecret)> {
+  /// Get the preferred key not rejected by the target, or the most preferred one if all are rejected
+  fn preferred_key(&self) -> &ODoHKey {
+    let rejected = self.rejected_key_ids.lock().unwrap();
+    self
+      .keys
+      .iter()
+      .find(|key| !rejected.contains(&key.id))
+      .unwrap_or(&self.keys[0])
+  }
+
+  /// Mark the key as rejected by the target, e.g., by 401, so that the next one is used
+  pub fn reject_key(&self, key_id: &[u8]) {
+    let mut rejected = self.rejected_key_ids.lock().unwrap();
+    if self.keys.len() > 1 && !rejected.iter().any(|v| v == key_id) {
+      debug!("[ODoH] Key of {} is rejected, use the next one", self.authority);
+      rejected.push(key_id.to_vec());
+    }
+  }
+
+  /// Encrypt query with the preferred key, padded by the policy
+  pub fn encrypt_query(&self, plaintext_query: &[u8], padding: &ODoHPaddingPolicy) -> Result<ODoHQuery> {
     debug!("[ODoH] Encrypt query");
     let mut rng = StdRng::from_entropy();
+    let key = self.preferred_key();
 
-    // TODO: Padding bytes should be add? Padding be handled by a client issuing plaintext queries.
-    // add a random padding for testing purpose
-    // let padding_len = rng.gen_range(0..10);
-    // let query = ObliviousDoHMessagePlaintext::new(&plaintext_query, padding_len);
-    // debug!("[ODoH] Encrypting DNS message with {} bytes of padding", padding_len);
-    let query = ObliviousDoHMessagePlaintext::new(plaintext_query, 0);
-    let (query_enc, cli_secret) = odoh_rs::encrypt_query(&query, &self.inner, &mut rng)?;
+    let padding_len = padding_len(padding, plaintext_query.len(), &mut rng);
+    debug!("[ODoH] Encrypting DNS message with {} bytes of padding", padding_len);
+    let query = ObliviousDoHMessagePlaintext::new(plaintext_query, padding_len);
+    let (query_enc, cli_secret) = odoh_rs::encrypt_query(&query, &key.contents, &mut rng)?;
+    if query_enc.key_id() != key.id.as_slice() {
+      return Err(DapError::ODoHKeyIdMismatch);
+    }
     let query_body = odoh_rs::compose(&query_enc)?.freeze();
-    Ok((query, query_body, cli_secret))
+    Ok(ODoHQuery {
+      plaintext: query,
+      body: query_body,
+      secret: cli_secret,
+      key_id: key.id.clone(),
+    })
   }
 
-  /// Decrypt response
+  /// Decrypt response.
+  /// The key id field of a response carries the response nonce bound to the key used for the query,
+  /// which is checked by its length here and by decryption with the secret of the query.
   pub fn decrypt_response(
     &self,
     plaintext_query: &ObliviousDoHMessagePlaintext,
@@ -56,9 +138,130 @@ impl ODoHConfig {
   ) -> Result<Bytes> {
     debug!("[ODoH] Decrypt query");
     let response_enc: ObliviousDoHMessage = parse(&mut (encrypted_response.clone()))?;
+    if response_enc.key_id().len() != RESPONSE_NONCE_LEN {
+      return Err(DapError::ODoHKeyIdMismatch);
+    }
     let response_dec = odoh_rs::decrypt_response(plaintext_query, &response_enc, client_secret)?;
-    debug!("[ODoH] Successfully decrypted");
+    // padding must be all zeros
+    if response_dec.padding().iter().any(|v| *v != 0) {
+      return Err(DapError::ODoHInvalidPadding);
+    }
+    debug!(
+      "[ODoH] Successfully decrypted with {} bytes of padding",
+      response_dec.padding().len()
+    );
 
     Ok(response_dec.into_msg())
   }
 }
+
+/// Length of padding of the plaintext query by the policy, within the limit of the padding length field
+fn padding_len<R: Rng>(policy: &ODoHPaddingPolicy, msg_len: usize, rng: &mut R) -> usize {
+  let plaintext_len = msg_len + PLAINTEXT_LEN_FIELDS;
+  let len = match policy {
+    ODoHPaddingPolicy::None => 0,
+    ODoHPaddingPolicy::Block(block_size) => (block_size - plaintext_len % block_size) % block_size,
+    ODoHPaddingPolicy::Random { min, max } => rng.gen_range(*min..=*max),
+    ODoHPaddingPolicy::Max(max_len) => max_len.saturating_sub(plaintext_len),
+  };
+  len.min(u16::MAX as usize)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use odoh_rs::{ObliviousDoHConfig, ObliviousDoHKeyPair};
+
+  fn configs(suites: &[(u16, u16, u16)]) -> Vec<u8> {
+    let configs = suites
+      .iter()
+      .enumerate()
+      .map(|(i, (kem, kdf, aead))| {
+        let key_pair = ObliviousDoHKeyPair::from_parameters(*kem, *kdf, *aead, &[i as u8; 32]);
+        ObliviousDoHConfig::from(key_pair.public().clone())
+      })
+      .collect::<Vec<_>>();
+    compose(&ObliviousDoHConfigs::from(configs)).unwrap().to_vec()
+  }
+
+  #[tokio::test]
+  async fn multiple_keys_work() {
+    // unsupported suite listed first
+    let config = ODoHConfig::new(
+      "target.example",
+      &configs(&[
+        (0x0010, 0x0001, 0x0001),
+        (0x0020, 0x0001, 0x0001),
+        (0x0020, 0x0001, 0x0001),
+      ]),
+    )
+    .unwrap();
+    assert_eq!(config.keys.len(), 2);
+    let first = config.preferred_key().id.clone();
+    let second = config.keys[1].id.clone();
+    assert_ne!(first, second);
+
+    // overlapping key is used after the preferred one is rejected
+    config.reject_key(&first);
+    assert_eq!(config.preferred_key().id, second);
+    config.reject_key(&second);
+    assert_eq!(config.preferred_key().id, first);
+
+    // no supported suite
+    assert!(ODoHConfig::new("target.example", &configs(&[(0x0010, 0x0001, 0x0001)])).is_err());
+  }
+
+  #[tokio::test]
+  async fn padding_policies_work() {
+    let config = ODoHConfig::new("target.example", &configs(&[(0x0020, 0x0001, 0x0001)])).unwrap();
+    let ciphertext_lens = |policy: &ODoHPaddingPolicy| {
+      (20..=120)
+        .map(|msg_len| config.encrypt_query(&vec![0u8; msg_len], policy).unwrap().body.len())
+        .collect::<Vec<_>>()
+    };
+    let unpadded = ciphertext_lens(&ODoHPaddingPolicy::None);
+    assert!(unpadded.windows(2).all(|v| v[0] + 1 == v[1]));
+    let overhead = unpadded[0] - 20 - PLAINTEXT_LEN_FIELDS;
+
+    // all queries within a block are of the same size
+    let block = ciphertext_lens(&ODoHPaddingPolicy::Block(128));
+    assert!(block.iter().all(|v| *v == 128 + overhead));
+    let block = ciphertext_lens(&ODoHPaddingPolicy::Block(64));
+    assert!(block.iter().all(|v| *v == 64 + overhead || *v == 128 + overhead));
+    assert!(block.contains(&(64 + overhead)) && block.contains(&(128 + overhead)));
+
+    // fixed maximum size
+    let max = ciphertext_lens(&ODoHPaddingPolicy::Max(256));
+    assert!(max.iter().all(|v| *v == 256 + overhead));
+    assert_eq!(
+      config
+        .encrypt_query(&[0u8; 300], &ODoHPaddingPolicy::Max(256))
+        .unwrap()
+        .body
+        .len(),
+      300 + PLAINTEXT_LEN_FIELDS + overhead
+    );
+
+    // random padding within the bounds
+    let random = ciphertext_lens(&ODoHPaddingPolicy::Random { min: 16, max: 32 });
+    assert!(random
+      .iter()
+      .zip(unpadded.iter())
+      .all(|(r, u)| *r >= u + 16 && *r <= u + 32));
+  }
+
+  #[tokio::test]
+  async fn response_padding_works() {
+    let config = ODoHConfig::new("target.example", &configs(&[(0x0020, 0x0001, 0x0001)])).unwrap();
+    let query = config
+      .encrypt_query(&[1u8; 40], &ODoHPaddingPolicy::Block(128))
+      .unwrap();
+    assert_eq!(query.plaintext.padding().len(), 128 - 40 - PLAINTEXT_LEN_FIELDS);
+
+    let response = ObliviousDoHMessagePlaintext::new([2u8; 100], 468 - 100 - PLAINTEXT_LEN_FIELDS);
+    let response_enc = odoh_rs::encrypt_response(&query.plaintext, &response, query.secret, [3u8; 16]).unwrap();
+    let body = compose(&response_enc).unwrap().freeze();
+    let decrypted = config.decrypt_response(&query.plaintext, &body, query.secret).unwrap();
+    assert_eq!(decrypted.as_ref(), &[2u8; 100]);
+  }
+}
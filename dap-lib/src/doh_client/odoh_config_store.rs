@@ -1,91 +1,304 @@
-use super::{odoh::ODoHConfig, path_manage::DoHTarget};
+use super::{dns_message, odoh::ODoHConfig, path_manage::DoHTarget};
 use crate::{
-  constants::{ODOH_CONFIG_PATH, ODOH_CONFIG_WATCH_DELAY},
+  constants::{
+    ODOH_CONFIG_MAX_STALE_SEC, ODOH_CONFIG_MIN_REFRESH_SEC, ODOH_CONFIG_PATH, ODOH_CONFIG_RETRY_INITIAL_SEC,
+    ODOH_CONFIG_SVC_PARAM_KEY, ODOH_CONFIG_WATCH_DELAY,
+  },
   error::*,
   http_client::HttpClientInner,
   log::*,
 };
+use async_trait::async_trait;
+use hickory_proto::{
+  op::Message,
+  rr::{
+    rdata::svcb::{SvcParamValue, Unknown},
+    RData, RecordType,
+  },
+};
+use reqwest::header::{self, HeaderMap};
 use rustc_hash::FxHashMap as HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock, Weak};
 use tokio::{
-  sync::{Notify, RwLock},
-  time::{sleep, Duration},
+  sync::{Mutex, Notify, RwLock},
+  time::{sleep_until, Duration, Instant},
 };
 use url::Url;
 
-#[allow(clippy::complexity)]
+#[derive(Debug, Clone)]
+/// ODoH config of a target with its refresh schedule
+struct ODoHConfigEntry {
+  /// last valid config, which is kept on fetch failures until it expires
+  config: Option<Arc<ODoHConfig>>,
+  /// time after which the config must not be used
+  expires_at: Instant,
+  /// time of the next fetch
+  next_fetch_at: Instant,
+  /// current backoff of retries on failure, `None` if the last fetch succeeded
+  backoff: Option<Duration>,
+  /// time when the last fetch started
+  fetch_started_at: Option<Instant>,
+  /// lock to coalesce concurrent refetches after key rotation
+  refetch_lock: Arc<Mutex<()>>,
+}
+
+impl ODoHConfigEntry {
+  /// Create an entry to be fetched immediately
+  fn new(now: Instant) -> Self {
+    Self {
+      config: None,
+      expires_at: now,
+      next_fetch_at: now,
+      backoff: None,
+      fetch_started_at: None,
+      refetch_lock: Arc::new(Mutex::new(())),
+    }
+  }
+
+  /// Get the config if not expired
+  fn config(&self, now: Instant) -> Option<Arc<ODoHConfig>> {
+    self.config.clone().filter(|_| now < self.expires_at)
+  }
+
+  /// Check if the config is due to be fetched
+  fn is_due(&self, now: Instant) -> bool {
+    self.next_fetch_at <= now
+  }
+
+  /// Update with the fetched config, which is refreshed after max-age and still used until max-stale after that
+  fn succeeded(&mut self, config: ODoHConfig, max_age: Duration, now: Instant) {
+    self.config = Some(Arc::new(config));
+    self.next_fetch_at = now + max_age;
+    self.expires_at = now + max_age + Duration::from_secs(ODOH_CONFIG_MAX_STALE_SEC);
+    self.backoff = None;
+  }
+
+  /// Schedule a retry with exponential backoff, keeping the last valid config until it expires
+  fn failed(&mut self, now: Instant) {
+    let backoff = match self.backoff {
+      None => Duration::from_secs(ODOH_CONFIG_RETRY_INITIAL_SEC),
+      Some(prev) => (prev * 2).min(Duration::from_secs(ODOH_CONFIG_WATCH_DELAY)),
+    };
+    self.backoff = Some(backoff);
+    self.next_fetch_at = now + backoff;
+    if now >= self.expires_at {
+      self.config = None;
+    }
+  }
+}
+
+#[async_trait]
+/// Resolver of DNS records through the proxy's own path, which is used to discover ODoH configs in HTTPS records
+pub(super) trait ResolveRecords: Send + Sync {
+  async fn resolve_records(&self, query_msg: &Message) -> Result<Message>;
+}
+
 /// ODoH config store
 pub struct ODoHConfigStore {
-  inner: Arc<RwLock<HashMap<Arc<DoHTarget>, Arc<Option<ODoHConfig>>>>>,
+  inner: Arc<RwLock<HashMap<Arc<DoHTarget>, ODoHConfigEntry>>>,
   http_client: Arc<RwLock<HttpClientInner>>,
+  /// discover configs in HTTPS records via the own oblivious path first, instead of fetching them from targets
+  via_dns: bool,
+  /// resolver of HTTPS records, set after the client is built
+  record_resolver: OnceLock<Weak<dyn ResolveRecords>>,
+  /// notifier of rescheduled fetches outside the watch service, e.g., refetch on 401
+  rescheduled: Notify,
 }
 
 impl ODoHConfigStore {
-  /// Create a new ODoHConfigStore
-  pub async fn new(http_client: Arc<RwLock<HttpClientInner>>, targets: &[Arc<DoHTarget>]) -> Result<Self> {
+  /// Create a new ODoHConfigStore.
+  /// Initial configs are fetched from well-known even if `via_dns` is set, since no oblivious path is available yet.
+  pub async fn new(
+    http_client: Arc<RwLock<HttpClientInner>>,
+    targets: &[Arc<DoHTarget>],
+    via_dns: bool,
+  ) -> Result<Self> {
+    let now = Instant::now();
     let inner = targets
       .iter()
-      .map(|target| (target.clone(), Arc::new(None as Option<ODoHConfig>)))
+      .map(|target| (target.clone(), ODoHConfigEntry::new(now)))
       .collect::<HashMap<_, _>>();
     let res = Self {
       inner: Arc::new(RwLock::new(inner)),
       http_client,
+      via_dns,
+      record_resolver: OnceLock::new(),
+      rescheduled: Notify::new(),
     };
-    res.update_odoh_config_from_well_known().await?;
+    res.fetch_due().await;
     Ok(res)
   }
 
-  /// Get a ODoHConfig for DoHTarget
-  pub async fn get(&self, target: &Arc<DoHTarget>) -> Option<Arc<Option<ODoHConfig>>> {
-    let inner_lock = self.inner.read().await;
-    let inner = inner_lock.get(target)?;
-    Some(inner.clone())
+  /// Set the resolver of HTTPS records to discover configs via DNS
+  pub(super) fn set_record_resolver(&self, resolver: Weak<dyn ResolveRecords>) {
+    let _ = self.record_resolver.set(resolver);
   }
 
-  /// Fetch ODoHConfig from target
-  pub async fn update_odoh_config_from_well_known(&self) -> Result<()> {
-    // TODO: Add auth token when fetching config?
-    // fetch public key from odoh target (/.well-known)
+  /// Get a valid ODoHConfig for DoHTarget
+  pub async fn get(&self, target: &Arc<DoHTarget>) -> Option<Arc<ODoHConfig>> {
     let inner_lock = self.inner.read().await;
-    let inner = inner_lock.clone();
+    inner_lock.get(target)?.config(Instant::now())
+  }
+
+  /// Add entries of the given targets not yet stored, and fetch configs of them.
+  /// Existing entries are kept, so that queries via targets to be removed still find their configs.
+  pub async fn add_targets(&self, targets: &[Arc<DoHTarget>]) {
+    let now = Instant::now();
+    let mut inner_lock = self.inner.write().await;
+    for target in targets.iter() {
+      inner_lock
+        .entry(target.clone())
+        .or_insert_with(|| ODoHConfigEntry::new(now));
+    }
     drop(inner_lock);
+    self.fetch_due().await;
+  }
+
+  /// Drop entries of targets other than the given ones, which is done after they are removed from the path set
+  pub async fn retain_targets(&self, targets: &[Arc<DoHTarget>]) {
+    self.inner.write().await.retain(|target, _| targets.contains(target));
+  }
+
+  /// Refetch the config of the target after its key is rotated, where concurrent refetches are coalesced.
+  /// The fetch is skipped if another one started after the rejected query was sent, since it reflects the rotation.
+  /// Returns an error if no valid config of the target is available after that.
+  pub async fn refetch(&self, target: &Arc<DoHTarget>, sent_at: Instant) -> Result<()> {
+    let Some(refetch_lock) = self.inner.read().await.get(target).map(|v| v.refetch_lock.clone()) else {
+      return Err(DapError::ODoHConfigFetchError(format!(
+        "{} is not a current target",
+        target.authority()
+      )));
+    };
+    let _lock = refetch_lock.lock().await;
+    let fetched_after_sent = self
+      .inner
+      .read()
+      .await
+      .get(target)
+      .and_then(|entry| entry.fetch_started_at)
+      .is_some_and(|started_at| started_at >= sent_at);
+    if fetched_after_sent {
+      debug!("ODoH config of {} is already refetched", target.authority());
+    } else {
+      self.fetch(std::slice::from_ref(target)).await;
+    }
+    match self.get(target).await {
+      Some(_) => Ok(()),
+      None => Err(DapError::ODoHConfigFetchError(format!(
+        "no valid config of {} after refetch",
+        target.authority()
+      ))),
+    }
+  }
+
+  /// Fetch configs of targets due to be refreshed or retried
+  async fn fetch_due(&self) {
+    let now = Instant::now();
+    let due = self
+      .inner
+      .read()
+      .await
+      .iter()
+      .filter(|(_, entry)| entry.is_due(now))
+      .map(|(target, _)| target.clone())
+      .collect::<Vec<_>>();
+    self.fetch(&due).await;
+  }
 
-    let futures = inner.keys().map(|target| async {
-      let mut destination = Url::parse(&format!("{}://{}", target.scheme(), target.authority())).unwrap();
-      destination.set_path(ODOH_CONFIG_PATH);
-      let lock = self.http_client.read().await;
-      debug!("Fetching ODoH config from {}", destination);
-      lock.get(destination).send().await
-    });
-    let joined = futures::future::join_all(futures);
-    let update_futures = joined.await.into_iter().zip(inner).map(|(res, current)| async move {
+  /// Fetch configs of the targets from well-known, and update entries with results
+  async fn fetch(&self, targets: &[Arc<DoHTarget>]) {
+    // TODO: Add auth token when fetching config?
+    let started_at = Instant::now();
+    let futures = targets.iter().map(|target| self.fetch_config(target));
+    let results = futures::future::join_all(futures).await;
+
+    let now = Instant::now();
+    let mut inner_lock = self.inner.write().await;
+    for (target, res) in targets.iter().zip(results) {
+      // the target may have been removed while fetching
+      let Some(entry) = inner_lock.get_mut(target) else {
+        continue;
+      };
+      entry.fetch_started_at = Some(started_at);
       match res {
-        Ok(response) => {
-          if response.status() != reqwest::StatusCode::OK {
-            error!("Failed to fetch ODoH config!: {:?}", response.status());
-            return (current.0.clone(), Arc::new(None as Option<ODoHConfig>));
-          }
-          let Ok(body) = response.bytes().await else {
-            error!("Failed to parse response body in ODoH config response");
-            return (current.0.clone(), Arc::new(None as Option<ODoHConfig>));
-          };
-          let config = ODoHConfig::new(current.0.authority(), &body).ok();
-          (current.0.clone(), Arc::new(config))
+        Ok((config, max_age)) => {
+          debug!(
+            "Fetched ODoH config from {}, refreshed after {:?}",
+            target.authority(),
+            max_age
+          );
+          entry.succeeded(config, max_age, now);
         }
         Err(e) => {
-          error!("Failed to fetch ODoH config!: {:?}", e);
-          (current.0.clone(), Arc::new(None as Option<ODoHConfig>))
+          entry.failed(now);
+          error!(
+            "Failed to fetch ODoH config from {}: {e}, retry after {:?}{}",
+            target.authority(),
+            entry.backoff.unwrap_or_default(),
+            if entry.config.is_some() {
+              ", keeping the last valid one"
+            } else {
+              ""
+            }
+          );
         }
       }
-    });
-    let update_joined = futures::future::join_all(update_futures)
-      .await
-      .into_iter()
-      .collect::<HashMap<_, _>>();
-    let mut inner_lock = self.inner.write().await;
-    *inner_lock = update_joined;
+    }
     drop(inner_lock);
-    Ok(())
+    self.rescheduled.notify_one();
+  }
+
+  /// Fetch ODoHConfig and its lifetime, via DNS if enabled, falling back to well-known
+  async fn fetch_config(&self, target: &DoHTarget) -> Result<(ODoHConfig, Duration)> {
+    if self.via_dns {
+      match self.fetch_from_dns(target).await {
+        Ok(res) => return Ok(res),
+        Err(e) => warn!(
+          "Failed to discover ODoH config of {} via DNS, fall back to well-known: {e}",
+          target.authority()
+        ),
+      }
+    }
+    self.fetch_from_well_known(target).await
+  }
+
+  /// Discover ODoHConfig in the HTTPS record of target through the own oblivious path, where its TTL is the lifetime
+  async fn fetch_from_dns(&self, target: &DoHTarget) -> Result<(ODoHConfig, Duration)> {
+    let Some(resolver) = self.record_resolver.get().and_then(|v| v.upgrade()) else {
+      return Err(DapError::ODoHConfigFetchError(
+        "no resolver of HTTPS records".to_string(),
+      ));
+    };
+    let url = Url::parse(&format!("{}://{}", target.scheme(), target.authority()))?;
+    let query_msg = dns_message::build_query(&https_query_name(&url)?, RecordType::HTTPS)?;
+    let response_msg = resolver.resolve_records(&query_msg).await?;
+    let Some((configs, ttl)) = odoh_configs_in_https_records(&response_msg) else {
+      return Err(DapError::ODoHConfigFetchError(
+        "no odohconfig in HTTPS records".to_string(),
+      ));
+    };
+    let config = ODoHConfig::new(target.authority(), &configs)?;
+    Ok((
+      config,
+      Duration::from_secs((ttl as u64).max(ODOH_CONFIG_MIN_REFRESH_SEC)),
+    ))
+  }
+
+  /// Fetch ODoHConfig and its max-age from target (/.well-known)
+  async fn fetch_from_well_known(&self, target: &DoHTarget) -> Result<(ODoHConfig, Duration)> {
+    let mut destination = Url::parse(&format!("{}://{}", target.scheme(), target.authority()))?;
+    destination.set_path(ODOH_CONFIG_PATH);
+    debug!("Fetching ODoH config from {}", destination);
+    let lock = self.http_client.read().await;
+    let response = lock.get(destination).send().await?;
+    drop(lock);
+    if response.status() != reqwest::StatusCode::OK {
+      return Err(DapError::ODoHConfigFetchError(format!("status {}", response.status())));
+    }
+    let max_age = max_age(response.headers()).unwrap_or(Duration::from_secs(ODOH_CONFIG_WATCH_DELAY));
+    let body = response.bytes().await?;
+    let config = ODoHConfig::new(target.authority(), &body)?;
+    Ok((config, max_age))
   }
 
   /// start odoh config watch service
@@ -110,11 +323,178 @@ impl ODoHConfigStore {
     Ok(())
   }
 
-  /// watch service
+  /// watch service, which fetches configs when they get stale or their retries are due
   async fn watch_service(&self) -> Result<()> {
     loop {
-      self.update_odoh_config_from_well_known().await?;
-      sleep(Duration::from_secs(ODOH_CONFIG_WATCH_DELAY as u64)).await;
+      let next_fetch_at = self
+        .inner
+        .read()
+        .await
+        .values()
+        .map(|entry| entry.next_fetch_at)
+        .min()
+        .unwrap_or_else(|| Instant::now() + Duration::from_secs(ODOH_CONFIG_WATCH_DELAY));
+      tokio::select! {
+        _ = sleep_until(next_fetch_at) => {
+          self.fetch_due().await;
+        }
+        _ = self.rescheduled.notified() => {}
+      }
+    }
+  }
+}
+
+/// Get max-age in the Cache-Control header, bounded below so as not to fetch too often
+pub(super) fn max_age(headers: &HeaderMap) -> Option<Duration> {
+  let cache_control = headers.get(header::CACHE_CONTROL)?.to_str().ok()?;
+  let max_age = cache_control
+    .split(',')
+    .find_map(|directive| directive.trim().strip_prefix("max-age="))?
+    .trim_matches('"')
+    .parse::<u64>()
+    .ok()?;
+  Some(Duration::from_secs(max_age.max(ODOH_CONFIG_MIN_REFRESH_SEC)))
+}
+
+/// Build the query name of the HTTPS record for the url, prefixed by the port if not default (RFC 9460)
+fn https_query_name(url: &Url) -> Result<String> {
+  let Some(host) = url.host_str() else {
+    return Err(DapError::ODoHConfigFetchError(format!("no host in {url}")));
+  };
+  match url.port() {
+    None => Ok(format!("{host}.")),
+    Some(port) => Ok(format!("_{port}._https.{host}.")),
+  }
+}
+
+/// Find serialized ObliviousDoHConfigs in odohconfig of HTTPS records, and return it with the TTL
+fn odoh_configs_in_https_records(msg: &Message) -> Option<(Vec<u8>, u32)> {
+  msg.answers().iter().find_map(|record| {
+    let Some(RData::HTTPS(https)) = record.data() else {
+      return None;
+    };
+    https
+      .svc_params()
+      .iter()
+      .find_map(|(key, value)| match value {
+        SvcParamValue::Unknown(Unknown(configs)) if u16::from(*key) == ODOH_CONFIG_SVC_PARAM_KEY => {
+          Some(configs.clone())
+        }
+        _ => None,
+      })
+      .map(|configs| (configs, record.ttl()))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn odoh_configs_in_https_records_work() {
+    use hickory_proto::rr::{
+      rdata::{svcb::SvcParamKey, HTTPS, SVCB},
+      Name, Record,
+    };
+    assert_eq!(
+      https_query_name(&Url::parse("https://odoh.example").unwrap()).unwrap(),
+      "odoh.example."
+    );
+    assert_eq!(
+      https_query_name(&Url::parse("https://odoh.example:8443").unwrap()).unwrap(),
+      "_8443._https.odoh.example."
+    );
+
+    let name = Name::from_ascii("odoh.example.").unwrap();
+    let mut msg = dns_message::build_query("odoh.example.", RecordType::HTTPS).unwrap();
+    let svcb = SVCB::new(
+      1,
+      Name::root(),
+      vec![(
+        SvcParamKey::from(ODOH_CONFIG_SVC_PARAM_KEY),
+        SvcParamValue::Unknown(Unknown(vec![0, 1, 2])),
+      )],
+    );
+    msg.add_answer(Record::from_rdata(name, 300, RData::HTTPS(HTTPS(svcb))));
+    assert_eq!(odoh_configs_in_https_records(&msg), Some((vec![0, 1, 2], 300)));
+
+    let mut msg = dns_message::build_query("odoh.example.", RecordType::HTTPS).unwrap();
+    assert_eq!(odoh_configs_in_https_records(&msg), None);
+    let svcb = SVCB::new(1, Name::root(), vec![]);
+    msg.add_answer(Record::from_rdata(
+      Name::from_ascii("odoh.example.").unwrap(),
+      300,
+      RData::HTTPS(HTTPS(svcb)),
+    ));
+    assert_eq!(odoh_configs_in_https_records(&msg), None);
+  }
+
+  #[tokio::test]
+  async fn max_age_works() {
+    let headers = |v: &str| {
+      let mut headers = HeaderMap::new();
+      headers.insert(header::CACHE_CONTROL, v.parse().unwrap());
+      headers
+    };
+    assert_eq!(
+      max_age(&headers("public, max-age=86400")),
+      Some(Duration::from_secs(86400))
+    );
+    assert_eq!(
+      max_age(&headers("max-age=0")),
+      Some(Duration::from_secs(ODOH_CONFIG_MIN_REFRESH_SEC))
+    );
+    assert_eq!(max_age(&headers("no-cache")), None);
+    assert_eq!(max_age(&HeaderMap::new()), None);
+  }
+
+  #[tokio::test]
+  async fn entry_keeps_last_valid_config() {
+    let now = Instant::now();
+    let mut entry = ODoHConfigEntry::new(now);
+    assert!(entry.is_due(now));
+    assert!(entry.config(now).is_none());
+
+    // no valid config yet, retried with exponential backoff
+    entry.failed(now);
+    assert_eq!(entry.backoff, Some(Duration::from_secs(ODOH_CONFIG_RETRY_INITIAL_SEC)));
+    entry.failed(now);
+    assert_eq!(
+      entry.backoff,
+      Some(Duration::from_secs(ODOH_CONFIG_RETRY_INITIAL_SEC * 2))
+    );
+    assert!(!entry.is_due(now));
+    for _ in 0..20 {
+      entry.failed(now);
     }
+    assert_eq!(entry.backoff, Some(Duration::from_secs(ODOH_CONFIG_WATCH_DELAY)));
+
+    // a valid config is kept on failures until it expires
+    entry.config = Some(Arc::new(dummy_config()));
+    let max_age = Duration::from_secs(600);
+    entry.next_fetch_at = now + max_age;
+    entry.expires_at = now + max_age + Duration::from_secs(ODOH_CONFIG_MAX_STALE_SEC);
+    entry.backoff = None;
+    let stale = now + max_age;
+    assert!(entry.is_due(stale));
+    entry.failed(stale);
+    assert!(entry.config(stale).is_some());
+    assert_eq!(
+      entry.next_fetch_at,
+      stale + Duration::from_secs(ODOH_CONFIG_RETRY_INITIAL_SEC)
+    );
+
+    let expired = entry.expires_at;
+    assert!(entry.config(expired).is_none());
+    entry.failed(expired);
+    assert!(entry.config.is_none());
+  }
+
+  fn dummy_config() -> ODoHConfig {
+    use odoh_rs::{ObliviousDoHConfig, ObliviousDoHConfigs, ObliviousDoHKeyPair};
+    use rand::{rngs::StdRng, SeedableRng};
+    let key_pair = ObliviousDoHKeyPair::new(&mut StdRng::from_seed([0u8; 32]));
+    let configs = ObliviousDoHConfigs::from(vec![ObliviousDoHConfig::from(key_pair.public().clone())]);
+    ODoHConfig::new("target.example", &odoh_rs::compose(&configs).unwrap()).unwrap()
   }
 }
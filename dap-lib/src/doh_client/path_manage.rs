@@ -1,10 +1,17 @@
 use super::DoHType;
-use crate::{error::*, globals::Globals};
+use crate::{
+  constants::ODOH_REJECTION_THRESHOLD,
+  dns_stamp::DNS_STAMP_SCHEME,
+  error::*,
+  globals::{HopCompatibilityConfig, HopLabelConfig, ProxyConfig},
+  log::*,
+};
 use itertools::Itertools;
-use rand::Rng;
+use rand::{seq::SliceRandom, Rng};
+use rustc_hash::FxHashMap as HashMap;
 use std::sync::{
   atomic::{AtomicBool, Ordering},
-  Arc,
+  Arc, RwLock,
 };
 use url::Url;
 
@@ -13,12 +20,15 @@ use url::Url;
 enum Scheme {
   Http,
   Https,
+  /// DNS stamp of dnscrypt servers and relays
+  Sdns,
 }
 impl Scheme {
   pub fn as_str(&self) -> &'static str {
     match self {
       Scheme::Http => "http",
       Scheme::Https => "https",
+      Scheme::Sdns => DNS_STAMP_SCHEME,
     }
   }
 }
@@ -28,6 +38,7 @@ impl TryFrom<&str> for Scheme {
     match s {
       "http" => Ok(Self::Http),
       "https" => Ok(Self::Https),
+      DNS_STAMP_SCHEME => Ok(Self::Sdns),
       _ => Err(DapError::FailedToBuildDohUrl),
     }
   }
@@ -41,16 +52,42 @@ pub struct DoHTarget {
   path: String,
   /// scheme
   scheme: Scheme,
+  /// operator and jurisdiction labels
+  labels: Vec<String>,
+  /// authorities of relays from which the target accepts queries, `None` if not restricted
+  allowed_relays: Option<Vec<String>>,
 }
 impl DoHTarget {
+  /// build target from url
+  fn new(url: &Url, labels: Vec<String>) -> Self {
+    Self {
+      authority: url.authority().to_string(),
+      path: url.path().to_string(),
+      scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
+      labels,
+      allowed_relays: None,
+    }
+  }
   /// get authority
   pub fn authority(&self) -> &str {
     &self.authority
   }
+  /// get path
+  pub fn path(&self) -> &str {
+    &self.path
+  }
   /// get scheme
   pub fn scheme(&self) -> &str {
     self.scheme.as_str()
   }
+  /// build url of the target
+  pub fn url(&self) -> Url {
+    hop_url(&self.scheme, &self.authority, &self.path)
+  }
+  /// check if the target is the one of the given url
+  fn matches(&self, url: &Url) -> bool {
+    self.scheme.as_str() == url.scheme() && self.authority == url.authority() && self.path == url.path()
+  }
 }
 
 /// ODoH and MODoH relay
@@ -63,6 +100,128 @@ struct DoHRelay {
   scheme: Scheme,
   /// can be the next hop relay of a client
   can_be_next_hop: bool,
+  /// operator and jurisdiction labels
+  labels: Vec<String>,
+  /// authorities of targets to which the relay forwards queries, `None` if not restricted
+  allowed_targets: Option<Vec<String>>,
+  /// health flag of the relay itself
+  is_healthy: IsHealthy,
+}
+impl DoHRelay {
+  /// build relay from url
+  fn new(url: &Url, can_be_next_hop: bool, labels: Vec<String>) -> Self {
+    Self {
+      authority: url.authority().to_string(),
+      path: url.path().to_string(),
+      scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
+      can_be_next_hop,
+      labels,
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
+    }
+  }
+  /// build url of the relay
+  fn url(&self) -> Url {
+    hop_url(&self.scheme, &self.authority, &self.path)
+  }
+  /// check if the relay is the one of the given url
+  fn matches(&self, url: &Url) -> bool {
+    self.scheme.as_str() == url.scheme() && self.authority == url.authority() && self.path == url.path()
+  }
+  /// check if the relay can directly forward queries to the target as declared in the config
+  fn is_declared_compatible(&self, target: &DoHTarget) -> bool {
+    self
+      .allowed_targets
+      .as_ref()
+      .is_none_or(|v| v.contains(&target.authority))
+      && target
+        .allowed_relays
+        .as_ref()
+        .is_none_or(|v| v.contains(&self.authority))
+  }
+  /// check if the relay can be chained with the target and the next hop,
+  /// i.e., it is neither identical to them nor shares a label with them
+  fn can_be_chained(&self, target: &DoHTarget, nexthop: &DoHRelay) -> bool {
+    self.authority != target.authority
+      && self.authority != nexthop.authority
+      && !shares_label(&self.labels, &target.labels)
+      && !shares_label(&self.labels, &nexthop.labels)
+  }
+}
+
+/// Build url of a hop from its components, which are taken from a valid url
+fn hop_url(scheme: &Scheme, authority: &str, path: &str) -> Url {
+  let mut url = Url::parse(&format!("{}://{}", scheme.as_str(), authority)).unwrap();
+  url.set_path(path);
+  url
+}
+
+/// Build labels of the hop of the given url from the config, matched by authority
+fn hop_labels(url: &Url, hop_labels: &[HopLabelConfig]) -> Vec<String> {
+  hop_labels
+    .iter()
+    .filter(|config| config.urls.iter().any(|v| v.authority() == url.authority()))
+    .flat_map(|config| {
+      let operator = config
+        .operator
+        .as_ref()
+        .map(|v| format!("operator:{}", v.to_ascii_lowercase()));
+      let jurisdiction = config
+        .jurisdiction
+        .as_ref()
+        .map(|v| format!("jurisdiction:{}", v.to_ascii_lowercase()));
+      operator.into_iter().chain(jurisdiction)
+    })
+    .unique()
+    .collect()
+}
+
+/// Build labels of the hop of the given url from the configured exclusions, matched by authority,
+/// where hops in the same exclusion set share a label so that they are never placed in the same path
+fn exclusion_labels(url: &Url, exclusions: &[Vec<Url>]) -> Vec<String> {
+  exclusions
+    .iter()
+    .enumerate()
+    .filter(|(_, urls)| urls.iter().any(|v| v.authority() == url.authority()))
+    .map(|(idx, _)| format!("exclusion:{idx}"))
+    .collect()
+}
+
+/// Build authorities of the counterparts declared compatible with the hop of the given url,
+/// where `allowed_targets` is true for a relay and false for a target. `None` means not restricted.
+fn declared_counterparts(
+  url: &Url,
+  compatibility: &[HopCompatibilityConfig],
+  allowed_targets: bool,
+) -> Option<Vec<String>> {
+  let declared = compatibility
+    .iter()
+    .filter_map(|config| match config {
+      HopCompatibilityConfig::AllowedTargets { relay_url, target_urls } if allowed_targets => {
+        Some((relay_url, target_urls))
+      }
+      HopCompatibilityConfig::AllowedRelays { target_url, relay_urls } if !allowed_targets => {
+        Some((target_url, relay_urls))
+      }
+      _ => None,
+    })
+    .filter(|(hop_url, _)| hop_url.authority() == url.authority())
+    .collect::<Vec<_>>();
+  if declared.is_empty() {
+    return None;
+  }
+  Some(
+    declared
+      .into_iter()
+      .flat_map(|(_, urls)| urls.iter().map(|v| v.authority().to_string()))
+      .unique()
+      .collect(),
+  )
+}
+
+/// Check if two hops share any label
+fn shares_label(a: &[String], b: &[String]) -> bool {
+  a.iter().any(|label| b.contains(label))
 }
 
 /// struct representing a specific path to the target resolver
@@ -85,8 +244,7 @@ impl DoHPath {
         if !self.relays.is_empty() {
           return Err(DapError::FailedToBuildDohUrl);
         }
-        let mut url = Url::parse(&self.target.authority)?;
-        url.set_scheme(self.target.scheme.as_str()).unwrap();
+        let mut url = Url::parse(format!("{}://{}", self.target.scheme.as_str(), &self.target.authority).as_str())?;
         url.set_path(&self.target.path);
         Ok(url)
       }
@@ -111,6 +269,27 @@ impl DoHPath {
         }
         Ok(url)
       }
+      DoHType::ObliviousHttp => {
+        // the relay forwards to the gateway, and the target is given in the encapsulated request
+        if self.relays.len() != 1 || !self.relays[0].can_be_next_hop {
+          return Err(DapError::FailedToBuildDohUrl);
+        }
+        let mut url =
+          Url::parse(format!("{}://{}", &self.relays[0].scheme.as_str(), &self.relays[0].authority).as_str())?;
+        url.set_path(&self.relays[0].path);
+        Ok(url)
+      }
+      DoHType::DnsCrypt => {
+        // not an http url but the stamp of the server, with the stamp of the anonymized dnscrypt relay if relayed
+        if self.relays.len() > 1 {
+          return Err(DapError::FailedToBuildDohUrl);
+        }
+        let mut url = self.target.url();
+        if let Some(relay) = self.relays.first() {
+          url.query_pairs_mut().append_pair("relay", relay.url().as_str());
+        }
+        Ok(url)
+      }
     }
   }
 
@@ -126,6 +305,14 @@ impl DoHPath {
     false
   }
 
+  /// check if any two hops in the path, including the target, share an operator, jurisdiction or exclusion label
+  pub fn is_colluding(&self) -> bool {
+    std::iter::once(&self.target.labels)
+      .chain(self.relays.iter().map(|relay| &relay.labels))
+      .tuple_combinations()
+      .any(|(a, b)| shares_label(a, b))
+  }
+
   /// check if the path is healthy
   pub fn is_healthy(&self) -> bool {
     self.is_healthy.get()
@@ -145,6 +332,11 @@ impl DoHPath {
   pub fn target(&self) -> &Arc<DoHTarget> {
     &self.target
   }
+
+  /// Get url of the next-hop relay if relayed
+  pub fn nexthop_url(&self) -> Option<Url> {
+    self.relays.first().map(|relay| relay.url())
+  }
 }
 
 /// represents the health of a path
@@ -159,191 +351,772 @@ impl IsHealthy {
   fn make_unhealthy(&self) {
     self.0.store(false, Ordering::Relaxed);
   }
+  fn set(&self, healthy: bool) {
+    self.0.store(healthy, Ordering::Relaxed);
+  }
   fn get(&self) -> bool {
     self.0.load(Ordering::Relaxed)
   }
 }
 
-/// Manages all possible paths
-pub struct DoHPathManager {
-  /// all possible paths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Status of hops (targets and relays).
+/// Targets and next-hop relays are suspected to be down when all direct paths containing them are unhealthy,
+/// and intermediate relays are suspected when their probe paths via healthy targets and next hops fail.
+pub struct HopStatus {
+  /// authorities of targets suspected to be down
+  pub suspected_targets: Vec<String>,
+  /// authorities of relays suspected to be down
+  pub suspected_relays: Vec<String>,
+}
+impl HopStatus {
+  /// check if any hop is suspected to be down
+  pub fn has_suspects(&self) -> bool {
+    !self.suspected_targets.is_empty() || !self.suspected_relays.is_empty()
+  }
+}
+impl std::fmt::Display for HopStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "suspected targets: {:?}, suspected relays: {:?}",
+      self.suspected_targets, self.suspected_relays
+    )
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Urls of targets and relays, which describe hops of the path set
+pub struct HopUrls {
+  /// urls of targets
+  pub targets: Vec<Url>,
+  /// urls of next-hop relays
+  pub nexthops: Vec<Url>,
+  /// urls of intermediate relays
+  pub mid_relays: Vec<Url>,
+}
+impl HopUrls {
+  /// check if no hop is given
+  pub fn is_empty(&self) -> bool {
+    self.targets.is_empty() && self.nexthops.is_empty() && self.mid_relays.is_empty()
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Update of the path set at runtime, where hops both added and removed are removed
+pub struct PathSetUpdate {
+  /// hops to be added
+  pub add: HopUrls,
+  /// hops to be removed
+  pub remove: HopUrls,
+}
+impl PathSetUpdate {
+  /// Build the update from the current hops to the next ones
+  pub fn diff(current: &HopUrls, next: &HopUrls) -> Self {
+    let diff = |a: &[Url], b: &[Url]| a.iter().filter(|v| !b.contains(v)).cloned().collect::<Vec<_>>();
+    Self {
+      add: HopUrls {
+        targets: diff(&next.targets, &current.targets),
+        nexthops: diff(&next.nexthops, &current.nexthops),
+        mid_relays: diff(&next.mid_relays, &current.mid_relays),
+      },
+      remove: HopUrls {
+        targets: diff(&current.targets, &next.targets),
+        nexthops: diff(&current.nexthops, &next.nexthops),
+        mid_relays: diff(&current.mid_relays, &next.mid_relays),
+      },
+    }
+  }
+
+  /// check if nothing is updated
+  pub fn is_empty(&self) -> bool {
+    self.add.is_empty() && self.remove.is_empty()
+  }
+}
+
+/// Apply additions and removals to hops, where existing hops are kept as they are to preserve their health
+fn updated_hops<T: Clone>(
+  current: &[T],
+  added: &[Url],
+  removed: &[Url],
+  matches: impl Fn(&T, &Url) -> bool,
+  build: impl Fn(&Url) -> T,
+) -> Vec<T> {
+  let kept = current
+    .iter()
+    .filter(|hop| !removed.iter().any(|url| matches(hop, url)))
+    .cloned();
+  let new = added
+    .iter()
+    .unique()
+    .filter(|url| !removed.contains(url) && !current.iter().any(|hop| matches(hop, url)))
+    .map(build);
+  kept.chain(new).collect()
+}
+
+/// Set of targets, relays and direct paths built from them, which is swapped atomically on runtime updates
+pub struct PathSet {
+  /// target resolvers
+  targets: Vec<Arc<DoHTarget>>,
+  /// health flags of target resolvers, which are held apart since targets are used as keys of ODoH config store
+  target_health: Vec<Arc<IsHealthy>>,
+  /// next-hop relays, empty for the standard doh
+  nexthops: Vec<Arc<DoHRelay>>,
+  /// intermediate relays after the next hop for the multiple-relay-based odoh
+  mid_relays: Vec<Arc<DoHRelay>>,
+  /// direct paths without intermediate relays
   /// first dimension: depends on doh target resolver
-  /// second dimension: depends on next-hop relays. for the standard doh, its is one dimensional.
-  /// third dimension: actual paths. for the standard doh, its is one dimensional.
-  pub(super) paths: Vec<Vec<Vec<Arc<DoHPath>>>>,
+  /// second dimension: depends on next-hop relays. for the standard doh, it has the single path.
+  direct_paths: Vec<Vec<Arc<DoHPath>>>,
+}
+impl PathSet {
+  /// get target list
+  pub fn targets(&self) -> Vec<Arc<DoHTarget>> {
+    self.targets.clone()
+  }
+
+  /// get urls of hops
+  fn hop_urls(&self) -> HopUrls {
+    HopUrls {
+      targets: self.targets.iter().map(|v| v.url()).collect(),
+      nexthops: self.nexthops.iter().map(|v| v.url()).collect(),
+      mid_relays: self.mid_relays.iter().map(|v| v.url()).collect(),
+    }
+  }
+
+  /// Build direct paths without loop, collusion or declared incompatibility from targets and relays.
+  /// Direct paths in the previous set are reused for the same hops to keep their health.
+  fn build(
+    targets: Vec<(Arc<DoHTarget>, Arc<IsHealthy>)>,
+    nexthops: Vec<Arc<DoHRelay>>,
+    mid_relays: Vec<Arc<DoHRelay>>,
+    previous: Option<&PathSet>,
+    (direct_type, relayed_type): (&DoHType, &DoHType),
+  ) -> Self {
+    let direct_path = |target: &Arc<DoHTarget>, nexthop: Option<&Arc<DoHRelay>>| {
+      let reused = previous.and_then(|prev| {
+        prev
+          .direct_paths
+          .iter()
+          .flatten()
+          .find(|path| {
+            Arc::ptr_eq(&path.target, target)
+              && match (path.relays.first(), nexthop) {
+                (None, None) => true,
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                _ => false,
+              }
+          })
+          .cloned()
+      });
+      reused.unwrap_or_else(|| {
+        Arc::new(DoHPath {
+          target: target.clone(),
+          relays: nexthop.into_iter().cloned().collect(),
+          is_healthy: IsHealthy::new(),
+          doh_type: match nexthop {
+            None => direct_type.clone(),
+            Some(_) => relayed_type.clone(),
+          },
+        })
+      })
+    };
+
+    let (targets, direct_paths): (Vec<_>, Vec<_>) = targets
+      .into_iter()
+      .map(|(target, is_healthy)| {
+        if nexthops.is_empty() {
+          let path = direct_path(&target, None);
+          return ((target, is_healthy), vec![path]);
+        }
+        let per_target = nexthops
+          .iter()
+          .map(|nexthop| direct_path(&target, Some(nexthop)))
+          .filter(|path| !path.is_looped() && !path.is_colluding())
+          .filter(|path| path.relays[0].is_declared_compatible(&path.target))
+          .collect::<Vec<_>>();
+        ((target, is_healthy), per_target)
+      })
+      .filter(|(_, per_target)| !per_target.is_empty())
+      .unzip();
+    let (targets, target_health) = targets.into_iter().unzip();
+
+    Self {
+      targets,
+      target_health,
+      nexthops,
+      mid_relays,
+      direct_paths,
+    }
+  }
+}
+
+/// Manages targets and relays, and samples paths from them on demand.
+/// Only direct paths (target, or next hop to target) are materialized.
+/// Paths via intermediate relays are sampled lazily for each query, since their number grows factorially.
+/// Targets and relays can be added or removed at runtime, where the path set is swapped atomically for readers.
+pub struct DoHPathManager {
+  /// current set of targets, relays and direct paths
+  path_set: RwLock<Arc<PathSet>>,
+  /// operator and jurisdiction labels, applied also to hops added at runtime
+  hop_labels: Vec<HopLabelConfig>,
+  /// sets of hops never placed in the same path, applied also to hops added at runtime
+  exclusions: Vec<Vec<Url>>,
+  /// declared compatibility of relays and targets, applied also to hops added at runtime
+  hop_compatibility: Vec<HopCompatibilityConfig>,
+  /// odoh, modoh or oblivious http, where next-hop relays are required
+  is_oblivious: bool,
+  /// doh type of paths without relays, standard doh or dnscrypt
+  direct_type: DoHType,
+  /// doh type of paths via relays, odoh, oblivious http or anonymized dnscrypt
+  relayed_type: DoHType,
+  /// minimum number of intermediate relays in a sampled path
+  min_mid_relays: usize,
+  /// maximum number of intermediate relays in a sampled path
+  max_mid_relays: usize,
   /// target randomization
   target_randomization: bool,
   /// next-hop randomization
   nexthop_randomization: bool,
+  /// status of hops inferred from the health checks
+  hop_status: RwLock<HopStatus>,
+  /// consecutive rejections for pairs of (relay, target) authorities,
+  /// where pairs reaching the threshold are learned as incompatible
+  rejections: RwLock<HashMap<(String, String), usize>>,
 }
 impl DoHPathManager {
+  /// get the current path set
+  pub fn path_set(&self) -> Arc<PathSet> {
+    self.path_set.read().unwrap().clone()
+  }
+
   /// get target list
   pub fn targets(&self) -> Vec<Arc<DoHTarget>> {
-    self
-      .paths
-      .iter()
-      .map(|per_target| per_target[0][0].target.clone())
-      .collect::<Vec<_>>()
+    self.path_set().targets()
   }
-  /// get a healthy path according to the randomization policy
-  pub fn get_path(&self) -> Option<Arc<DoHPath>> {
-    let healthy_paths = self
-      .paths
+
+  /// get urls of current targets and relays
+  pub fn hop_urls(&self) -> HopUrls {
+    self.path_set().hop_urls()
+  }
+
+  /// Get urls of first hops to which the client connects, i.e., next-hop relays for odoh, targets otherwise
+  pub fn endpoints(&self) -> Vec<Url> {
+    // dnscrypt servers and relays are reached by ip addresses in their stamps
+    if let DoHType::DnsCrypt = self.direct_type {
+      return vec![];
+    }
+    let hop_urls = self.hop_urls();
+    if self.is_oblivious {
+      hop_urls.nexthops
+    } else {
+      hop_urls.targets
+    }
+  }
+
+  /// Build a new path set by applying the update to the current one, without swapping it.
+  /// Existing hops and direct paths are kept in the new set, so their health is preserved.
+  /// Updates must be serialized by the caller from `stage` to `commit`.
+  pub fn stage(&self, update: &PathSetUpdate) -> Result<PathSet> {
+    let invalid = |e: &str| DapError::InvalidPathSetUpdate(e.to_string());
+    if !self.is_oblivious && (!update.add.nexthops.is_empty() || !update.add.mid_relays.is_empty()) {
+      return Err(invalid("relays cannot be added for the standard doh"));
+    }
+    if self.max_mid_relays == 0 && !update.add.mid_relays.is_empty() {
+      return Err(invalid("intermediate relays are not enabled"));
+    }
+
+    let current = self.path_set();
+    let targets = updated_hops(
+      &current
+        .targets
+        .iter()
+        .cloned()
+        .zip(current.target_health.iter().cloned())
+        .collect::<Vec<_>>(),
+      &update.add.targets,
+      &update.remove.targets,
+      |(target, _), url| target.matches(url),
+      |url| (self.new_target(url), Arc::new(IsHealthy::new())),
+    );
+    let nexthops = updated_hops(
+      &current.nexthops,
+      &update.add.nexthops,
+      &update.remove.nexthops,
+      |relay, url| relay.matches(url),
+      |url| self.new_relay(url, true),
+    );
+    let mid_relays = updated_hops(
+      &current.mid_relays,
+      &update.add.mid_relays,
+      &update.remove.mid_relays,
+      |relay, url| relay.matches(url),
+      |url| self.new_relay(url, false),
+    );
+    if targets.is_empty() {
+      return Err(invalid("no target remains"));
+    }
+    if self.is_oblivious && nexthops.is_empty() {
+      return Err(invalid("no next-hop relay remains"));
+    }
+    if mid_relays.len() < self.min_mid_relays {
+      return Err(invalid("intermediate relays are fewer than the minimum"));
+    }
+
+    let path_set = PathSet::build(
+      targets,
+      nexthops,
+      mid_relays,
+      Some(&current),
+      (&self.direct_type, &self.relayed_type),
+    );
+    if path_set.targets.is_empty() {
+      return Err(invalid("no path is available"));
+    }
+    Ok(path_set)
+  }
+
+  /// Swap the path set atomically, where readers holding the previous one are not affected
+  pub fn commit(&self, path_set: PathSet) {
+    *self.path_set.write().unwrap() = Arc::new(path_set);
+  }
+
+  /// Get paths to be health-checked, keyed by a stable identifier.
+  /// These are all direct paths, plus a path sampled for each intermediate relay
+  /// via a healthy next hop and target, which probes the intermediate relay.
+  pub fn healthcheck_paths(&self) -> Vec<(String, Arc<DoHPath>)> {
+    let set = self.path_set();
+    let mut paths = set
+      .direct_paths
       .iter()
-      .map(|per_target| {
+      .flatten()
+      .filter(|path| self.is_path_compatible(path))
+      .filter_map(|path| path.as_url().ok().map(|url| (url.to_string(), path.clone())))
+      .collect::<Vec<_>>();
+
+    let mut rng = rand::thread_rng();
+    for mid_relay in set.mid_relays.iter() {
+      let candidates = set
+        .direct_paths
+        .iter()
+        .flatten()
+        .filter(|path| mid_relay.can_be_chained(&path.target, &path.relays[0]))
+        .filter(|path| self.is_compatible(mid_relay, &path.target))
+        .collect::<Vec<_>>();
+      // prefer healthy direct paths so that the result reflects the health of the intermediate relay
+      let healthy = candidates
+        .iter()
+        .filter(|path| self.is_usable(&set, path))
+        .collect::<Vec<_>>();
+      let Some(base) = healthy
+        .choose(&mut rng)
+        .copied()
+        .or_else(|| candidates.choose(&mut rng))
+      else {
+        continue;
+      };
+      let mut relays = base.relays.clone();
+      relays.push(mid_relay.clone());
+      let probe = Arc::new(DoHPath {
+        target: base.target.clone(),
+        relays,
+        is_healthy: IsHealthy::new(),
+        doh_type: DoHType::Oblivious,
+      });
+      paths.push((format!("mid-relay {}", mid_relay.authority), probe));
+    }
+    paths
+  }
+
+  /// Update the health of targets and relays from the results of health checks, and get the status of hops.
+  /// Targets and next hops are healthy if any direct path containing them is healthy.
+  /// Intermediate relays are updated from the given checked probe paths,
+  /// where failure is attributed to the intermediate relay only when the rest of the probe path is healthy.
+  pub fn update_hop_status(&self, checked: &[Arc<DoHPath>]) -> HopStatus {
+    let set = self.path_set();
+    for (is_healthy, per_target) in set.target_health.iter().zip(set.direct_paths.iter()) {
+      is_healthy.set(
         per_target
           .iter()
-          .map(|per_next_hop| {
-            per_next_hop
-              .iter()
-              .filter(|path| path.is_healthy())
-              .cloned()
-              .collect::<Vec<_>>()
-          })
-          .filter(|per_next_hop| !per_next_hop.is_empty())
-          .collect::<Vec<_>>()
-      })
-      .filter(|per_target| !per_target.is_empty())
-      .collect::<Vec<_>>();
+          .any(|path| path.is_healthy() && self.is_path_compatible(path)),
+      );
+    }
+    for nexthop in set.nexthops.iter() {
+      let healthy = set
+        .direct_paths
+        .iter()
+        .flatten()
+        .filter(|path| path.relays.iter().any(|relay| Arc::ptr_eq(relay, nexthop)))
+        .any(|path| path.is_healthy() && self.is_path_compatible(path));
+      nexthop.is_healthy.set(healthy);
+    }
+    for probe in checked.iter().filter(|path| path.relays.len() > 1) {
+      let (nexthop, mid_relays) = (&probe.relays[0], &probe.relays[1..]);
+      if probe.is_healthy() {
+        mid_relays.iter().for_each(|relay| relay.is_healthy.make_healthy());
+      } else if is_target_healthy(&set, &probe.target) && nexthop.is_healthy.get() {
+        mid_relays.iter().for_each(|relay| relay.is_healthy.make_unhealthy());
+      }
+    }
 
-    if healthy_paths.is_empty() {
-      return None;
+    let suspected = |authorities: Vec<&String>| authorities.into_iter().cloned().sorted().collect::<Vec<_>>();
+    let hop_status = HopStatus {
+      suspected_targets: suspected(
+        set
+          .targets
+          .iter()
+          .filter(|target| !is_target_healthy(&set, target))
+          .map(|target| &target.authority)
+          .collect(),
+      ),
+      suspected_relays: suspected(
+        set
+          .nexthops
+          .iter()
+          .chain(set.mid_relays.iter())
+          .filter(|relay| !relay.is_healthy.get())
+          .map(|relay| &relay.authority)
+          .unique()
+          .collect(),
+      ),
+    };
+    *self.hop_status.write().unwrap() = hop_status.clone();
+    hop_status
+  }
+
+  /// get status of hops
+  pub fn hop_status(&self) -> HopStatus {
+    self.hop_status.read().unwrap().clone()
+  }
+
+  /// check if the direct path and all hops in it are healthy, and the path is not known to be incompatible
+  fn is_usable(&self, set: &PathSet, path: &DoHPath) -> bool {
+    path.is_healthy()
+      && is_target_healthy(set, &path.target)
+      && path.relays.iter().all(|relay| relay.is_healthy.get())
+      && self.is_path_compatible(path)
+  }
+
+  /// Check if the relay can directly forward queries to the target,
+  /// i.e., it is declared compatible and not learned as incompatible at runtime
+  fn is_compatible(&self, relay: &DoHRelay, target: &DoHTarget) -> bool {
+    if !relay.is_declared_compatible(target) {
+      return false;
     }
-    let mut rng = rand::thread_rng();
-    let target_idx = if self.target_randomization {
-      rng.gen_range(0..healthy_paths.len())
-    } else {
-      0
+    let rejections = self.rejections.read().unwrap();
+    rejections
+      .get(&(relay.authority.clone(), target.authority.clone()))
+      .is_none_or(|count| *count < ODOH_REJECTION_THRESHOLD)
+  }
+
+  /// check if the relay directly preceding the target is compatible with the target
+  fn is_path_compatible(&self, path: &DoHPath) -> bool {
+    path
+      .relays
+      .last()
+      .is_none_or(|relay| self.is_compatible(relay, &path.target))
+  }
+
+  /// Record a rejection of the path by relays, like 403 for a target not allowed.
+  /// The relay directly preceding the target and the target are learned as incompatible after consecutive rejections.
+  pub fn report_rejection(&self, path: &DoHPath) {
+    let Some(relay) = path.relays.last() else {
+      return;
     };
-    let nexthop_idx = if self.nexthop_randomization {
-      rng.gen_range(0..healthy_paths[target_idx].len())
-    } else {
-      0
+    let mut rejections = self.rejections.write().unwrap();
+    let count = rejections
+      .entry((relay.authority.clone(), path.target.authority.clone()))
+      .or_default();
+    *count += 1;
+    if *count == ODOH_REJECTION_THRESHOLD {
+      warn!(
+        "Relay {} is learned as incompatible with target {}, and excluded from path selection",
+        relay.authority, path.target.authority
+      );
+    }
+  }
+
+  /// Reset the rejection count of the path on success
+  pub fn report_acceptance(&self, path: &DoHPath) {
+    let Some(relay) = path.relays.last() else {
+      return;
     };
-    let path_idx = rng.gen_range(0..healthy_paths[target_idx][nexthop_idx].len());
-    Some(healthy_paths[target_idx][nexthop_idx][path_idx].clone())
+    let key = (relay.authority.clone(), path.target.authority.clone());
+    if !self.rejections.read().unwrap().contains_key(&key) {
+      return;
+    }
+    self.rejections.write().unwrap().remove(&key);
   }
 
-  /// build all possible paths without loop
-  pub fn new(globals: &Arc<Globals>) -> Result<Self> {
-    let targets = globals.proxy_config.target_config.doh_target_urls.iter().map(|url| {
-      Arc::new(DoHTarget {
-        authority: url.authority().to_string(),
-        path: url.path().to_string(),
-        scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
-      })
-    });
+  /// check if the intermediate relay can be used in the path to the target via the next hop
+  fn is_eligible_mid_relay(&self, relay: &DoHRelay, target: &DoHTarget, nexthop: &DoHRelay) -> bool {
+    relay.is_healthy.get() && relay.can_be_chained(target, nexthop)
+  }
 
-    // standard doh
-    if globals.proxy_config.nexthop_relay_config.is_none() {
-      let paths = targets
-        .map(|target| {
-          vec![vec![Arc::new(DoHPath {
-            target,
-            relays: vec![],
-            is_healthy: IsHealthy::new(),
-            doh_type: DoHType::Standard,
-          })]]
-        })
-        .collect::<Vec<_>>();
-      return Ok(Self {
-        paths,
-        target_randomization: globals.proxy_config.target_config.target_randomization,
-        nexthop_randomization: false,
-      });
+  /// Sample intermediate relays in random order, where the number is uniformly chosen within the configured range.
+  /// The last one must be compatible with the target, and relays sharing a label with already sampled ones are skipped.
+  /// Returns `None` if eligible relays are fewer than the minimum.
+  fn sample_mid_relays(
+    &self,
+    set: &PathSet,
+    target: &DoHTarget,
+    nexthop: &DoHRelay,
+    rng: &mut impl Rng,
+  ) -> Option<Vec<Arc<DoHRelay>>> {
+    let mut eligible = set
+      .mid_relays
+      .iter()
+      .filter(|relay| self.is_eligible_mid_relay(relay, target, nexthop))
+      .collect::<Vec<_>>();
+    if eligible.len() < self.min_mid_relays {
+      return None;
     }
+    let max = self.max_mid_relays.min(eligible.len());
+    let num = rng.gen_range(self.min_mid_relays..=max);
+    eligible.shuffle(rng);
 
-    // odoh and modoh
-    let nexthop_relay_config = globals.proxy_config.nexthop_relay_config.as_ref().unwrap();
-    let nexthops = nexthop_relay_config.odoh_relay_urls.iter().map(|url| {
-      Arc::new(DoHRelay {
-        authority: url.authority().to_string(),
-        path: url.path().to_string(),
-        scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
-        can_be_next_hop: true,
-      })
-    });
-    let subseq_relay_config = globals.proxy_config.subseq_relay_config.as_ref();
-    let subseq_relay_paths = subseq_relay_config.map(|v| {
-      let subseq_relays = v.mid_relay_urls.iter().map(|url| {
-        Arc::new(DoHRelay {
-          authority: url.authority().to_string(),
-          path: url.path().to_string(),
-          scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
-          can_be_next_hop: false,
-        })
-      });
-      let max = v.max_mid_relays.max(subseq_relays.len());
-      let mut paths_after_nexthop = vec![];
-      (0..max + 1).for_each(|num| {
-        let x: Vec<_> = subseq_relays.clone().permutations(num).collect();
-        paths_after_nexthop.extend(x);
-      });
-      paths_after_nexthop
-    });
-    let relay_paths = nexthops.clone().map(|nexthop| {
-      let relays = match &subseq_relay_paths {
-        None => vec![vec![nexthop.clone()]],
-        Some(subseq_relay_paths) => subseq_relay_paths
-          .iter()
-          .map(|subseq_relay_path| {
-            let mut relays = vec![nexthop.clone()];
-            relays.extend(subseq_relay_path.clone());
-            relays
-          })
-          .collect::<Vec<_>>(),
+    let mut sampled: Vec<Arc<DoHRelay>> = Vec::with_capacity(num);
+    if num > 0 {
+      let Some(last_idx) = eligible.iter().position(|relay| self.is_compatible(relay, target)) else {
+        return (self.min_mid_relays == 0).then(Vec::new);
       };
-      relays
-    });
-
-    // build path object
-    let maybe_looped_paths = targets.map(|target| {
-      relay_paths
-        .clone()
-        .map(|relay_path| {
-          relay_path
-            .iter()
-            .map(|relays| {
-              Arc::new(DoHPath {
-                target: target.clone(),
-                relays: relays.clone(),
-                is_healthy: IsHealthy::new(),
-                doh_type: DoHType::Oblivious,
-              })
-            })
-            .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>()
-    });
+      sampled.push(eligible.remove(last_idx).clone());
+    }
+    for relay in eligible {
+      if sampled.len() == num {
+        break;
+      }
+      if sampled.iter().any(|v| shares_label(&v.labels, &relay.labels)) {
+        continue;
+      }
+      sampled.push(relay.clone());
+    }
+    if sampled.len() < self.min_mid_relays {
+      return None;
+    }
+    // place the one compatible with the target at last
+    let len = sampled.len();
+    sampled.rotate_left(1.min(len));
+    Some(sampled)
+  }
 
-    // remove looped paths
-    let loop_free_paths = maybe_looped_paths
+  /// get a healthy path according to the randomization policy.
+  /// If intermediate relays cannot be sampled for the chosen target and next hop, other usable ones are tried in turn.
+  pub fn get_path(&self) -> Option<Arc<DoHPath>> {
+    let set = self.path_set();
+    let mut usable_paths = set
+      .direct_paths
+      .iter()
       .map(|per_target| {
-        let loop_free = per_target.iter().map(|per_next_hop| {
-          per_next_hop
-            .iter()
-            .filter(|path| !path.is_looped())
-            .cloned()
-            .collect::<Vec<_>>()
-        });
-        loop_free
-          .filter(|per_next_hop| !per_next_hop.is_empty())
+        per_target
+          .iter()
+          .filter(|path| self.is_usable(&set, path))
           .collect::<Vec<_>>()
       })
       .filter(|per_target| !per_target.is_empty())
       .collect::<Vec<_>>();
 
-    Ok(Self {
-      paths: loop_free_paths,
-      target_randomization: globals.proxy_config.target_config.target_randomization,
-      nexthop_randomization: nexthop_relay_config.odoh_relay_randomization,
+    let mut rng = rand::thread_rng();
+    if self.target_randomization {
+      usable_paths.shuffle(&mut rng);
+    }
+    if self.nexthop_randomization {
+      usable_paths
+        .iter_mut()
+        .for_each(|per_target| per_target.shuffle(&mut rng));
+    }
+    if set.mid_relays.is_empty() || self.max_mid_relays == 0 {
+      return usable_paths.first().map(|per_target| per_target[0].clone());
+    }
+
+    // sample intermediate relays after the next hop
+    for direct_path in usable_paths.into_iter().flatten() {
+      let nexthop = &direct_path.relays[0];
+      let Some(mid_relays) = self.sample_mid_relays(&set, &direct_path.target, nexthop, &mut rng) else {
+        debug!(
+          "Not enough healthy intermediate relays for {} via {}",
+          direct_path.target.authority, nexthop.authority
+        );
+        continue;
+      };
+      if mid_relays.is_empty() {
+        return Some(direct_path.clone());
+      }
+      let mut relays = vec![nexthop.clone()];
+      relays.extend(mid_relays);
+      return Some(Arc::new(DoHPath {
+        target: direct_path.target.clone(),
+        relays,
+        is_healthy: IsHealthy::new(),
+        doh_type: DoHType::Oblivious,
+      }));
+    }
+    None
+  }
+
+  /// check if any path is available
+  pub fn has_healthy_path(&self) -> bool {
+    self.get_path().is_some()
+  }
+
+  /// build target from url with labels and declared compatibility
+  fn new_target(&self, url: &Url) -> Arc<DoHTarget> {
+    Arc::new(DoHTarget {
+      allowed_relays: declared_counterparts(url, &self.hop_compatibility, false),
+      ..DoHTarget::new(url, self.labels(url))
+    })
+  }
+
+  /// build relay from url with labels and declared compatibility
+  fn new_relay(&self, url: &Url, can_be_next_hop: bool) -> Arc<DoHRelay> {
+    Arc::new(DoHRelay {
+      allowed_targets: declared_counterparts(url, &self.hop_compatibility, true),
+      ..DoHRelay::new(url, can_be_next_hop, self.labels(url))
     })
   }
+
+  /// build labels of the hop from operator and jurisdiction labels and exclusions
+  fn labels(&self, url: &Url) -> Vec<String> {
+    let mut labels = hop_labels(url, &self.hop_labels);
+    labels.extend(exclusion_labels(url, &self.exclusions));
+    labels
+  }
+
+  /// build targets and relays from the config
+  pub fn new(proxy_config: &ProxyConfig) -> Result<Self> {
+    let target_config = &proxy_config.target_config;
+    let (nexthop_urls, nexthop_randomization, direct_type, relayed_type) = match (
+      proxy_config.nexthop_relay_config.as_ref(),
+      proxy_config.oblivious_http_config.as_ref(),
+      proxy_config.dnscrypt_config.as_ref(),
+    ) {
+      // odoh and modoh
+      (Some(v), _, _) => (
+        v.odoh_relay_urls.clone(),
+        v.odoh_relay_randomization,
+        DoHType::Standard,
+        DoHType::Oblivious,
+      ),
+      // oblivious http
+      (None, Some(v), _) => (
+        v.relay_urls.clone(),
+        v.relay_randomization,
+        DoHType::Standard,
+        DoHType::ObliviousHttp,
+      ),
+      // dnscrypt and anonymized dnscrypt
+      (None, None, Some(v)) => (
+        v.relay_urls.clone(),
+        v.relay_randomization,
+        DoHType::DnsCrypt,
+        DoHType::DnsCrypt,
+      ),
+      // standard doh
+      (None, None, None) => (vec![], false, DoHType::Standard, DoHType::Oblivious),
+    };
+    let (mid_relay_urls, range, exclusions) = match proxy_config.subseq_relay_config.as_ref() {
+      Some(v) if proxy_config.nexthop_relay_config.is_some() => (
+        v.mid_relay_urls.clone(),
+        (v.min_mid_relays, v.max_mid_relays),
+        v.exclusions.clone(),
+      ),
+      _ => (vec![], (0, 0), vec![]),
+    };
+
+    let mut manager = Self {
+      hop_labels: proxy_config.hop_labels.clone(),
+      exclusions,
+      hop_compatibility: proxy_config.hop_compatibility.clone(),
+      ..Self::build(
+        vec![],
+        vec![],
+        vec![],
+        range,
+        target_config.target_randomization,
+        nexthop_randomization,
+      )
+    };
+    manager.is_oblivious = proxy_config.nexthop_relay_config.is_some()
+      || proxy_config.oblivious_http_config.is_some()
+      || !nexthop_urls.is_empty();
+    manager.direct_type = direct_type;
+    manager.relayed_type = relayed_type;
+    let targets = target_config
+      .doh_target_urls
+      .iter()
+      .map(|url| (manager.new_target(url), Arc::new(IsHealthy::new())))
+      .collect();
+    let nexthops = nexthop_urls.iter().map(|url| manager.new_relay(url, true)).collect();
+    let mid_relays = mid_relay_urls.iter().map(|url| manager.new_relay(url, false)).collect();
+    let path_set = PathSet::build(
+      targets,
+      nexthops,
+      mid_relays,
+      None,
+      (&manager.direct_type, &manager.relayed_type),
+    );
+    // every target may be filtered out as looped, colluding or incompatible with next hops
+    if path_set.targets.is_empty() {
+      error!("No path is available from the given targets and relays");
+      return Err(DapError::NoPathAvailable);
+    }
+    manager.commit(path_set);
+    Ok(manager)
+  }
+
+  /// build the manager of direct paths from targets and relays
+  fn build(
+    targets: Vec<Arc<DoHTarget>>,
+    nexthops: Vec<Arc<DoHRelay>>,
+    mid_relays: Vec<Arc<DoHRelay>>,
+    (min_mid_relays, max_mid_relays): (usize, usize),
+    target_randomization: bool,
+    nexthop_randomization: bool,
+  ) -> Self {
+    let targets = targets
+      .into_iter()
+      .map(|target| (target, Arc::new(IsHealthy::new())))
+      .collect();
+    Self {
+      is_oblivious: !nexthops.is_empty(),
+      direct_type: DoHType::Standard,
+      relayed_type: DoHType::Oblivious,
+      path_set: RwLock::new(Arc::new(PathSet::build(
+        targets,
+        nexthops,
+        mid_relays,
+        None,
+        (&DoHType::Standard, &DoHType::Oblivious),
+      ))),
+      hop_labels: vec![],
+      exclusions: vec![],
+      hop_compatibility: vec![],
+      min_mid_relays,
+      max_mid_relays: max_mid_relays.max(min_mid_relays),
+      target_randomization,
+      nexthop_randomization,
+      hop_status: RwLock::new(HopStatus::default()),
+      rejections: RwLock::new(HashMap::default()),
+    }
+  }
+}
+
+/// check if the target is healthy in the path set
+fn is_target_healthy(set: &PathSet, target: &Arc<DoHTarget>) -> bool {
+  set
+    .targets
+    .iter()
+    .position(|v| Arc::ptr_eq(v, target))
+    .map(|idx| set.target_health[idx].get())
+    .unwrap_or(false)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::globals::{
+    DnsCryptConfig, NextHopRelayConfig, ODoHPaddingPolicy, ObliviousHttpConfig, SubseqRelayConfig, TargetConfig,
+  };
   use urlencoding::decode;
 
   #[tokio::test]
@@ -352,24 +1125,35 @@ mod tests {
       authority: "dns.google".to_string(),
       path: "/dns-query".to_string(),
       scheme: Scheme::Https,
+      labels: vec![],
+      allowed_relays: None,
     });
     let relay1 = Arc::new(DoHRelay {
       authority: "relay1.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let relay2 = Arc::new(DoHRelay {
       authority: "relay2.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let relay3 = Arc::new(DoHRelay {
       authority: "relay3.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let path = Arc::new(DoHPath {
       target,
@@ -389,24 +1173,35 @@ mod tests {
       authority: "dns.google".to_string(),
       path: "/dns-query".to_string(),
       scheme: Scheme::Https,
+      labels: vec![],
+      allowed_relays: None,
     });
     let relay1 = Arc::new(DoHRelay {
       authority: "relay1.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let relay2 = Arc::new(DoHRelay {
       authority: "relay2.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let relay3 = Arc::new(DoHRelay {
       authority: "relay3.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
     let mut path = DoHPath {
       target,
@@ -421,9 +1216,460 @@ mod tests {
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      labels: vec![],
+      allowed_targets: None,
+      is_healthy: IsHealthy::new(),
     });
 
     path.relays.push(relay4);
     assert!(path.is_looped());
   }
+
+  fn url(s: &str) -> Url {
+    Url::parse(s).unwrap()
+  }
+
+  #[tokio::test]
+  async fn build_standard_url_works() {
+    let target = Arc::new(DoHTarget::new(&url("https://dns.google/dns-query"), vec![]));
+    let manager = DoHPathManager::build(vec![target], vec![], vec![], (0, 0), false, false);
+    let path = manager.get_path().unwrap();
+    assert_eq!(path.as_url().unwrap().as_str(), "https://dns.google/dns-query");
+  }
+
+  #[tokio::test]
+  async fn build_ohttp_url_works() {
+    let proxy_config = ProxyConfig {
+      target_config: TargetConfig {
+        doh_target_urls: vec![url("https://dns.google/dns-query")],
+        ..Default::default()
+      },
+      oblivious_http_config: Some(ObliviousHttpConfig {
+        relay_urls: vec![url("https://relay.example/gateway")],
+        relay_randomization: false,
+        gateway_key_url: url("https://gateway.example/.well-known/ohttp-gateway"),
+      }),
+      ..Default::default()
+    };
+    let manager = DoHPathManager::new(&proxy_config).unwrap();
+    assert_eq!(manager.endpoints(), vec![url("https://relay.example/gateway")]);
+    // the target is not given in the url but in the encapsulated request
+    let path = manager.get_path().unwrap();
+    assert_eq!(path.as_url().unwrap().as_str(), "https://relay.example/gateway");
+    assert_eq!(path.target().authority(), "dns.google");
+  }
+
+  #[tokio::test]
+  async fn build_dnscrypt_url_works() {
+    let server = "sdns://AQAAAAAAAAAACTE5Mi4wLjIuMSAHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBxsyLmRuc2NyeXB0LWNlcnQuZXhhbXBsZS5jb20";
+    let relay = "sdns://gQwxOTguNTEuMTAwLjE";
+    let proxy_config = ProxyConfig {
+      target_config: TargetConfig {
+        doh_target_urls: vec![url(server)],
+        ..Default::default()
+      },
+      dnscrypt_config: Some(DnsCryptConfig {
+        relay_urls: vec![url(relay)],
+        relay_randomization: false,
+      }),
+      ..Default::default()
+    };
+    let manager = DoHPathManager::new(&proxy_config).unwrap();
+    // stamps are not http endpoints, and case-sensitive stamps are kept as they are
+    assert!(manager.endpoints().is_empty());
+    let path = manager.get_path().unwrap();
+    assert_eq!(path.target().url().as_str(), server);
+    assert_eq!(path.nexthop_url().unwrap().as_str(), relay);
+    assert!(path.as_url().unwrap().as_str().starts_with(server));
+
+    // direct dnscrypt without relays
+    let proxy_config = ProxyConfig {
+      dnscrypt_config: Some(DnsCryptConfig {
+        relay_urls: vec![],
+        relay_randomization: false,
+      }),
+      ..proxy_config
+    };
+    let manager = DoHPathManager::new(&proxy_config).unwrap();
+    let path = manager.get_path().unwrap();
+    assert!(path.nexthop_url().is_none());
+    assert_eq!(path.as_url().unwrap().as_str(), server);
+  }
+
+  #[tokio::test]
+  async fn lazy_sampling_works() {
+    let targets = vec![Arc::new(DoHTarget::new(
+      &url("https://target.dns.google/dns-query"),
+      vec![],
+    ))];
+    let nexthops = vec![
+      Arc::new(DoHRelay::new(&url("https://relay1.dns.google/proxy"), true, vec![])),
+      Arc::new(DoHRelay::new(&url("https://target.dns.google/proxy"), true, vec![])),
+    ];
+    let mid_relays = (0..8)
+      .map(|i| {
+        Arc::new(DoHRelay::new(
+          &url(&format!("https://mid{i}.dns.google/proxy")),
+          false,
+          vec![],
+        ))
+      })
+      .collect::<Vec<_>>();
+    let manager = DoHPathManager::build(targets, nexthops, mid_relays.clone(), (1, 3), false, true);
+
+    // looped direct path is excluded, and only direct paths and probes for mid relays are checked
+    assert_eq!(manager.path_set().direct_paths[0].len(), 1);
+    assert_eq!(manager.healthcheck_paths().len(), 1 + 8);
+
+    for _ in 0..100 {
+      let path = manager.get_path().unwrap();
+      assert!((2..=4).contains(&path.relays.len()));
+      assert!(!path.is_looped());
+    }
+
+    // unhealthy mid relays are never sampled
+    mid_relays[1..]
+      .iter()
+      .for_each(|relay| relay.is_healthy.make_unhealthy());
+    for _ in 0..100 {
+      let path = manager.get_path().unwrap();
+      assert_eq!(path.relays.len(), 2);
+      assert_eq!(path.relays[1].authority, "mid0.dns.google");
+    }
+    mid_relays[0].is_healthy.make_unhealthy();
+    assert!(manager.get_path().is_none());
+  }
+
+  #[tokio::test]
+  async fn exclusions_and_fallback_work() {
+    let modoh_config = |targets: &[&str], exclusions: Vec<Vec<Url>>| ProxyConfig {
+      target_config: TargetConfig {
+        doh_target_urls: targets.iter().map(|v| url(v)).collect(),
+        target_randomization: true,
+        ..Default::default()
+      },
+      nexthop_relay_config: Some(NextHopRelayConfig {
+        odoh_relay_urls: vec![url("https://relay.example/proxy")],
+        odoh_relay_randomization: false,
+        odoh_config_via_dns: false,
+        odoh_padding: ODoHPaddingPolicy::None,
+      }),
+      subseq_relay_config: Some(SubseqRelayConfig {
+        mid_relay_urls: vec![url("https://mid.example/proxy")],
+        min_mid_relays: 1,
+        max_mid_relays: 1,
+        exclusions,
+      }),
+      ..Default::default()
+    };
+
+    // the mid relay is never chained with target1, so paths always go to target2
+    let proxy_config = modoh_config(
+      &["https://target1.example/dns-query", "https://target2.example/dns-query"],
+      vec![vec![
+        url("https://mid.example/proxy"),
+        url("https://target1.example/dns-query"),
+      ]],
+    );
+    let manager = DoHPathManager::new(&proxy_config).unwrap();
+    for _ in 0..20 {
+      let path = manager.get_path().unwrap();
+      assert_eq!(path.target().authority(), "target2.example");
+      assert_eq!(path.relays.len(), 2);
+    }
+
+    // no path remains if the next hop is excluded with every target
+    let proxy_config = modoh_config(
+      &["https://target1.example/dns-query"],
+      vec![vec![
+        url("https://relay.example/proxy"),
+        url("https://target1.example/dns-query"),
+      ]],
+    );
+    assert!(DoHPathManager::new(&proxy_config).is_err());
+  }
+
+  #[tokio::test]
+  async fn hop_status_update_works() {
+    let targets = vec![
+      Arc::new(DoHTarget::new(&url("https://target1.dns.google/dns-query"), vec![])),
+      Arc::new(DoHTarget::new(&url("https://target2.dns.google/dns-query"), vec![])),
+    ];
+    let nexthops = vec![
+      Arc::new(DoHRelay::new(&url("https://relay1.dns.google/proxy"), true, vec![])),
+      Arc::new(DoHRelay::new(&url("https://relay2.dns.google/proxy"), true, vec![])),
+    ];
+    let mid_relays = vec![Arc::new(DoHRelay::new(
+      &url("https://mid.dns.google/proxy"),
+      false,
+      vec![],
+    ))];
+    let manager = DoHPathManager::build(targets, nexthops, mid_relays, (0, 1), true, true);
+    let path_set = manager.path_set();
+    let paths = &path_set.direct_paths;
+
+    // all healthy
+    assert!(!manager.update_hop_status(&[]).has_suspects());
+
+    // relay1 is down
+    paths[0][0].make_unhealthy();
+    paths[1][0].make_unhealthy();
+    let status = manager.update_hop_status(&[]);
+    assert!(status.suspected_targets.is_empty());
+    assert_eq!(status.suspected_relays, vec!["relay1.dns.google".to_string()]);
+    for _ in 0..20 {
+      assert_eq!(manager.get_path().unwrap().relays[0].authority, "relay2.dns.google");
+    }
+
+    // target2 is also down
+    paths[1][1].make_unhealthy();
+    let status = manager.update_hop_status(&[]);
+    assert_eq!(status.suspected_targets, vec!["target2.dns.google".to_string()]);
+    assert_eq!(status.suspected_relays, vec!["relay1.dns.google".to_string()]);
+
+    // probe of the mid relay via healthy hops fails
+    let (_, probe) = manager
+      .healthcheck_paths()
+      .into_iter()
+      .find(|(key, _)| key.starts_with("mid-relay"))
+      .unwrap();
+    assert_eq!(probe.target.authority, "target1.dns.google");
+    assert_eq!(probe.relays[0].authority, "relay2.dns.google");
+    probe.make_unhealthy();
+    let status = manager.update_hop_status(&[probe]);
+    assert_eq!(
+      status.suspected_relays,
+      vec!["mid.dns.google".to_string(), "relay1.dns.google".to_string()]
+    );
+    for _ in 0..20 {
+      assert_eq!(manager.get_path().unwrap().relays.len(), 1);
+    }
+  }
+
+  #[tokio::test]
+  async fn colluding_paths_are_rejected() {
+    let label = |urls: &[&str], operator: Option<&str>, jurisdiction: Option<&str>| HopLabelConfig {
+      urls: urls.iter().map(|v| url(v)).collect(),
+      operator: operator.map(|v| v.to_string()),
+      jurisdiction: jurisdiction.map(|v| v.to_string()),
+    };
+    let configs = [
+      label(
+        &["https://target.example/dns-query", "https://relay1.example/proxy"],
+        Some("Example Inc."),
+        None,
+      ),
+      label(&["https://relay2.example:443/proxy"], Some("Relay Inc."), Some("JP")),
+      label(
+        &["https://mid1.example/proxy", "https://mid2.example/proxy"],
+        None,
+        Some("jp"),
+      ),
+      label(
+        &["https://mid3.example/proxy", "https://mid4.example/proxy"],
+        None,
+        Some("US"),
+      ),
+    ];
+    let target_url = url("https://target.example/dns-query");
+    assert_eq!(
+      hop_labels(&target_url, &configs),
+      vec!["operator:example inc.".to_string()]
+    );
+    assert_eq!(
+      hop_labels(&url("https://relay2.example/proxy"), &configs),
+      vec!["operator:relay inc.".to_string(), "jurisdiction:jp".to_string()]
+    );
+
+    let target = Arc::new(DoHTarget::new(&target_url, hop_labels(&target_url, &configs)));
+    let relay =
+      |s: &str, can_be_next_hop: bool| Arc::new(DoHRelay::new(&url(s), can_be_next_hop, hop_labels(&url(s), &configs)));
+    let nexthops = vec![
+      relay("https://relay1.example/proxy", true),
+      relay("https://relay2.example/proxy", true),
+    ];
+    let mid_relays = (1..=5)
+      .map(|i| relay(&format!("https://mid{i}.example/proxy"), false))
+      .collect::<Vec<_>>();
+
+    // next hop run by the target operator
+    let path = DoHPath {
+      target: target.clone(),
+      relays: vec![nexthops[0].clone()],
+      is_healthy: IsHealthy::new(),
+      doh_type: DoHType::Oblivious,
+    };
+    assert!(path.is_colluding());
+    // two intermediate relays in the same jurisdiction
+    let path = DoHPath {
+      target: target.clone(),
+      relays: vec![nexthops[1].clone(), mid_relays[2].clone(), mid_relays[3].clone()],
+      is_healthy: IsHealthy::new(),
+      doh_type: DoHType::Oblivious,
+    };
+    assert!(path.is_colluding());
+    let path = DoHPath {
+      target: target.clone(),
+      relays: vec![nexthops[1].clone(), mid_relays[2].clone(), mid_relays[4].clone()],
+      is_healthy: IsHealthy::new(),
+      doh_type: DoHType::Oblivious,
+    };
+    assert!(!path.is_colluding());
+
+    let manager = DoHPathManager::build(vec![target], nexthops, mid_relays, (1, 3), false, true);
+    assert_eq!(manager.path_set().direct_paths[0].len(), 1);
+    for _ in 0..100 {
+      let path = manager.get_path().unwrap();
+      assert_eq!(path.relays[0].authority, "relay2.example");
+      assert!(!path.is_colluding());
+      // mid1 and mid2 share the jurisdiction with the next hop
+      assert!(path.relays[1..]
+        .iter()
+        .all(|v| !v.authority.starts_with("mid1") && !v.authority.starts_with("mid2")));
+    }
+    for (_, path) in manager.healthcheck_paths() {
+      assert!(!path.is_colluding());
+    }
+  }
+
+  #[tokio::test]
+  async fn compatibility_works() {
+    let compatibility = [
+      HopCompatibilityConfig::AllowedTargets {
+        relay_url: url("https://relay1.example/proxy"),
+        target_urls: vec![url("https://target1.example/dns-query")],
+      },
+      HopCompatibilityConfig::AllowedRelays {
+        target_url: url("https://target2.example/dns-query"),
+        relay_urls: vec![url("https://relay2.example/proxy"), url("https://mid1.example/proxy")],
+      },
+    ];
+    let target = |s: &str| {
+      Arc::new(DoHTarget {
+        allowed_relays: declared_counterparts(&url(s), &compatibility, false),
+        ..DoHTarget::new(&url(s), vec![])
+      })
+    };
+    let relay = |s: &str, can_be_next_hop: bool| {
+      Arc::new(DoHRelay {
+        allowed_targets: declared_counterparts(&url(s), &compatibility, true),
+        ..DoHRelay::new(&url(s), can_be_next_hop, vec![])
+      })
+    };
+    let targets = vec![
+      target("https://target1.example/dns-query"),
+      target("https://target2.example/dns-query"),
+    ];
+    assert_eq!(targets[0].allowed_relays, None);
+    let nexthops = vec![
+      relay("https://relay1.example/proxy", true),
+      relay("https://relay2.example/proxy", true),
+    ];
+    let mid_relays = vec![
+      relay("https://mid1.example/proxy", false),
+      relay("https://mid2.example/proxy", false),
+    ];
+
+    // relay1 -> target2 is not declared, relay2 -> target2 is declared
+    let manager = DoHPathManager::build(targets, nexthops, mid_relays, (0, 2), true, true);
+    assert_eq!(manager.path_set().direct_paths[0].len(), 2);
+    assert_eq!(manager.path_set().direct_paths[1].len(), 1);
+    assert_eq!(
+      manager.path_set().direct_paths[1][0].relays[0].authority,
+      "relay2.example"
+    );
+    for _ in 0..100 {
+      let path = manager.get_path().unwrap();
+      if path.target.authority == "target2.example" {
+        // mid2 never forwards to target2 directly
+        assert_ne!(path.relays.last().unwrap().authority, "mid2.example");
+      }
+    }
+
+    // relay2 -> target1 is learned as incompatible after persistent rejections
+    let path = manager.path_set().direct_paths[0][1].clone();
+    assert_eq!(path.relays[0].authority, "relay2.example");
+    for _ in 0..ODOH_REJECTION_THRESHOLD - 1 {
+      manager.report_rejection(&path);
+    }
+    manager.report_acceptance(&path);
+    for _ in 0..ODOH_REJECTION_THRESHOLD - 1 {
+      manager.report_rejection(&path);
+    }
+    assert!(manager.is_path_compatible(&path));
+    manager.report_rejection(&path);
+    assert!(!manager.is_path_compatible(&path));
+    assert!(manager.healthcheck_paths().iter().all(|(_, v)| !Arc::ptr_eq(v, &path)));
+    assert!(!manager.update_hop_status(&[]).has_suspects());
+  }
+
+  #[tokio::test]
+  async fn runtime_update_works() {
+    let targets = vec![Arc::new(DoHTarget::new(
+      &url("https://target1.example/dns-query"),
+      vec![],
+    ))];
+    let nexthops = vec![Arc::new(DoHRelay::new(
+      &url("https://relay1.example/proxy"),
+      true,
+      vec![],
+    ))];
+    let mid_relays = vec![Arc::new(DoHRelay::new(
+      &url("https://mid1.example/proxy"),
+      false,
+      vec![],
+    ))];
+    let manager = DoHPathManager::build(targets, nexthops, mid_relays, (0, 1), false, true);
+    let before = manager.path_set();
+    before.direct_paths[0][0].make_unhealthy();
+    assert!(manager.get_path().is_none());
+
+    let next = HopUrls {
+      targets: vec![url("https://target1.example/dns-query")],
+      nexthops: vec![url("https://relay1.example/proxy"), url("https://relay2.example/proxy")],
+      mid_relays: vec![url("https://mid2.example/proxy")],
+    };
+    let update = PathSetUpdate::diff(&manager.hop_urls(), &next);
+    assert_eq!(update.add.nexthops, vec![url("https://relay2.example/proxy")]);
+    assert_eq!(update.remove.mid_relays, vec![url("https://mid1.example/proxy")]);
+    manager.commit(manager.stage(&update).unwrap());
+    assert_eq!(manager.hop_urls(), next);
+    assert_eq!(
+      manager.endpoints(),
+      vec![url("https://relay1.example/proxy"), url("https://relay2.example/proxy")]
+    );
+
+    // existing direct paths are kept with their health, and readers of the previous set are not affected
+    let after = manager.path_set();
+    assert!(Arc::ptr_eq(&before.direct_paths[0][0], &after.direct_paths[0][0]));
+    assert!(!after.direct_paths[0][0].is_healthy());
+    assert_eq!(before.mid_relays[0].authority, "mid1.example");
+    for _ in 0..20 {
+      let path = manager.get_path().unwrap();
+      assert_eq!(path.relays[0].authority, "relay2.example");
+      assert!(path.relays[1..].iter().all(|v| v.authority == "mid2.example"));
+    }
+
+    // updates leaving no path are rejected
+    let update = PathSetUpdate {
+      remove: HopUrls {
+        targets: vec![url("https://target1.example/dns-query")],
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    assert!(manager.stage(&update).is_err());
+    let update = PathSetUpdate {
+      add: HopUrls {
+        nexthops: vec![url("https://target1.example/proxy")],
+        ..Default::default()
+      },
+      remove: HopUrls {
+        nexthops: next.nexthops.clone(),
+        ..Default::default()
+      },
+    };
+    assert!(manager.stage(&update).is_err());
+    assert_eq!(manager.hop_urls(), next);
+  }
 }
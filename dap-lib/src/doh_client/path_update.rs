@@ -0,0 +1,79 @@
+use super::{
+  odoh_config_store::ODoHConfigStore,
+  path_manage::{DoHPathManager, PathSetUpdate},
+};
+use crate::{error::*, http_client::HttpClient, log::*};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Updater of the path set at runtime, which keeps the ODoH config store and endpoints of the http client
+/// consistent with the path set
+pub(super) struct PathSetUpdater {
+  /// path manager whose path set is updated
+  path_manager: Arc<DoHPathManager>,
+  /// odoh config store whose targets are updated
+  odoh_configs: Option<Arc<ODoHConfigStore>>,
+  /// http client whose endpoints are updated
+  http_client: Arc<HttpClient>,
+  /// lock to serialize updates
+  lock: Mutex<()>,
+}
+
+impl PathSetUpdater {
+  /// Create a new updater
+  pub fn new(
+    path_manager: Arc<DoHPathManager>,
+    odoh_configs: Option<Arc<ODoHConfigStore>>,
+    http_client: Arc<HttpClient>,
+  ) -> Self {
+    Self {
+      path_manager,
+      odoh_configs,
+      http_client,
+      lock: Mutex::new(()),
+    }
+  }
+
+  /// Apply the update to the path set.
+  /// ODoH configs of new targets are fetched before the new path set is swapped in, and those of removed targets are
+  /// dropped after that. New first hops are resolved by the endpoint ip update service right after the swap.
+  pub async fn apply(&self, update: &PathSetUpdate) -> Result<()> {
+    if update.is_empty() {
+      return Ok(());
+    }
+    let _lock = self.lock.lock().await;
+    let path_set = self.path_manager.stage(update)?;
+    let targets = path_set.targets();
+    if let Some(odoh_configs) = &self.odoh_configs {
+      odoh_configs.add_targets(&targets).await;
+    }
+
+    let before = self.path_manager.endpoints();
+    self.path_manager.commit(path_set);
+    if let Some(odoh_configs) = &self.odoh_configs {
+      odoh_configs.retain_targets(&targets).await;
+    }
+    let after = self.path_manager.endpoints();
+    let added = after
+      .iter()
+      .filter(|v| !before.contains(v))
+      .cloned()
+      .collect::<Vec<_>>();
+    let removed = before
+      .iter()
+      .filter(|v| !after.contains(v))
+      .cloned()
+      .collect::<Vec<_>>();
+    if !added.is_empty() || !removed.is_empty() {
+      self.http_client.update_endpoints(&added, &removed);
+    }
+    let hop_urls = self.path_manager.hop_urls();
+    info!(
+      "Path set is updated: {} targets, {} next-hop relays, {} intermediate relays",
+      hop_urls.targets.len(),
+      hop_urls.nexthops.len(),
+      hop_urls.mid_relays.len()
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,35 @@
+use super::{
+  path_manage::{HopStatus, HopUrls, PathSetUpdate},
+  DoHClient,
+};
+use crate::error::*;
+use std::sync::Arc;
+
+#[derive(Clone)]
+/// Handle of the running proxy given by `entrypoint`, through which hops are inspected and updated at runtime
+pub struct ProxyHandle {
+  doh_client: Arc<DoHClient>,
+}
+
+impl ProxyHandle {
+  /// Create a new handle of the proxy served by the doh client
+  pub(crate) fn new(doh_client: Arc<DoHClient>) -> Self {
+    Self { doh_client }
+  }
+
+  /// Get status of hops (targets and relays) inferred from the health of paths
+  pub fn hop_status(&self) -> HopStatus {
+    self.doh_client.hop_status()
+  }
+
+  /// Get urls of current targets and relays
+  pub fn hop_urls(&self) -> HopUrls {
+    self.doh_client.path_manager().hop_urls()
+  }
+
+  /// Add or remove targets, next-hop relays and intermediate relays at runtime.
+  /// The update is rejected and the current path set is kept if no path remains after that.
+  pub async fn update_path_set(&self, update: &PathSetUpdate) -> Result<()> {
+    self.doh_client.update_path_set(update).await
+  }
+}
@@ -21,7 +21,15 @@ pub enum DapError {
   TokenError(String),
 
   #[error("HttpClient error")]
-  HttpClientError(#[from] reqwest::Error),
+  HttpClientError(#[source] reqwest::Error),
+  #[error("Invalid TLS config: {0}")]
+  InvalidTlsConfig(String),
+  #[error("TLS certificate chain does not match any SPKI pin")]
+  SpkiPinMismatch,
+  #[error("TLS certificate chain does not match any certificate hash of the DNS stamp")]
+  CertHashMismatch,
+  #[error("Invalid HTTP header config: {0}")]
+  InvalidHeaderConfig(String),
   #[error("Failed to resolve ips for HTTP client")]
   FailedToResolveIpsForHttpClient,
   #[error("Too many fails to resolve ips for HTTP client in periodic task")]
@@ -44,12 +52,36 @@ pub enum DapError {
   ODoHNoRelayUrl,
   #[error("ODoH No Client Config")]
   ODoHNoClientConfig,
+  #[error("Failed to fetch ODoH config: {0}")]
+  ODoHConfigFetchError(String),
   #[error("ODoH does not allow GET method")]
   ODoHGetNotAllowed,
   #[error("ODoH invalid content length")]
   ODoHInvalidContentLength,
+  #[error("ODoH key id mismatch")]
+  ODoHKeyIdMismatch,
+  #[error("ODoH invalid padding in response")]
+  ODoHInvalidPadding,
+  #[error("ODoH query rejected by relay, maybe the target is not allowed: {0}")]
+  ODoHQueryRejected(reqwest::StatusCode),
   #[error("ODoH operation error")]
   ODoHError(#[from] odoh_rs::Error),
+  #[error("OHTTP No Key Config")]
+  OHttpNoKeyConfig,
+  #[error("Failed to fetch OHTTP key config: {0}")]
+  OHttpKeyConfigFetchError(String),
+  #[error("OHTTP operation error")]
+  OHttpError(#[from] ohttp::Error),
+  #[error("Binary HTTP operation error")]
+  BHttpError(#[from] bhttp::Error),
+  #[error("Invalid DNS stamp: {0}")]
+  InvalidDnsStamp(String),
+  #[error("DNSCrypt No Valid Certificate")]
+  DnsCryptNoValidCert,
+  #[error("Failed to fetch DNSCrypt certificate: {0}")]
+  DnsCryptCertFetchError(String),
+  #[error("DNSCrypt operation error: {0}")]
+  DnsCryptError(String),
 
   #[error("Invalid DNS query")]
   InvalidDnsQuery,
@@ -60,6 +92,23 @@ pub enum DapError {
   NoPathAvailable,
   #[error("DoH query error")]
   DoHQueryError,
+  #[error("Coalesced upstream query failed")]
+  CoalescedQueryFailed,
+  #[error("Invalid health check config: {0}")]
+  InvalidHealthCheckConfig(String),
+  #[error("Invalid directory: {0}")]
+  InvalidDirectory(String),
+  #[error("Invalid update of path set: {0}")]
+  InvalidPathSetUpdate(String),
+
+  #[error("Invalid relay request: {0}")]
+  RelayInvalidRequest(String),
+  #[error("Relay loop detected")]
+  RelayLoopDetected,
+  #[error("Target not allowed by relay: {0}")]
+  RelayTargetNotAllowed(String),
+  #[error("Relay server error: {0}")]
+  RelayServerError(String),
 
   #[error("Regex error: {0}")]
   RegexError(#[from] regex::Error),
@@ -67,3 +116,16 @@ pub enum DapError {
   #[error(transparent)]
   Other(#[from] anyhow::Error),
 }
+
+impl From<reqwest::Error> for DapError {
+  fn from(e: reqwest::Error) -> Self {
+    // pin mismatches are distinguished from other failures of connections
+    if crate::http_client::is_spki_pin_mismatch(&e) {
+      return DapError::SpkiPinMismatch;
+    }
+    if crate::http_client::is_cert_hash_mismatch(&e) {
+      return DapError::CertHashMismatch;
+    }
+    DapError::HttpClientError(e)
+  }
+}
@@ -1,7 +1,8 @@
-use crate::constants::*;
+use crate::{constants::*, dns_stamp::StampProps};
 use auth_client::AuthenticationConfig;
 use std::{
   net::{IpAddr, SocketAddr},
+  path::PathBuf,
   sync::Arc,
 };
 use tokio::{sync::Notify, time::Duration};
@@ -30,8 +31,14 @@ pub struct ProxyConfig {
   pub bootstrap_dns: BootstrapDns,
   /// endpoint resolution period
   pub endpoint_resolution_period_sec: Duration,
-  /// health check period
+  /// health check period for healthy paths
   pub healthcheck_period_sec: Duration,
+  /// initial backoff to re-probe unhealthy paths, doubled up to the health check period
+  pub healthcheck_initial_backoff_sec: Duration,
+  /// max number of concurrent health checks
+  pub healthcheck_max_concurrency: usize,
+  /// health check probe definitions, where the first one matching a target is applied.
+  pub healthcheck_configs: Vec<HealthCheckConfig>,
 
   // udp and tcp proxy setting
   pub udp_buffer_size: usize,
@@ -42,6 +49,21 @@ pub struct ProxyConfig {
   /// timeout for HTTP requests (DoH, ODoH, and authentication requests)
   pub http_timeout_sec: Duration,
 
+  /// TLS settings specific to endpoints, i.e., targets, relays and the token API
+  pub endpoint_tls_configs: Vec<EndpointTlsConfig>,
+
+  /// User-Agent and custom headers of HTTP requests to endpoints
+  pub endpoint_header_configs: Vec<EndpointHeaderConfig>,
+
+  /// SOCKS5 or HTTP CONNECT proxy through which all HTTP connections go out, i.e., socks5h:// or http(s)://
+  pub egress_proxy: Option<Url>,
+
+  /// source address, interface and fwmark of sockets to targets, relays and the token API
+  pub upstream_binding: SocketBindingConfig,
+
+  /// source address, interface and fwmark of sockets to bootstrap DNS resolvers
+  pub bootstrap_binding: SocketBindingConfig,
+
   /// doh, odoh, modoh target settings
   pub target_config: TargetConfig,
 
@@ -51,9 +73,30 @@ pub struct ProxyConfig {
   /// modoh relay settings
   pub subseq_relay_config: Option<SubseqRelayConfig>,
 
+  /// oblivious http relay settings, exclusive with odoh
+  pub oblivious_http_config: Option<ObliviousHttpConfig>,
+
+  /// dnscrypt settings, where targets are dnscrypt servers given in DNS stamps, exclusive with odoh and oblivious http
+  pub dnscrypt_config: Option<DnsCryptConfig>,
+
+  /// properties of targets and relays given in DNS stamps
+  pub hop_stamps: Vec<HopStampConfig>,
+
+  /// operator and jurisdiction labels of targets and relays, where hops sharing a label are never chained
+  pub hop_labels: Vec<HopLabelConfig>,
+
+  /// declared compatibility between relays and targets
+  pub hop_compatibility: Vec<HopCompatibilityConfig>,
+
+  /// signed remote directory of targets and relays
+  pub directory_config: Option<DirectoryConfig>,
+
   /// authentication settings
   pub authentication_config: Option<AuthenticationConfig>,
 
+  /// built-in odoh relay server settings
+  pub relay_config: Option<RelayConfig>,
+
   /// query manipulation settings
   pub query_manipulation_config: Option<Arc<QueryManipulationConfig>>,
 }
@@ -65,6 +108,84 @@ pub struct BootstrapDns {
   pub port: u16,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// TLS settings applied to connections to endpoints sharing the host and port with the given urls
+pub struct EndpointTlsConfig {
+  pub urls: Vec<Url>,
+  /// PEM file of CA certificates trusted instead of the default roots
+  pub ca_file: Option<PathBuf>,
+  /// base64-encoded SHA-256 digests of SubjectPublicKeyInfo, one of which must be found in the certificate chain.
+  /// Backup pins should be given to survive key rotation.
+  pub spki_pins: Vec<String>,
+  /// accept TLS 1.3 only
+  pub tls13_only: bool,
+  /// client certificate for mutual TLS, used with or without bearer tokens
+  pub client_identity: Option<ClientIdentityConfig>,
+  /// SHA-256 digests of TBS certificates given in DNS stamps, one of which must be found in the certificate chain
+  pub cert_hashes: Vec<[u8; 32]>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Client certificate and private key for mutual TLS, reloaded when the files are modified
+pub enum ClientIdentityConfig {
+  /// PEM files of the certificate chain and the private key, which can be the same file
+  Pem { cert_file: PathBuf, key_file: PathBuf },
+  /// PKCS#12 archive and its password
+  Pkcs12 { file: PathBuf, password: String },
+}
+
+impl ClientIdentityConfig {
+  /// Files of the identity to be watched for modification
+  pub fn files(&self) -> Vec<&PathBuf> {
+    match self {
+      ClientIdentityConfig::Pem { cert_file, key_file } => vec![cert_file, key_file],
+      ClientIdentityConfig::Pkcs12 { file, .. } => vec![file],
+    }
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+/// HTTP headers of requests to endpoints sharing the host and port with the given urls.
+/// If no url is given, they are applied to endpoints not listed in the others.
+pub struct EndpointHeaderConfig {
+  pub urls: Vec<Url>,
+  pub user_agent: UserAgentPolicy,
+  /// static headers added to every request, e.g., API keys required by managed DoH providers
+  pub headers: Vec<(String, String)>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+/// User-Agent header of requests, which may fingerprint users to relays and targets
+pub enum UserAgentPolicy {
+  /// "doh-auth-proxy/<version>"
+  #[default]
+  Default,
+  /// the given one
+  Fixed(String),
+  /// no User-Agent header
+  Suppressed,
+  /// one randomly chosen from the pool for every request
+  Random(Vec<String>),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+/// Options applied to outbound sockets so that they leave through a particular interface or routing policy
+pub struct SocketBindingConfig {
+  /// source address, applied to sockets of the same address family
+  pub source_ip: Option<IpAddr>,
+  /// interface bound with SO_BINDTODEVICE (Linux only)
+  pub interface: Option<String>,
+  /// fwmark set with SO_MARK (Linux only)
+  pub fwmark: Option<u32>,
+}
+
+impl SocketBindingConfig {
+  /// Check if the interface or the fwmark is given, which cannot be set to sockets of reqwest by itself
+  pub fn has_socket_options(&self) -> bool {
+    self.interface.is_some() || self.fwmark.is_some()
+  }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 /// doh, odoh, modoh target settings
 pub struct TargetConfig {
@@ -78,13 +199,156 @@ pub struct TargetConfig {
 pub struct NextHopRelayConfig {
   pub odoh_relay_urls: Vec<Url>,
   pub odoh_relay_randomization: bool,
+  /// discover configs of targets in their HTTPS records via the own oblivious path, falling back to well-known
+  pub odoh_config_via_dns: bool,
+  /// padding of plaintext queries to hide their lengths
+  pub odoh_padding: ODoHPaddingPolicy,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Padding policy of ODoH plaintext queries, where lengths are of the serialized plaintext, i.e.,
+/// the DNS message, padding and their 2-byte length fields, which determines the ciphertext size
+pub enum ODoHPaddingPolicy {
+  /// no padding
+  None,
+  /// pad to the multiple of the block size
+  Block(usize),
+  /// pad with random length within the bounds
+  Random { min: usize, max: usize },
+  /// pad to the fixed maximum size, falling back to no padding if the query exceeds it
+  Max(usize),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Oblivious HTTP (RFC 9458), where DNS queries are sent to targets via OHTTP relays and the gateway
+pub struct ObliviousHttpConfig {
+  /// relays forwarding encapsulated requests to the gateway
+  pub relay_urls: Vec<Url>,
+  pub relay_randomization: bool,
+  /// url serving key configs of the gateway in application/ohttp-keys
+  pub gateway_key_url: Url,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// DNSCrypt v2, where queries are sent to DNSCrypt servers directly or via Anonymized DNSCrypt relays
+pub struct DnsCryptConfig {
+  /// DNS stamps of Anonymized DNSCrypt relays, empty to send queries directly to servers
+  pub relay_urls: Vec<Url>,
+  pub relay_randomization: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// DoH or ODoH target or relay given in a DNS stamp
+pub struct HopStampConfig {
+  /// https url built from the hostname and path in the stamp
+  pub url: Url,
+  /// ip address of the hop, which is used instead of resolving the hostname
+  pub addr: Option<SocketAddr>,
+  /// SHA-256 digests of the TBS certificates expected in the validation chain of the hop
+  pub cert_hashes: Vec<[u8; 32]>,
+  /// properties announced in the stamp
+  pub props: StampProps,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 /// modoh
 pub struct SubseqRelayConfig {
   pub mid_relay_urls: Vec<Url>,
+  /// range of the number of intermediate relays sampled for each query
+  pub min_mid_relays: usize,
   pub max_mid_relays: usize,
+  /// sets of targets and relays, matched by authority, where no two hops in a set are placed in the same path,
+  /// e.g., relays suspected to collude
+  pub exclusions: Vec<Vec<Url>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Labels of the party running targets and relays.
+/// Paths are built so that no two hops share the same operator or jurisdiction.
+pub struct HopLabelConfig {
+  /// urls of targets and relays, matched by authority
+  pub urls: Vec<Url>,
+  /// operator like "example inc."
+  pub operator: Option<String>,
+  /// jurisdiction like "US"
+  pub jurisdiction: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Declared compatibility between relays and targets, since many relays forward only to a fixed set of targets.
+/// This restricts the relay directly preceding the target in a path, where urls are matched by authority.
+pub enum HopCompatibilityConfig {
+  /// the relay forwards only to the listed targets
+  AllowedTargets { relay_url: Url, target_urls: Vec<Url> },
+  /// the target accepts only queries forwarded by the listed relays
+  AllowedRelays { target_url: Url, relay_urls: Vec<Url> },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Signed remote directory of targets and relays, which overrides the configured urls when fetched and verified
+pub struct DirectoryConfig {
+  /// url of the directory
+  pub url: Url,
+  /// pinned Ed25519 public key in base64 to verify the directory
+  pub public_key: String,
+  /// refresh period
+  pub refresh_period_sec: Duration,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Built-in ODoH and μODoH relay server, through which peers mutualize relaying.
+/// The relay is served over plain HTTP, which is expected to be behind a TLS-terminating reverse proxy.
+pub struct RelayConfig {
+  /// listen addresses of the relay
+  pub listen_addresses: Vec<SocketAddr>,
+  /// path of the relay endpoint like "/proxy"
+  pub path: String,
+  /// targets to which queries are forwarded, matched by authority. `None` allows any target.
+  pub allowed_targets: Option<Vec<Url>>,
+  /// maximum number of subsequent relays after this relay
+  pub max_subseq_relays: usize,
+  /// validation of bearer tokens given by clients. `None` accepts queries without tokens.
+  pub token_validation: Option<TokenValidationConfig>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Validation of id tokens in JWT signed with EdDSA by the token server
+pub struct TokenValidationConfig {
+  /// Ed25519 public keys in base64 to verify tokens
+  pub public_keys: Vec<String>,
+  /// expected issuer of tokens
+  pub issuer: Option<String>,
+  /// expected audiences of tokens, i.e., client ids
+  pub audiences: Option<Vec<String>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Health check probe definition for a group of targets.
+/// Like query manipulation rules, this is based on raw strings and converted to actual probe objects in the DoH client.
+pub struct HealthCheckConfig {
+  /// target urls to which this definition applies. `None` means all targets not covered by other definitions.
+  pub target_urls: Option<Vec<Url>>,
+  /// query name like "dns.google."
+  pub query_name: String,
+  /// query type like "A"
+  pub query_type: String,
+  /// expected response code like "NOERROR"
+  pub expected_rcode: String,
+  /// expected answer in the response
+  pub expected_answer: Option<HealthCheckAnswer>,
+  /// number of probes sent for every check
+  pub probes: usize,
+  /// number of successful probes required to flag the path healthy
+  pub success_threshold: usize,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Expected answer for health check probes
+pub enum HealthCheckAnswer {
+  /// any non-empty answer section
+  Any,
+  /// at least one of the given rdata like "8.8.8.8"
+  OneOf(Vec<String>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -109,6 +373,20 @@ impl Default for TargetConfig {
   }
 }
 
+impl Default for HealthCheckConfig {
+  fn default() -> Self {
+    Self {
+      target_urls: None,
+      query_name: HEALTHCHECK_TARGET_FQDN.to_string(),
+      query_type: HEALTHCHECK_QUERY_TYPE.to_string(),
+      expected_rcode: HEALTHCHECK_EXPECTED_RCODE.to_string(),
+      expected_answer: Some(HealthCheckAnswer::OneOf(vec![HEALTHCHECK_TARGET_ADDR.to_string()])),
+      probes: HEALTHCHECK_PROBES,
+      success_threshold: HEALTHCHECK_SUCCESS_THRESHOLD,
+    }
+  }
+}
+
 impl Default for QueryManipulationConfig {
   fn default() -> Self {
     QueryManipulationConfig {
@@ -132,6 +410,9 @@ impl Default for ProxyConfig {
       },
       endpoint_resolution_period_sec: Duration::from_secs(ENDPOINT_RESOLUTION_PERIOD_MIN * 60),
       healthcheck_period_sec: Duration::from_secs(HEALTHCHECK_PERIOD_MIN * 60),
+      healthcheck_initial_backoff_sec: Duration::from_secs(HEALTHCHECK_INITIAL_BACKOFF_SEC),
+      healthcheck_max_concurrency: HEALTHCHECK_MAX_CONCURRENCY,
+      healthcheck_configs: vec![HealthCheckConfig::default()],
 
       udp_buffer_size: UDP_BUFFER_SIZE,
       udp_channel_capacity: UDP_CHANNEL_CAPACITY,
@@ -139,12 +420,24 @@ impl Default for ProxyConfig {
       tcp_listen_backlog: TCP_LISTEN_BACKLOG,
 
       http_timeout_sec: Duration::from_secs(HTTP_TIMEOUT_SEC),
+      endpoint_tls_configs: vec![],
+      endpoint_header_configs: vec![],
+      egress_proxy: None,
+      upstream_binding: SocketBindingConfig::default(),
+      bootstrap_binding: SocketBindingConfig::default(),
 
       target_config: TargetConfig::default(),
       nexthop_relay_config: None,
       subseq_relay_config: None,
+      oblivious_http_config: None,
+      dnscrypt_config: None,
+      hop_stamps: vec![],
+      hop_labels: vec![],
+      hop_compatibility: vec![],
+      directory_config: None,
 
       authentication_config: None,
+      relay_config: None,
 
       query_manipulation_config: None,
     }
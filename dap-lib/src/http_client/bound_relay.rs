@@ -0,0 +1,332 @@
+use super::endpoint_resolver::EndpointResolver;
+use crate::{
+  error::*,
+  globals::SocketBindingConfig,
+  log::*,
+  outbound_socket::{connect_tcp, is_same_family},
+};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use std::{
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::Arc,
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  task::JoinHandle,
+};
+
+/// SOCKS5 version
+const SOCKS_VERSION: u8 = 0x05;
+/// Username/password authentication (RFC 1929)
+const SOCKS_USERPASS_AUTH: u8 = 0x02;
+/// Version of the username/password subnegotiation
+const SOCKS_USERPASS_VERSION: u8 = 0x01;
+/// Status of the username/password subnegotiation
+const SOCKS_AUTH_SUCCEEDED: u8 = 0x00;
+const SOCKS_AUTH_FAILED: u8 = 0x01;
+/// No acceptable methods
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+/// CONNECT command
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+/// Address types
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+/// Replies
+const SOCKS_REP_SUCCEEDED: u8 = 0x00;
+const SOCKS_REP_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS_REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+/// Length of the random username and password of the relay
+const CREDENTIAL_LEN: usize = 32;
+
+#[derive(Debug)]
+/// Loopback SOCKS5 server through which reqwest connects to endpoints, since reqwest cannot set the interface and
+/// the fwmark to its sockets. Connections to endpoints are made with the socket binding, where hostnames are resolved
+/// with the ip addresses of endpoints the http client holds, falling back to the bootstrap resolver.
+/// Clients must authenticate with random credentials of the instance, so that other local users cannot bypass the
+/// policy routing by the fwmark through the relay.
+pub(super) struct BoundRelay {
+  /// loopback address the relay listens on
+  addr: SocketAddr,
+  /// credentials required to use the relay
+  credentials: Arc<Credentials>,
+  /// accept loop, aborted when dropped
+  handle: JoinHandle<()>,
+}
+
+/// Username and password of the relay
+struct Credentials {
+  username: String,
+  password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Credentials").finish_non_exhaustive()
+  }
+}
+
+impl Credentials {
+  /// Generate random credentials
+  fn generate() -> Self {
+    let random = || {
+      (&mut OsRng)
+        .sample_iter(&Alphanumeric)
+        .take(CREDENTIAL_LEN)
+        .map(char::from)
+        .collect::<String>()
+    };
+    Self {
+      username: random(),
+      password: random(),
+    }
+  }
+}
+
+impl Drop for BoundRelay {
+  fn drop(&mut self) {
+    self.handle.abort();
+  }
+}
+
+impl BoundRelay {
+  /// Start the relay on an ephemeral loopback port
+  pub(super) async fn try_new(binding: &SocketBindingConfig, resolver: EndpointResolver) -> Result<Self> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let binding = Arc::new(binding.clone());
+    let credentials = Arc::new(Credentials::generate());
+    let handle = tokio::spawn({
+      let credentials = credentials.clone();
+      async move {
+        loop {
+          let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+              warn!("Failed to accept a connection to the bound socket relay: {e}");
+              continue;
+            }
+          };
+          let binding = binding.clone();
+          let resolver = resolver.clone();
+          let credentials = credentials.clone();
+          tokio::spawn(async move {
+            if let Err(e) = relay(stream, &credentials, &binding, &resolver).await {
+              debug!("Bound socket relay failed: {e}");
+            }
+          });
+        }
+      }
+    });
+    debug!("Bound socket relay listens on {addr}");
+    Ok(Self {
+      addr,
+      credentials,
+      handle,
+    })
+  }
+
+  /// Proxy url of the relay given to reqwest, where hostnames are passed to the relay
+  pub(super) fn proxy_url(&self) -> String {
+    format!(
+      "socks5h://{}:{}@{}",
+      self.credentials.username, self.credentials.password, self.addr
+    )
+  }
+}
+
+/// Serve a SOCKS5 CONNECT request from reqwest, and relay the connection to the requested endpoint
+async fn relay(
+  mut stream: TcpStream,
+  credentials: &Credentials,
+  binding: &SocketBindingConfig,
+  resolver: &EndpointResolver,
+) -> Result<()> {
+  // method selection
+  let mut buf = [0u8; 2];
+  stream.read_exact(&mut buf).await?;
+  let mut methods = vec![0u8; buf[1] as usize];
+  stream.read_exact(&mut methods).await?;
+  if buf[0] != SOCKS_VERSION || !methods.contains(&SOCKS_USERPASS_AUTH) {
+    stream.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS]).await?;
+    return Err(DapError::Other(anyhow!("Unsupported SOCKS version or methods")));
+  }
+  stream.write_all(&[SOCKS_VERSION, SOCKS_USERPASS_AUTH]).await?;
+
+  // username/password authentication
+  let version = stream.read_u8().await?;
+  let mut username = vec![0u8; stream.read_u8().await? as usize];
+  stream.read_exact(&mut username).await?;
+  let mut password = vec![0u8; stream.read_u8().await? as usize];
+  stream.read_exact(&mut password).await?;
+  if version != SOCKS_USERPASS_VERSION
+    || username != credentials.username.as_bytes()
+    || password != credentials.password.as_bytes()
+  {
+    stream.write_all(&[SOCKS_USERPASS_VERSION, SOCKS_AUTH_FAILED]).await?;
+    return Err(DapError::Other(anyhow!("Invalid SOCKS credentials")));
+  }
+  stream
+    .write_all(&[SOCKS_USERPASS_VERSION, SOCKS_AUTH_SUCCEEDED])
+    .await?;
+
+  // request
+  let mut buf = [0u8; 4];
+  stream.read_exact(&mut buf).await?;
+  if buf[0] != SOCKS_VERSION || buf[1] != SOCKS_CMD_CONNECT {
+    reply(&mut stream, SOCKS_REP_COMMAND_NOT_SUPPORTED).await?;
+    return Err(DapError::Other(anyhow!("Unsupported SOCKS command")));
+  }
+  let host = match buf[3] {
+    SOCKS_ATYP_IPV4 => {
+      let mut addr = [0u8; 4];
+      stream.read_exact(&mut addr).await?;
+      Ipv4Addr::from(addr).to_string()
+    }
+    SOCKS_ATYP_IPV6 => {
+      let mut addr = [0u8; 16];
+      stream.read_exact(&mut addr).await?;
+      Ipv6Addr::from(addr).to_string()
+    }
+    SOCKS_ATYP_DOMAIN => {
+      let len = stream.read_u8().await? as usize;
+      let mut domain = vec![0u8; len];
+      stream.read_exact(&mut domain).await?;
+      String::from_utf8_lossy(&domain).to_string()
+    }
+    _ => {
+      reply(&mut stream, SOCKS_REP_COMMAND_NOT_SUPPORTED).await?;
+      return Err(DapError::Other(anyhow!("Unsupported SOCKS address type")));
+    }
+  };
+  let port = stream.read_u16().await?;
+
+  // connect to the endpoint with the socket binding
+  let addrs = match host.parse() {
+    Ok(ip) => vec![SocketAddr::new(ip, port)],
+    Err(_) => resolver.lookup(&host, port).await.unwrap_or_else(|e| {
+      debug!("Failed to resolve {host}: {e}");
+      vec![]
+    }),
+  };
+  // addresses of another address family than the source address are skipped
+  let addrs = addrs
+    .into_iter()
+    .filter(|addr| is_same_family(binding, &addr.ip()))
+    .collect::<Vec<_>>();
+  let mut upstream = None;
+  for addr in addrs.iter() {
+    match connect_tcp(binding, *addr).await {
+      Ok(v) => {
+        upstream = Some(v);
+        break;
+      }
+      Err(e) => debug!("Failed to connect to {host} ({addr}) with the socket binding: {e}"),
+    }
+  }
+  let Some(mut upstream) = upstream else {
+    reply(&mut stream, SOCKS_REP_HOST_UNREACHABLE).await?;
+    return Err(DapError::Other(anyhow!("Failed to connect to {host}:{port}")));
+  };
+  reply(&mut stream, SOCKS_REP_SUCCEEDED).await?;
+
+  tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+  Ok(())
+}
+
+/// Reply to the request, where the bound address is not informed
+async fn reply(stream: &mut TcpStream, rep: u8) -> Result<()> {
+  stream
+    .write_all(&[SOCKS_VERSION, rep, 0x00, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+    .await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::trait_resolve_ips::{ResolveIpResponse, ResolveIps};
+  use async_trait::async_trait;
+  use std::sync::RwLock;
+  use url::Url;
+
+  struct NoResolver;
+
+  #[async_trait]
+  impl ResolveIps for NoResolver {
+    async fn resolve_ips(&self, _target_url: &Url) -> Result<ResolveIpResponse> {
+      Err(DapError::FailedToResolveIpsForHttpClient)
+    }
+  }
+
+  #[tokio::test]
+  async fn relay_connects_to_resolved_ips() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let dest = listener.local_addr().unwrap();
+    let resolved_ips = Arc::new(RwLock::new(vec![ResolveIpResponse {
+      hostname: "endpoint.example".to_string(),
+      addresses: vec![SocketAddr::new(dest.ip(), 443)],
+    }]));
+    let resolver = EndpointResolver::new(resolved_ips, Arc::new(NoResolver), &SocketBindingConfig::default());
+    let relay = BoundRelay::try_new(&SocketBindingConfig::default(), resolver)
+      .await
+      .unwrap();
+
+    // connections without the credentials are refused
+    let mut stream = TcpStream::connect(relay.addr).await.unwrap();
+    stream.write_all(&[SOCKS_VERSION, 1, 0x00]).await.unwrap();
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS]);
+
+    let mut stream = TcpStream::connect(relay.addr).await.unwrap();
+    stream
+      .write_all(&[SOCKS_VERSION, 1, SOCKS_USERPASS_AUTH])
+      .await
+      .unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [SOCKS_VERSION, SOCKS_USERPASS_AUTH]);
+    stream
+      .write_all(&[SOCKS_USERPASS_VERSION, 1, b'a', 1, b'b'])
+      .await
+      .unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [SOCKS_USERPASS_VERSION, SOCKS_AUTH_FAILED]);
+
+    let mut stream = TcpStream::connect(relay.addr).await.unwrap();
+    stream
+      .write_all(&[SOCKS_VERSION, 1, SOCKS_USERPASS_AUTH])
+      .await
+      .unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    let mut auth = vec![SOCKS_USERPASS_VERSION, CREDENTIAL_LEN as u8];
+    auth.extend_from_slice(relay.credentials.username.as_bytes());
+    auth.push(CREDENTIAL_LEN as u8);
+    auth.extend_from_slice(relay.credentials.password.as_bytes());
+    stream.write_all(&auth).await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [SOCKS_USERPASS_VERSION, SOCKS_AUTH_SUCCEEDED]);
+
+    let domain = b"endpoint.example";
+    let mut request = vec![
+      SOCKS_VERSION,
+      SOCKS_CMD_CONNECT,
+      0x00,
+      SOCKS_ATYP_DOMAIN,
+      domain.len() as u8,
+    ];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf[1], SOCKS_REP_SUCCEEDED);
+
+    let (mut upstream, _) = listener.accept().await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    upstream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+  }
+}
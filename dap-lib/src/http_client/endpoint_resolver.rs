@@ -0,0 +1,258 @@
+use crate::{
+  error::*,
+  globals::SocketBindingConfig,
+  log::*,
+  outbound_socket::is_same_family,
+  trait_resolve_ips::{ResolveIpResponse, ResolveIps},
+};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::{
+  net::{IpAddr, SocketAddr},
+  sync::{Arc, RwLock},
+};
+use url::Url;
+
+#[derive(Clone)]
+/// DNS resolver of reqwest backed by ip addresses of endpoints shared with HttpClient. Updated addresses apply to new
+/// connections without rebuilding clients, so that established connections and TLS sessions are kept alive.
+/// Hostnames other than endpoints are resolved by the fallback resolver, i.e., the bootstrap resolver, so that they
+/// never leak to the system resolver and its queries go out with the bootstrap socket binding.
+/// Addresses of another address family than the source address of the socket binding are skipped.
+pub(super) struct EndpointResolver {
+  resolved_ips: Arc<RwLock<Vec<ResolveIpResponse>>>,
+  fallback_resolver: Arc<dyn ResolveIps + Send + Sync>,
+  binding: Arc<SocketBindingConfig>,
+  /// skip internal addresses, checked every time hostnames are resolved for connections against DNS rebinding
+  external_only: bool,
+}
+
+impl std::fmt::Debug for EndpointResolver {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("EndpointResolver")
+      .field("resolved_ips", &self.resolved_ips)
+      .finish_non_exhaustive()
+  }
+}
+
+impl EndpointResolver {
+  pub(super) fn new(
+    resolved_ips: Arc<RwLock<Vec<ResolveIpResponse>>>,
+    fallback_resolver: Arc<dyn ResolveIps + Send + Sync>,
+    binding: &SocketBindingConfig,
+  ) -> Self {
+    Self {
+      resolved_ips,
+      fallback_resolver,
+      binding: Arc::new(binding.clone()),
+      external_only: false,
+    }
+  }
+
+  /// Resolver of the same addresses skipping internal ones
+  pub(super) fn external_only(&self) -> Self {
+    Self {
+      external_only: true,
+      ..self.clone()
+    }
+  }
+
+  /// Lookup ip addresses of the hostname, where ports of addresses are the given one
+  pub(super) async fn lookup(&self, hostname: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let resolved = self
+      .resolved_ips
+      .read()
+      .unwrap()
+      .iter()
+      .find(|v| v.hostname == hostname)
+      .map(|v| v.addresses.clone());
+    let addrs = match resolved {
+      Some(addrs) => addrs,
+      None => {
+        debug!("{hostname} is not an endpoint, resolved by the bootstrap resolver");
+        let url = Url::parse(&format!("https://{hostname}"))?;
+        self.fallback_resolver.resolve_ips(&url).await?.addresses
+      }
+    };
+    let addrs = addrs
+      .into_iter()
+      .filter(|v| is_same_family(&self.binding, &v.ip()))
+      .map(|v| SocketAddr::new(v.ip(), port))
+      .collect::<Vec<_>>();
+    if addrs.is_empty() {
+      return Err(DapError::Other(anyhow!(
+        "No address of {hostname} in the address family of the source address"
+      )));
+    }
+    if !self.external_only {
+      return Ok(addrs);
+    }
+    let addrs = addrs.into_iter().filter(|v| !is_internal(&v.ip())).collect::<Vec<_>>();
+    if addrs.is_empty() {
+      return Err(DapError::Other(anyhow!("No external address of {hostname}")));
+    }
+    Ok(addrs)
+  }
+}
+
+/// Check if the ip address is in loopback, link-local, private, shared, benchmarking, unspecified, multicast or
+/// broadcast ranges
+pub(crate) fn is_internal(ip: &IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => {
+      let octets = ip.octets();
+      ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_private()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        // shared address space (100.64.0.0/10)
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // benchmarking (198.18.0.0/15)
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+    }
+    IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+      Some(ip) => is_internal(&IpAddr::V4(ip)),
+      // unique local (fc00::/7) and link-local (fe80::/10)
+      None => {
+        ip.is_loopback()
+          || ip.is_unspecified()
+          || ip.is_multicast()
+          || (ip.segments()[0] & 0xfe00) == 0xfc00
+          || (ip.segments()[0] & 0xffc0) == 0xfe80
+      }
+    },
+  }
+}
+
+impl Resolve for EndpointResolver {
+  /// Ports of addresses are ignored by reqwest, where those of urls are used
+  fn resolve(&self, name: Name) -> Resolving {
+    let resolver = self.clone();
+    Box::pin(async move {
+      let addrs: Addrs = Box::new(resolver.lookup(name.as_str(), 0).await?.into_iter());
+      Ok(addrs)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_trait::async_trait;
+  use std::str::FromStr;
+
+  struct StaticResolver;
+
+  #[async_trait]
+  impl ResolveIps for StaticResolver {
+    async fn resolve_ips(&self, target_url: &Url) -> Result<ResolveIpResponse> {
+      Ok(ResolveIpResponse {
+        hostname: target_url.host_str().unwrap().to_string(),
+        addresses: vec![SocketAddr::from(([198, 51, 100, 1], 443))],
+      })
+    }
+  }
+
+  #[tokio::test]
+  async fn updated_addresses_are_resolved() {
+    let resolved_ips = Arc::new(RwLock::new(vec![ResolveIpResponse {
+      hostname: "endpoint.example".to_string(),
+      addresses: vec![SocketAddr::from(([192, 0, 2, 1], 443))],
+    }]));
+    let resolver = EndpointResolver::new(
+      resolved_ips.clone(),
+      Arc::new(StaticResolver),
+      &SocketBindingConfig::default(),
+    );
+    let name = || Name::from_str("endpoint.example").unwrap();
+    let addrs = resolver.resolve(name()).await.unwrap().collect::<Vec<_>>();
+    assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 1], 0))]);
+
+    resolved_ips.write().unwrap()[0].addresses = vec![SocketAddr::from(([192, 0, 2, 2], 443))];
+    let addrs = resolver.resolve(name()).await.unwrap().collect::<Vec<_>>();
+    assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 2], 0))]);
+
+    // others are resolved by the fallback resolver, not by the system resolver
+    let addrs = resolver
+      .resolve(Name::from_str("localhost").unwrap())
+      .await
+      .unwrap()
+      .collect::<Vec<_>>();
+    assert_eq!(addrs, vec![SocketAddr::from(([198, 51, 100, 1], 0))]);
+
+    // addresses of another address family than the source address are skipped
+    let binding = SocketBindingConfig {
+      source_ip: Some("2001:db8::1".parse().unwrap()),
+      ..Default::default()
+    };
+    let resolver = EndpointResolver::new(resolved_ips, Arc::new(StaticResolver), &binding);
+    assert!(resolver.resolve(name()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn internal_addresses_are_skipped() {
+    let resolved_ips = Arc::new(RwLock::new(vec![ResolveIpResponse {
+      hostname: "rebind.example".to_string(),
+      addresses: vec![SocketAddr::from(([127, 0, 0, 1], 443))],
+    }]));
+    let resolver = EndpointResolver::new(
+      resolved_ips.clone(),
+      Arc::new(StaticResolver),
+      &SocketBindingConfig::default(),
+    );
+    let name = || Name::from_str("rebind.example").unwrap();
+    assert!(resolver.resolve(name()).await.is_ok());
+
+    // a hostname resolving to an internal address is refused
+    let resolver = resolver.external_only();
+    assert!(resolver.resolve(name()).await.is_err());
+
+    // only external addresses are used if mixed
+    resolved_ips.write().unwrap()[0].addresses = vec![
+      SocketAddr::from(([169, 254, 169, 254], 443)),
+      SocketAddr::from(([192, 0, 2, 1], 443)),
+    ];
+    let addrs = resolver.resolve(name()).await.unwrap().collect::<Vec<_>>();
+    assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 1], 0))]);
+  }
+
+  #[test]
+  fn internal_addresses_are_detected() {
+    let internal = [
+      "127.0.0.1",
+      "10.0.0.1",
+      "172.16.0.1",
+      "192.168.1.1",
+      "169.254.169.254",
+      "0.0.0.0",
+      "100.64.0.1",
+      "100.127.255.254",
+      "198.18.0.1",
+      "198.19.255.254",
+      "224.0.0.1",
+      "255.255.255.255",
+      "::1",
+      "::",
+      "fd00::1",
+      "fe80::1",
+      "ff02::1",
+      "::ffff:192.168.1.1",
+      "::ffff:100.64.0.1",
+    ];
+    for ip in internal {
+      assert!(is_internal(&ip.parse().unwrap()), "{ip}");
+    }
+    let external = [
+      "1.1.1.1",
+      "100.128.0.1",
+      "198.20.0.1",
+      "2606:4700:4700::1111",
+      "::ffff:8.8.8.8",
+    ];
+    for ip in external {
+      assert!(!is_internal(&ip.parse().unwrap()), "{ip}");
+    }
+  }
+}
@@ -0,0 +1,141 @@
+use crate::{
+  constants::HTTP_USER_AGENT,
+  error::*,
+  globals::{EndpointHeaderConfig, UserAgentPolicy},
+};
+use rand::seq::SliceRandom;
+use reqwest::header::{
+  HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, USER_AGENT,
+};
+
+/// Headers set by the proxy for DoH, ODoH and the token API, which cannot be given as custom headers
+const RESERVED_HEADERS: [HeaderName; 4] = [AUTHORIZATION, CONTENT_TYPE, ACCEPT, CACHE_CONTROL];
+
+#[derive(Debug, Clone)]
+/// User-Agent and custom headers added to requests to an endpoint
+pub(super) struct EndpointHeaders {
+  /// candidates of User-Agent, one of which is randomly chosen for every request, and none if empty
+  user_agents: Vec<HeaderValue>,
+  /// static custom headers
+  custom: HeaderMap,
+}
+
+impl Default for EndpointHeaders {
+  fn default() -> Self {
+    Self {
+      user_agents: vec![default_user_agent()],
+      custom: HeaderMap::new(),
+    }
+  }
+}
+
+impl TryFrom<&EndpointHeaderConfig> for EndpointHeaders {
+  type Error = DapError;
+
+  fn try_from(config: &EndpointHeaderConfig) -> Result<Self> {
+    let header_value =
+      |v: &str| HeaderValue::from_str(v).map_err(|_| DapError::InvalidHeaderConfig(format!("invalid value: {v}")));
+    let user_agents = match &config.user_agent {
+      UserAgentPolicy::Default => vec![default_user_agent()],
+      UserAgentPolicy::Fixed(v) => vec![header_value(v)?],
+      UserAgentPolicy::Suppressed => vec![],
+      UserAgentPolicy::Random(pool) if pool.is_empty() => {
+        return Err(DapError::InvalidHeaderConfig("empty user agent pool".to_string()));
+      }
+      UserAgentPolicy::Random(pool) => pool.iter().map(|v| header_value(v)).collect::<Result<Vec<_>>>()?,
+    };
+    let mut custom = HeaderMap::new();
+    for (name, value) in config.headers.iter() {
+      let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| DapError::InvalidHeaderConfig(format!("invalid name: {name}")))?;
+      if name == USER_AGENT {
+        return Err(DapError::InvalidHeaderConfig(
+          "User-Agent must be given as the user agent policy".to_string(),
+        ));
+      }
+      if RESERVED_HEADERS.contains(&name) {
+        return Err(DapError::InvalidHeaderConfig(format!(
+          "{name} is set by the proxy and cannot be given as a custom header"
+        )));
+      }
+      custom.append(name, header_value(value)?);
+    }
+    Ok(Self { user_agents, custom })
+  }
+}
+
+impl EndpointHeaders {
+  /// Headers of a request, where User-Agent is chosen from the candidates every time
+  pub(super) fn headers(&self) -> HeaderMap {
+    let mut headers = self.custom.clone();
+    if let Some(user_agent) = self.user_agents.choose(&mut rand::thread_rng()) {
+      headers.insert(USER_AGENT, user_agent.clone());
+    }
+    headers
+  }
+}
+
+/// "doh-auth-proxy/<version>"
+fn default_user_agent() -> HeaderValue {
+  HeaderValue::from_str(&format!("{}/{}", HTTP_USER_AGENT, env!("CARGO_PKG_VERSION"))).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn endpoint_headers_work() {
+    let headers = EndpointHeaders::default().headers();
+    assert!(headers
+      .get(USER_AGENT)
+      .unwrap()
+      .to_str()
+      .unwrap()
+      .starts_with(HTTP_USER_AGENT));
+
+    let config = EndpointHeaderConfig {
+      urls: vec![],
+      user_agent: UserAgentPolicy::Suppressed,
+      headers: vec![("X-Api-Key".to_string(), "secret".to_string())],
+    };
+    let headers = EndpointHeaders::try_from(&config).unwrap().headers();
+    assert!(headers.get(USER_AGENT).is_none());
+    assert_eq!(headers.get("x-api-key").unwrap(), "secret");
+
+    let pool = vec!["agent-a".to_string(), "agent-b".to_string()];
+    let config = EndpointHeaderConfig {
+      user_agent: UserAgentPolicy::Random(pool.clone()),
+      ..Default::default()
+    };
+    let endpoint_headers = EndpointHeaders::try_from(&config).unwrap();
+    for _ in 0..10 {
+      let user_agent = endpoint_headers
+        .headers()
+        .get(USER_AGENT)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+      assert!(pool.contains(&user_agent));
+    }
+
+    let config = EndpointHeaderConfig {
+      headers: vec![("User-Agent".to_string(), "agent".to_string())],
+      ..Default::default()
+    };
+    assert!(EndpointHeaders::try_from(&config).is_err());
+    for name in ["Authorization", "content-type", "Accept", "Cache-Control"] {
+      let config = EndpointHeaderConfig {
+        headers: vec![(name.to_string(), "value".to_string())],
+        ..Default::default()
+      };
+      assert!(EndpointHeaders::try_from(&config).is_err());
+    }
+    let config = EndpointHeaderConfig {
+      user_agent: UserAgentPolicy::Random(vec![]),
+      ..Default::default()
+    };
+    assert!(EndpointHeaders::try_from(&config).is_err());
+  }
+}
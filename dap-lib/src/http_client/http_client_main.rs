@@ -1,11 +1,19 @@
+use super::{
+  bound_relay::BoundRelay, endpoint_resolver::EndpointResolver, headers::EndpointHeaders, tls::build_tls_config,
+};
 use crate::{
-  constants::HTTP_USER_AGENT,
   error::*,
+  globals::{EndpointHeaderConfig, EndpointTlsConfig, SocketBindingConfig},
   trait_resolve_ips::{resolve_ips, ResolveIpResponse, ResolveIps},
 };
-use reqwest::{header::HeaderMap, Client, IntoUrl, RequestBuilder, Url};
-use std::sync::Arc;
-use tokio::{sync::RwLock, time::Duration};
+use reqwest::{header::HeaderMap, Client, IntoUrl, Proxy, RequestBuilder, Url};
+use rustc_hash::FxHashMap as HashMap;
+use std::{net::IpAddr, sync::Arc};
+use tokio::{
+  sync::{Notify, RwLock},
+  time::Duration,
+};
+use url::Host;
 
 #[derive(Debug)]
 /// HttpClient that is a wrapper of reqwest::Client
@@ -15,35 +23,100 @@ pub struct HttpClient {
 
   /// domain: endpoint candidates that the client will connect to, where these ip addresses are resolved when instantiated by a given resolver implementing ResolveIps.
   /// This would be targets for DoH, nexthop relay for ODoH (path including target, not mid-relays for dynamic randomization)
-  endpoints: Vec<Url>,
+  /// They can be updated at runtime when the path set is updated.
+  endpoints: std::sync::RwLock<Vec<Url>>,
 
-  /// default headers
-  default_headers: Option<HeaderMap>,
+  /// notifier of endpoint updates, upon which endpoints are resolved immediately
+  endpoints_updated: Notify,
 
-  /// timeout for http request
-  timeout_sec: Duration,
+  /// ip addresses of endpoints given in DNS stamps, which are used without resolution
+  static_ips: Vec<ResolveIpResponse>,
+
+  /// ip addresses of endpoints, shared with the DNS resolver of the inner client and the bound socket relay
+  resolved_ips: Arc<std::sync::RwLock<Vec<ResolveIpResponse>>>,
+
+  /// DNS resolver of the inner client and the bound socket relay, falling back to the bootstrap resolver
+  resolver: EndpointResolver,
+
+  /// loopback relay connecting to endpoints with the interface and the fwmark, which reqwest cannot set by itself
+  bound_relay: Option<BoundRelay>,
+
+  /// settings of connections and requests
+  options: HttpClientOptions,
 
   /// period for endpoint ip resolution, such as next hop relay
   endpoint_resolution_period_sec: Duration,
 }
 
+#[derive(Debug, Clone, Default)]
+/// Settings of connections and requests, with which the inner client is built and rebuilt upon re-resolution
+pub struct HttpClientOptions {
+  /// timeout for http request
+  pub timeout_sec: Duration,
+  /// default headers
+  pub default_headers: Option<HeaderMap>,
+  /// TLS settings specific to endpoints
+  pub tls_configs: Vec<EndpointTlsConfig>,
+  /// User-Agent and custom headers of requests to endpoints
+  pub header_configs: Vec<EndpointHeaderConfig>,
+  /// SOCKS5 or HTTP CONNECT proxy through which all connections go out
+  pub egress_proxy: Option<Url>,
+  /// source address, interface and fwmark of sockets, applied to connections to the egress proxy if given
+  pub binding: SocketBindingConfig,
+}
+
+impl HttpClientOptions {
+  /// Check if endpoints need to be resolved by the bootstrap and DoH resolvers, which is not the case with the egress
+  /// proxy. Hostnames are resolved by the proxy itself (socks5h, http and https), where pre-resolved ip addresses are
+  /// never used.
+  pub fn needs_local_resolution(&self) -> bool {
+    self.egress_proxy.is_none()
+  }
+}
+
 impl HttpClient {
   /// Build HttpClient
   pub async fn new(
     endpoints: &[Url],
-    timeout_sec: Duration,
-    default_headers: Option<&HeaderMap>,
-    resolver_ips: impl ResolveIps,
+    resolver_ips: impl ResolveIps + Clone + Send + Sync + 'static,
+    static_ips: &[ResolveIpResponse],
+    options: HttpClientOptions,
     endpoint_resolution_period_sec: Duration,
   ) -> Result<Self> {
-    let resolved_ips = resolve_ips(endpoints, resolver_ips).await?;
+    // socks5 resolves hostnames by the system resolver of this host before connecting to the proxy
+    if options.egress_proxy.as_ref().is_some_and(|v| v.scheme() == "socks5") {
+      return Err(DapError::Other(anyhow!(
+        "socks5:// egress proxy leaks hostnames to the system resolver, use socks5h:// instead"
+      )));
+    }
+    let resolved_ips = match options.needs_local_resolution() {
+      true => resolve_endpoint_ips(endpoints, static_ips, resolver_ips.clone()).await?,
+      false => vec![],
+    };
+    let resolved_ips = Arc::new(std::sync::RwLock::new(resolved_ips));
+    let resolver = EndpointResolver::new(resolved_ips.clone(), Arc::new(resolver_ips), &options.binding);
+
+    // sockets of reqwest are bound to the interface and marked via the loopback relay
+    let bound_relay = match options.binding.has_socket_options() {
+      true if options.egress_proxy.is_some() => {
+        return Err(DapError::Other(anyhow!(
+          "Interface and fwmark of sockets cannot be used with the egress proxy"
+        )));
+      }
+      true => Some(BoundRelay::try_new(&options.binding, resolver.clone()).await?),
+      false => None,
+    };
+    let inner = HttpClientInner::new(&options, &resolver, bound_relay.as_ref()).await?;
+
     Ok(Self {
-      inner: Arc::new(RwLock::new(
-        HttpClientInner::new(timeout_sec, default_headers, &resolved_ips).await?,
-      )),
-      default_headers: default_headers.cloned(),
-      timeout_sec,
-      endpoints: endpoints.to_vec(),
+      inner: Arc::new(RwLock::new(inner)),
+      options,
+      endpoints: std::sync::RwLock::new(endpoints.to_vec()),
+      endpoints_updated: Notify::new(),
+      static_ips: static_ips.to_vec(),
+      resolved_ips,
+      resolver,
+      bound_relay,
       endpoint_resolution_period_sec,
     })
   }
@@ -54,18 +127,86 @@ impl HttpClient {
   }
 
   /// Get endpoints
-  pub fn endpoints(&self) -> &[Url] {
-    &self.endpoints
+  pub fn endpoints(&self) -> Vec<Url> {
+    self.endpoints.read().unwrap().clone()
+  }
+
+  /// Add and remove endpoints, and notify the endpoint ip update service to resolve them immediately
+  pub fn update_endpoints(&self, added: &[Url], removed: &[Url]) {
+    let mut endpoints = self.endpoints.write().unwrap();
+    endpoints.retain(|v| !removed.contains(v));
+    for url in added {
+      if !endpoints.contains(url) {
+        endpoints.push(url.clone());
+      }
+    }
+    drop(endpoints);
+    self.endpoints_updated.notify_one();
+  }
+
+  /// Wait for the update of endpoints
+  pub(super) async fn endpoints_updated(&self) {
+    self.endpoints_updated.notified().await;
+  }
+
+  /// Get static ip addresses of endpoints
+  pub(super) fn static_ips(&self) -> &[ResolveIpResponse] {
+    &self.static_ips
+  }
+
+  /// Get the DNS resolver shared with the inner client
+  pub(super) fn resolver(&self) -> &EndpointResolver {
+    &self.resolver
+  }
+
+  /// Lookup ip addresses of the host of the url in the same way as the inner client resolves it
+  pub(crate) async fn lookup(&self, url: &Url) -> Result<Vec<IpAddr>> {
+    let port = url.port_or_known_default().unwrap_or_default();
+    match url.host() {
+      Some(Host::Ipv4(ip)) => Ok(vec![IpAddr::V4(ip)]),
+      Some(Host::Ipv6(ip)) => Ok(vec![IpAddr::V6(ip)]),
+      Some(Host::Domain(domain)) => Ok(
+        self
+          .resolver
+          .lookup(domain, port)
+          .await?
+          .iter()
+          .map(|v| v.ip())
+          .collect(),
+      ),
+      None => Err(DapError::Other(anyhow!("No host in {url}"))),
+    }
   }
 
-  /// Get default headers
-  pub fn default_headers(&self) -> Option<&HeaderMap> {
-    self.default_headers.as_ref()
+  /// Set ip addresses of endpoints, which apply to new connections of the inner client without rebuilding it
+  pub(super) fn set_resolved_ips(&self, resolved_ips: &[ResolveIpResponse]) {
+    *self.resolved_ips.write().unwrap() = resolved_ips.to_vec();
   }
 
-  /// Get timeout
-  pub fn timeout_sec(&self) -> Duration {
-    self.timeout_sec
+  /// Get settings of connections and requests
+  pub fn options(&self) -> &HttpClientOptions {
+    &self.options
+  }
+
+  /// Get the bound socket relay if the interface or the fwmark is given
+  pub(super) fn bound_relay(&self) -> Option<&BoundRelay> {
+    self.bound_relay.as_ref()
+  }
+
+  /// Build a client of requests to hosts not given in the config, e.g., next hops given by clients of the relay.
+  /// It never connects to internal addresses even if hostnames are resolved to them again at connection time, except
+  /// with the egress proxy that resolves hostnames by itself.
+  pub(crate) async fn external_client(&self) -> Result<ExternalHttpClient> {
+    let resolver = self.resolver.external_only();
+    let bound_relay = match &self.bound_relay {
+      Some(_) => Some(BoundRelay::try_new(&self.options.binding, resolver.clone()).await?),
+      None => None,
+    };
+    let inner = HttpClientInner::new(&self.options, &resolver, bound_relay.as_ref()).await?;
+    Ok(ExternalHttpClient {
+      inner,
+      _bound_relay: bound_relay,
+    })
   }
 
   /// Get rebootstrap period
@@ -74,45 +215,162 @@ impl HttpClient {
   }
 }
 
+/// Resolve ip addresses of endpoints, where those with static ip addresses are not resolved and their static ones are used
+pub(super) async fn resolve_endpoint_ips(
+  endpoints: &[Url],
+  static_ips: &[ResolveIpResponse],
+  resolver_ips: impl ResolveIps,
+) -> Result<Vec<ResolveIpResponse>> {
+  let is_static = |url: &Url| static_ips.iter().any(|v| url.host_str() == Some(v.hostname.as_str()));
+  let to_be_resolved = endpoints
+    .iter()
+    .filter(|url| !is_static(url))
+    .cloned()
+    .collect::<Vec<_>>();
+  let mut resolved_ips = resolve_ips(&to_be_resolved, resolver_ips).await?;
+  resolved_ips.extend(static_ips.iter().cloned());
+  Ok(resolved_ips)
+}
+
+#[derive(Debug)]
+/// Client of requests to hosts not given in the config, skipping internal addresses of hostnames
+pub(crate) struct ExternalHttpClient {
+  inner: HttpClientInner,
+  /// bound socket relay resolving hostnames in the same way, kept alive with the client
+  _bound_relay: Option<BoundRelay>,
+}
+
+impl ExternalHttpClient {
+  /// Post wrapper
+  pub(crate) fn post(&self, url: impl IntoUrl + AsRef<str>) -> RequestBuilder {
+    self.inner.post(url)
+  }
+}
+
 #[derive(Debug)]
 /// Simple wrapper of reqwest::Client
 pub struct HttpClientInner {
   /// client: reqwest::Client,
   pub client: Client,
+  /// clients with TLS settings specific to endpoints, keyed by "host:port" of endpoints
+  tls_clients: HashMap<String, Client>,
+  /// User-Agent and custom headers of requests specific to endpoints, keyed by "host:port" of endpoints
+  endpoint_headers: HashMap<String, EndpointHeaders>,
+  /// User-Agent and custom headers of requests to the other endpoints
+  default_endpoint_headers: EndpointHeaders,
 }
 impl HttpClientInner {
-  /// Build HttpClientInner
+  /// Build HttpClientInner, where hostnames of endpoints are resolved with the ip addresses shared with HttpClient.
+  /// If the bound socket relay is given, connections go through it and hostnames are resolved there.
   pub(super) async fn new(
-    timeout_sec: Duration,
-    default_headers: Option<&HeaderMap>,
-    resolved_ips: &[ResolveIpResponse],
+    options: &HttpClientOptions,
+    resolver: &EndpointResolver,
+    bound_relay: Option<&BoundRelay>,
   ) -> Result<Self> {
-    let mut client = Client::builder()
-      .user_agent(format!("{}/{}", HTTP_USER_AGENT, env!("CARGO_PKG_VERSION")))
-      .timeout(timeout_sec)
-      .trust_dns(true);
-
-    // Override pre-resolved ip addresses
-    client = resolved_ips.iter().fold(client, |client, resolve_ip| {
-      client.resolve_to_addrs(&resolve_ip.hostname, &resolve_ip.addresses)
-    });
-
-    // Set default headers
-    if let Some(headers) = default_headers {
-      client = client.default_headers(headers.clone());
+    let resolver = Arc::new(resolver.clone());
+    let build_client = |tls: Option<rustls::ClientConfig>| {
+      // User-Agent is set to every request with the headers of the endpoint
+      let mut client = Client::builder()
+        .timeout(options.timeout_sec)
+        .dns_resolver(resolver.clone());
+
+      // Route all connections through the bound socket relay or the egress proxy
+      let proxy = match bound_relay {
+        Some(relay) => Some(relay.proxy_url()),
+        None => options.egress_proxy.as_ref().map(|v| v.to_string()),
+      };
+      if let Some(proxy) = proxy {
+        client = client.proxy(Proxy::all(proxy).map_err(DapError::HttpClientError)?);
+      }
+
+      // Bind the source address if the relay does not
+      if let (Some(ip), None) = (options.binding.source_ip, bound_relay) {
+        client = client.local_address(ip);
+      }
+
+      // Set default headers
+      if let Some(headers) = &options.default_headers {
+        client = client.default_headers(headers.clone());
+      }
+
+      // Set TLS settings of endpoints
+      if let Some(tls) = tls {
+        client = client.use_preconfigured_tls(tls);
+      }
+      client.build().map_err(DapError::HttpClientError)
+    };
+
+    let mut tls_clients = HashMap::default();
+    for tls_config in options.tls_configs.iter() {
+      let client = build_client(Some(build_tls_config(tls_config)?))?;
+      for url in tls_config.urls.iter() {
+        if let Some(authority) = authority(url) {
+          tls_clients.insert(authority, client.clone());
+        }
+      }
     }
+
+    let mut endpoint_headers = HashMap::default();
+    let mut default_endpoint_headers = EndpointHeaders::default();
+    for header_config in options.header_configs.iter() {
+      let headers = EndpointHeaders::try_from(header_config)?;
+      if header_config.urls.is_empty() {
+        default_endpoint_headers = headers;
+        continue;
+      }
+      for url in header_config.urls.iter() {
+        if let Some(authority) = authority(url) {
+          endpoint_headers.insert(authority, headers.clone());
+        }
+      }
+    }
+
     Ok(Self {
-      client: client.build().map_err(DapError::HttpClientError)?,
+      client: build_client(None)?,
+      tls_clients,
+      endpoint_headers,
+      default_endpoint_headers,
     })
   }
 
+  /// Get the client for the url, where TLS settings specific to the endpoint are applied if given
+  fn client_for(&self, url: &str) -> &Client {
+    if self.tls_clients.is_empty() {
+      return &self.client;
+    }
+    Url::parse(url)
+      .ok()
+      .and_then(|url| authority(&url))
+      .and_then(|authority| self.tls_clients.get(&authority))
+      .unwrap_or(&self.client)
+  }
+
+  /// Get User-Agent and custom headers of requests to the url
+  fn headers_for(&self, url: &str) -> &EndpointHeaders {
+    if self.endpoint_headers.is_empty() {
+      return &self.default_endpoint_headers;
+    }
+    Url::parse(url)
+      .ok()
+      .and_then(|url| authority(&url))
+      .and_then(|authority| self.endpoint_headers.get(&authority))
+      .unwrap_or(&self.default_endpoint_headers)
+  }
+
   /// Post wrapper
-  pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
-    self.client.post(url)
+  pub fn post(&self, url: impl IntoUrl + AsRef<str>) -> RequestBuilder {
+    let headers = self.headers_for(url.as_ref()).headers();
+    self.client_for(url.as_ref()).post(url).headers(headers)
   }
 
   /// Get wrapper
-  pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
-    self.client.get(url)
+  pub fn get(&self, url: impl IntoUrl + AsRef<str>) -> RequestBuilder {
+    let headers = self.headers_for(url.as_ref()).headers();
+    self.client_for(url.as_ref()).get(url).headers(headers)
   }
 }
+
+/// "host:port" of the url, with which TLS settings of endpoints are looked up
+fn authority(url: &Url) -> Option<String> {
+  Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?))
+}
@@ -1,15 +1,12 @@
-use tokio::time::sleep;
+use tokio::time::{sleep, Duration};
 
-use super::{HttpClient, HttpClientInner};
-use crate::{
-  error::*,
-  log::*,
-  trait_resolve_ips::{resolve_ips, ResolveIpResponse, ResolveIps},
-};
-use std::sync::Arc;
+use super::{http_client_main::resolve_endpoint_ips, HttpClient, HttpClientInner};
+use crate::{constants::CLIENT_IDENTITY_WATCH_PERIOD_SEC, error::*, log::*, trait_resolve_ips::ResolveIps};
+use std::{path::PathBuf, sync::Arc};
 
 impl HttpClient {
-  /// Periodically resolves endpoints to ip addresses, and override their ip addresses in the inner client.
+  /// Periodically resolves endpoints to ip addresses, which apply to new connections of the inner client while
+  /// established connections are kept alive.
   pub async fn start_endpoint_ip_update_service(
     &self,
     primary_resolver: impl ResolveIps + Clone,
@@ -47,24 +44,28 @@ impl HttpClient {
   ) -> Result<()> {
     let mut fail_cnt = 0;
     loop {
-      sleep(self.endpoint_resolution_period_sec()).await;
+      tokio::select! {
+        _ = sleep(self.endpoint_resolution_period_sec()) => {}
+        _ = self.endpoints_updated() => {
+          info!("Endpoints are updated, resolving their ip addresses");
+        }
+      }
       let endpoints = self.endpoints();
 
-      let primary_res = resolve_ips(endpoints, primary_resolver.clone()).await;
-      if primary_res.is_ok() {
-        self.update_inner(&primary_res.unwrap()).await?;
-        fail_cnt = 0;
-        info!("Resolved endpoint ip addresses by DoH resolver");
-        continue;
+      match resolve_endpoint_ips(&endpoints, self.static_ips(), primary_resolver.clone()).await {
+        Ok(resolved_ips) => {
+          self.set_resolved_ips(&resolved_ips);
+          fail_cnt = 0;
+          info!("Resolved endpoint ip addresses by DoH resolver");
+          continue;
+        }
+        Err(e) => {
+          warn!("Failed to resolve endpoint ip addresses by doh resolver, trying fallback with bootstrap resolver: {e}")
+        }
       }
-      warn!(
-        "Failed to resolve endpoint ip addresses by doh resolver, trying fallback with bootstrap resolver: {}",
-        primary_res.err().unwrap()
-      );
 
-      let fallback_res = resolve_ips(endpoints, fallback_resolver.clone()).await;
-      if fallback_res.is_ok() {
-        self.update_inner(&fallback_res.unwrap()).await?;
+      if let Ok(resolved_ips) = resolve_endpoint_ips(&endpoints, self.static_ips(), fallback_resolver.clone()).await {
+        self.set_resolved_ips(&resolved_ips);
         fail_cnt = 0;
         info!("Resolved endpoint ip addresses by bootstrap resolver");
         continue;
@@ -78,12 +79,72 @@ impl HttpClient {
     }
   }
 
-  /// Update http client inner
-  async fn update_inner(&self, resolved_ips: &[ResolveIpResponse]) -> Result<()> {
-    let inner = self.inner();
-    let mut inner_lock = inner.write().await;
-    *inner_lock = HttpClientInner::new(self.timeout_sec(), self.default_headers(), resolved_ips).await?;
-    drop(inner_lock);
+  /// Periodically checks modification of client certificate files for mutual TLS, and rebuilds the inner client
+  /// with reloaded certificates.
+  pub async fn start_client_identity_reload_service(
+    &self,
+    term_notify: Option<Arc<tokio::sync::Notify>>,
+  ) -> Result<()> {
+    info!("start periodic service for reloading client certificates of mutual TLS");
+
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          _ = self.reload_client_identity_service() => {
+            warn!("Client certificate reload service got down");
+          }
+          _ = term.notified() => {
+            info!("Client certificate reload service receives term signal");
+          }
+        }
+      }
+      None => {
+        self.reload_client_identity_service().await?;
+        warn!("Client certificate reload service got down");
+      }
+    }
+    Ok(())
+  }
+
+  /// periodic checker of modification times of client certificate files
+  async fn reload_client_identity_service(&self) -> Result<()> {
+    let files = self
+      .options()
+      .tls_configs
+      .iter()
+      .filter_map(|v| v.client_identity.as_ref())
+      .flat_map(|v| v.files())
+      .cloned()
+      .collect::<Vec<_>>();
+    let modified_times = |files: &[PathBuf]| {
+      files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|v| v.modified()).ok())
+        .collect::<Vec<_>>()
+    };
+
+    let mut last_modified = modified_times(&files);
+    loop {
+      sleep(Duration::from_secs(CLIENT_IDENTITY_WATCH_PERIOD_SEC)).await;
+      let modified = modified_times(&files);
+      if modified == last_modified {
+        continue;
+      }
+      // the current client is kept if the certificates are being replaced and invalid for now
+      match self.rebuild_inner().await {
+        Ok(_) => {
+          info!("Reloaded client certificates of mutual TLS");
+          last_modified = modified;
+        }
+        Err(e) => warn!("Failed to reload client certificates of mutual TLS: {e}"),
+      }
+    }
+  }
+
+  /// Rebuild http client inner, which is built before taking the write lock not to stall concurrent requests
+  async fn rebuild_inner(&self) -> Result<()> {
+    let new_inner = HttpClientInner::new(self.options(), self.resolver(), self.bound_relay()).await?;
+    *self.inner().write().await = new_inner;
     Ok(())
   }
 }
@@ -1,3 +1,10 @@
+mod bound_relay;
+mod endpoint_resolver;
+mod headers;
 mod http_client_main;
 mod http_client_service;
-pub use http_client_main::{HttpClient, HttpClientInner};
+mod tls;
+pub(crate) use endpoint_resolver::is_internal;
+pub(crate) use http_client_main::ExternalHttpClient;
+pub use http_client_main::{HttpClient, HttpClientInner, HttpClientOptions};
+pub(crate) use tls::{is_cert_hash_mismatch, is_spki_pin_mismatch};
@@ -1,23 +1,42 @@
 mod auth;
 mod bootstrap;
 mod constants;
+mod dns_stamp;
 mod doh_client;
 mod error;
 mod globals;
 mod http_client;
 mod log;
+mod outbound_socket;
 mod proxy;
+mod relay;
 mod trait_resolve_ips;
 
-use crate::{doh_client::DoHClient, error::*, globals::Globals, http_client::HttpClient, log::*, proxy::Proxy};
+use crate::{
+  doh_client::DoHClient,
+  error::*,
+  globals::Globals,
+  http_client::{HttpClient, HttpClientOptions},
+  log::*,
+  proxy::Proxy,
+  relay::Relay,
+  trait_resolve_ips::ResolveIpResponse,
+};
 use futures::{
   future::{select_all, FutureExt},
   select,
 };
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 pub use auth_client::AuthenticationConfig;
-pub use globals::{NextHopRelayConfig, ProxyConfig, QueryManipulationConfig, SubseqRelayConfig, TargetConfig};
+pub use dns_stamp::{DnsCryptStamp, DnsStamp, HttpStamp, StampProps};
+pub use doh_client::{HopStatus, HopUrls, PathSetUpdate, ProxyHandle};
+pub use globals::{
+  ClientIdentityConfig, DirectoryConfig, DnsCryptConfig, EndpointHeaderConfig, EndpointTlsConfig, HealthCheckAnswer,
+  HealthCheckConfig, HopCompatibilityConfig, HopLabelConfig, HopStampConfig, NextHopRelayConfig, ODoHPaddingPolicy,
+  ObliviousHttpConfig, ProxyConfig, QueryManipulationConfig, RelayConfig, SocketBindingConfig, SubseqRelayConfig,
+  TargetConfig, TokenValidationConfig, UserAgentPolicy,
+};
 
 /// entrypoint of DoH w/ Auth Proxy
 /// This spawns UDP and TCP listeners and spawns the following services
@@ -25,10 +44,14 @@ pub use globals::{NextHopRelayConfig, ProxyConfig, QueryManipulationConfig, Subs
 /// - HTTP client update service loop, changing DNS resolver to the self when it works (Done)
 /// - Health check service checking every path, flag unreachable patterns as unhealthy (as individual service inside doh_client?),
 ///   which also needs ODoH config refresh.
+///
+/// If `handle_tx` is given, the handle of the proxy is sent through it once the doh client is built,
+/// which is used to update targets and relays without restarting services.
 pub async fn entrypoint(
   proxy_config: &ProxyConfig,
   runtime_handle: &tokio::runtime::Handle,
   term_notify: Option<Arc<tokio::sync::Notify>>,
+  handle_tx: Option<tokio::sync::oneshot::Sender<ProxyHandle>>,
 ) -> Result<()> {
   info!("Start DoH w/ Auth Proxy");
 
@@ -40,24 +63,84 @@ pub async fn entrypoint(
   });
 
   // build bootstrap DNS resolver
-  let bootstrap_dns_resolver =
-    Arc::new(bootstrap::BootstrapDnsResolver::try_new(&proxy_config.bootstrap_dns, runtime_handle.clone()).await?);
+  let bootstrap_dns_resolver = Arc::new(
+    bootstrap::BootstrapDnsResolver::try_new(
+      &proxy_config.bootstrap_dns,
+      &proxy_config.bootstrap_binding,
+      runtime_handle.clone(),
+    )
+    .await?,
+  );
 
   // build http client that is used commonly by DoH client and authentication client
   let mut endpoint_candidates = vec![];
   if let Some(nexthop_relay_config) = &proxy_config.nexthop_relay_config {
     endpoint_candidates.extend(nexthop_relay_config.odoh_relay_urls.clone());
-  } else {
+  } else if let Some(oblivious_http_config) = &proxy_config.oblivious_http_config {
+    endpoint_candidates.extend(oblivious_http_config.relay_urls.clone());
+  } else if proxy_config.dnscrypt_config.is_none() {
+    // dnscrypt servers and relays are not http endpoints, and reached by ip addresses in their stamps
     endpoint_candidates.extend(proxy_config.target_config.doh_target_urls.clone());
   }
   if let Some(auth) = &proxy_config.authentication_config {
     endpoint_candidates.push(auth.token_api.clone());
   }
+  // ip addresses of targets and relays given in DNS stamps, which are used without resolution
+  let mut static_ips: Vec<ResolveIpResponse> = vec![];
+  for hop in proxy_config.hop_stamps.iter() {
+    let (Some(addr), Some(hostname)) = (hop.addr, hop.url.host_str()) else {
+      continue;
+    };
+    let addr = SocketAddr::new(addr.ip(), hop.url.port_or_known_default().unwrap_or(addr.port()));
+    match static_ips.iter_mut().find(|v| v.hostname == hostname) {
+      Some(v) if !v.addresses.contains(&addr) => v.addresses.push(addr),
+      Some(_) => (),
+      None => static_ips.push(ResolveIpResponse {
+        hostname: hostname.to_string(),
+        addresses: vec![addr],
+      }),
+    }
+  }
+  // certificate hashes in DNS stamps are enforced on connections to the hops, in addition to their TLS settings if given,
+  // where a later config overrides earlier ones for the same authority
+  let mut tls_configs = proxy_config.endpoint_tls_configs.clone();
+  for hop in proxy_config.hop_stamps.iter().filter(|v| !v.cert_hashes.is_empty()) {
+    let base = tls_configs
+      .iter()
+      .rev()
+      .find(|v| v.urls.iter().any(|url| url.authority() == hop.url.authority()))
+      .cloned();
+    let tls_config = match base {
+      Some(base) => EndpointTlsConfig {
+        urls: vec![hop.url.clone()],
+        cert_hashes: [base.cert_hashes.as_slice(), hop.cert_hashes.as_slice()].concat(),
+        ..base
+      },
+      None => EndpointTlsConfig {
+        urls: vec![hop.url.clone()],
+        ca_file: None,
+        spki_pins: vec![],
+        tls13_only: false,
+        client_identity: None,
+        cert_hashes: hop.cert_hashes.clone(),
+      },
+    };
+    tls_configs.push(tls_config);
+  }
+  let http_client_options = HttpClientOptions {
+    timeout_sec: proxy_config.http_timeout_sec,
+    default_headers: None,
+    tls_configs,
+    header_configs: proxy_config.endpoint_header_configs.clone(),
+    egress_proxy: proxy_config.egress_proxy.clone(),
+    binding: proxy_config.upstream_binding.clone(),
+  };
+  let needs_local_resolution = http_client_options.needs_local_resolution();
   let http_client = HttpClient::new(
     &endpoint_candidates,
-    proxy_config.http_timeout_sec,
-    None,
     bootstrap_dns_resolver.clone(),
+    &static_ips,
+    http_client_options,
     proxy_config.endpoint_resolution_period_sec,
   )
   .await?;
@@ -81,17 +164,42 @@ pub async fn entrypoint(
   }
 
   // build doh_client
-  let doh_client = Arc::new(DoHClient::new(globals.clone(), http_client.inner(), authenticator).await?);
+  let doh_client = DoHClient::new(globals.clone(), http_client.clone(), authenticator.clone()).await?;
+  if let Some(handle_tx) = handle_tx {
+    // the receiver may have been dropped if the caller does not need the handle
+    let _ = handle_tx.send(ProxyHandle::new(doh_client.clone()));
+  }
 
-  // spawn endpoint ip update service with bootstrap dns resolver and doh_client
+  // spawn endpoint ip update service with bootstrap dns resolver and doh_client,
+  // unless endpoints are resolved by the egress proxy, where it never completes
   let doh_client_clone = doh_client.clone();
   let term_notify_clone = term_notify.clone();
   let http_client_clone = http_client.clone();
   let ip_resolution_service = runtime_handle.spawn(async move {
-    http_client_clone
-      .start_endpoint_ip_update_service(doh_client_clone, bootstrap_dns_resolver, term_notify_clone)
-      .await
-      .with_context(|| "endpoint ip update service got down")
+    match needs_local_resolution {
+      true => http_client_clone
+        .start_endpoint_ip_update_service(doh_client_clone, bootstrap_dns_resolver, term_notify_clone)
+        .await
+        .with_context(|| "endpoint ip update service got down"),
+      false => futures::future::pending().await,
+    }
+  });
+
+  // spawn reload service of client certificates for mutual TLS if given, otherwise never completes
+  let has_client_identity = proxy_config
+    .endpoint_tls_configs
+    .iter()
+    .any(|v| v.client_identity.is_some());
+  let term_notify_clone = term_notify.clone();
+  let http_client_clone = http_client.clone();
+  let client_identity_service = runtime_handle.spawn(async move {
+    match has_client_identity {
+      true => http_client_clone
+        .start_client_identity_reload_service(term_notify_clone)
+        .await
+        .with_context(|| "client certificate reload service got down"),
+      false => futures::future::pending().await,
+    }
   });
 
   // spawn health check service for checking every possible path and purging expired DNS cache
@@ -111,6 +219,20 @@ pub async fn entrypoint(
     globals.runtime_handle.spawn(async move { proxy.start().await })
   }));
 
+  // Start built-in odoh relay if enabled, otherwise never completes
+  let relay = match &proxy_config.relay_config {
+    Some(relay_config) => Some(Arc::new(
+      Relay::try_new(&globals, relay_config, &http_client, authenticator).await?,
+    )),
+    None => None,
+  };
+  let relay_service = runtime_handle.spawn(async move {
+    match relay {
+      Some(relay) => relay.start().await.with_context(|| "relay service got down"),
+      None => futures::future::pending().await,
+    }
+  });
+
   // wait for all future
   if let Some(auth_service) = auth_service {
     select! {
@@ -126,6 +248,12 @@ pub async fn entrypoint(
       _ = healthcheck_service.fuse() => {
         warn!("Health check service is down, or term notified");
       }
+      _ = relay_service.fuse() => {
+        warn!("Relay service is down, or term notified");
+      }
+      _ = client_identity_service.fuse() => {
+        warn!("Client certificate reload service is down, or term notified");
+      }
     }
   } else {
     select! {
@@ -138,6 +266,12 @@ pub async fn entrypoint(
       _ = healthcheck_service.fuse() => {
         warn!("Health check service is down, or term notified");
       }
+      _ = relay_service.fuse() => {
+        warn!("Relay service is down, or term notified");
+      }
+      _ = client_identity_service.fuse() => {
+        warn!("Client certificate reload service is down, or term notified");
+      }
     }
   }
 
@@ -0,0 +1,99 @@
+use crate::globals::SocketBindingConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Create a socket for the destination, to which the source address, the interface and the fwmark are applied.
+/// Destinations of another address family than the source address are refused.
+fn bound_socket(binding: &SocketBindingConfig, dest: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+  let socket = Socket::new(Domain::for_address(*dest), ty, Some(protocol))?;
+  socket.set_nonblocking(true)?;
+
+  #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+  {
+    if let Some(interface) = &binding.interface {
+      socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    if let Some(fwmark) = binding.fwmark {
+      socket.set_mark(fwmark)?;
+    }
+  }
+  #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+  if binding.has_socket_options() {
+    return Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "interface and fwmark of sockets are supported only on Linux",
+    ));
+  }
+
+  match binding.source_ip {
+    Some(ip) if !is_same_family(binding, &dest.ip()) => {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("source address {ip} is of another address family than {dest}"),
+      ));
+    }
+    Some(ip) => socket.bind(&SocketAddr::new(ip, 0).into())?,
+    None if ty == Type::DGRAM => socket.bind(&unspecified(dest).into())?,
+    None => (),
+  }
+  Ok(socket)
+}
+
+/// Check if the destination is of the same address family as the source address, or the source address is not given
+pub(crate) fn is_same_family(binding: &SocketBindingConfig, dest: &IpAddr) -> bool {
+  binding.source_ip.is_none_or(|ip| ip.is_ipv4() == dest.is_ipv4())
+}
+
+/// Unspecified address of the same address family as the destination
+fn unspecified(dest: &SocketAddr) -> SocketAddr {
+  match dest {
+    SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+  }
+}
+
+/// Connect to the destination over TCP with the socket binding
+pub(crate) async fn connect_tcp(binding: &SocketBindingConfig, dest: SocketAddr) -> io::Result<TcpStream> {
+  let socket = bound_socket(binding, &dest, Type::STREAM, Protocol::TCP)?;
+  TcpSocket::from_std_stream(socket.into()).connect(dest).await
+}
+
+/// Create a UDP socket to exchange datagrams with the destination with the socket binding, which is not connected
+pub(crate) fn bind_udp(binding: &SocketBindingConfig, dest: SocketAddr) -> io::Result<UdpSocket> {
+  let socket = bound_socket(binding, &dest, Type::DGRAM, Protocol::UDP)?;
+  UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn source_ip_is_bound() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap();
+    let binding = SocketBindingConfig {
+      source_ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+      ..Default::default()
+    };
+    let stream = connect_tcp(&binding, dest).await.unwrap();
+    assert_eq!(stream.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    // destination of another address family than the source address is refused
+    let binding = SocketBindingConfig {
+      source_ip: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+      ..Default::default()
+    };
+    assert!(!is_same_family(&binding, &dest.ip()));
+    assert!(bind_udp(&binding, dest).is_err());
+    assert!(connect_tcp(&binding, dest).await.is_err());
+
+    // unspecified address is bound to udp sockets without the source address
+    let socket = bind_udp(&SocketBindingConfig::default(), dest).unwrap();
+    assert_eq!(socket.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+  }
+}
@@ -0,0 +1,5 @@
+mod relay_main;
+mod relay_request;
+mod token;
+
+pub use relay_main::Relay;
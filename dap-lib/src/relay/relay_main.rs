@@ -0,0 +1,232 @@
+use super::{relay_request::RelayRequest, token::TokenValidator};
+use crate::{
+  auth::Authenticator,
+  constants::RELAY_MAX_BODY_SIZE,
+  error::*,
+  globals::{Globals, RelayConfig},
+  http_client::{is_internal, ExternalHttpClient, HttpClient},
+  log::*,
+};
+use hyper::{
+  body::HttpBody,
+  header,
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use url::Url;
+
+/// Media type of ODoH messages
+const ODOH_MEDIA_TYPE: &str = "application/oblivious-dns-message";
+
+/// Built-in ODoH and μODoH relay forwarding queries to the next hop
+pub struct Relay {
+  /// relay settings
+  config: RelayConfig,
+  /// validator of bearer tokens given by clients
+  token_validator: Option<TokenValidator>,
+  /// http client to forward queries
+  http_client: Arc<HttpClient>,
+  /// http client to forward queries to next hops given only by clients, which never connects to internal addresses
+  external_client: ExternalHttpClient,
+  /// authenticator to attach the own token to queries forwarded to configured next hops
+  auth_client: Option<Arc<Authenticator>>,
+  /// globals
+  globals: Arc<Globals>,
+}
+
+impl Relay {
+  /// Create a new relay
+  pub async fn try_new(
+    globals: &Arc<Globals>,
+    config: &RelayConfig,
+    http_client: &Arc<HttpClient>,
+    auth_client: Option<Arc<Authenticator>>,
+  ) -> Result<Self> {
+    if config.allowed_targets.is_none() && config.token_validation.is_none() {
+      return Err(DapError::Other(anyhow!(
+        "Relay requires allowed targets and/or token validation"
+      )));
+    }
+    let token_validator = config
+      .token_validation
+      .as_ref()
+      .map(TokenValidator::try_from)
+      .transpose()?;
+    let external_client = http_client.external_client().await?;
+    Ok(Self {
+      config: config.clone(),
+      token_validator,
+      http_client: http_client.clone(),
+      external_client,
+      auth_client,
+      globals: globals.clone(),
+    })
+  }
+
+  /// Start relay servers on all listen addresses
+  pub async fn start(self: Arc<Self>) -> Result<()> {
+    let servers = self.config.listen_addresses.iter().map(|addr| {
+      let self_clone = self.clone();
+      let addr = *addr;
+      self
+        .globals
+        .runtime_handle
+        .spawn(async move { self_clone.start_server(addr).await })
+    });
+    let (res, _, _) = futures::future::select_all(servers).await;
+    res.map_err(|e| DapError::RelayServerError(e.to_string()))?
+  }
+
+  /// Start relay server for single address
+  async fn start_server(self: Arc<Self>, listening_on: SocketAddr) -> Result<()> {
+    let self_clone = self.clone();
+    let make_service = make_service_fn(move |_| {
+      let relay = self_clone.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let relay = relay.clone();
+          async move { Ok::<_, Infallible>(relay.serve(req).await) }
+        }))
+      }
+    });
+    let server = Server::try_bind(&listening_on)
+      .map_err(|e| DapError::RelayServerError(e.to_string()))?
+      .serve(make_service);
+    info!("Listening on {} as ODoH relay ({})", listening_on, self.config.path);
+    server.await.map_err(|e| DapError::RelayServerError(e.to_string()))
+  }
+
+  /// Serve a request, responding with an error status on failure
+  async fn serve(&self, req: Request<Body>) -> Response<Body> {
+    match self.relay(req).await {
+      Ok(response) => response,
+      Err(e) => {
+        let status = match &e {
+          DapError::TokenError(_) => StatusCode::UNAUTHORIZED,
+          DapError::RelayTargetNotAllowed(_) => StatusCode::FORBIDDEN,
+          DapError::RelayInvalidRequest(_) | DapError::RelayLoopDetected => StatusCode::BAD_REQUEST,
+          _ => StatusCode::BAD_GATEWAY,
+        };
+        warn!("[Relay] Failed to relay query: {e}");
+        status_response(status)
+      }
+    }
+  }
+
+  /// Validate and forward the query to the next hop
+  async fn relay(&self, req: Request<Body>) -> Result<Response<Body>> {
+    if req.uri().path() != self.config.path {
+      return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    if req.method() != Method::POST {
+      return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if content_type != Some(ODOH_MEDIA_TYPE) {
+      return Ok(status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    if let Some(validator) = &self.token_validator {
+      let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+      validator.validate_header(authorization)?;
+    }
+
+    let relay_request = RelayRequest::parse(req.uri().query().unwrap_or_default(), self.config.max_subseq_relays)?;
+    if relay_request.is_looped() {
+      return Err(DapError::RelayLoopDetected);
+    }
+    if let Some(allowed_targets) = &self.config.allowed_targets {
+      let target = &relay_request.target.authority;
+      if !allowed_targets
+        .iter()
+        .any(|v| v.authority().eq_ignore_ascii_case(target))
+      {
+        return Err(DapError::RelayTargetNotAllowed(target.to_owned()));
+      }
+    }
+
+    if req.body().size_hint().lower() > RELAY_MAX_BODY_SIZE as u64 {
+      return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    let body = hyper::body::to_bytes(req.into_body())
+      .await
+      .map_err(|e| DapError::RelayInvalidRequest(e.to_string()))?;
+    if body.len() > RELAY_MAX_BODY_SIZE {
+      return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    // forward to the next hop, where hosts in internal networks are reachable only if configured.
+    // internal addresses are skipped again when connecting, since the hostname may be resolved to them by then.
+    let next_hop_url = relay_request.next_hop_url()?;
+    let is_configured = self.is_configured(&next_hop_url);
+    if !is_configured && self.http_client.lookup(&next_hop_url).await?.iter().all(is_internal) {
+      return Err(DapError::RelayTargetNotAllowed(next_hop_url.authority().to_owned()));
+    }
+    debug!("[Relay] Forward query to {}", next_hop_url.authority());
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+      reqwest::header::CONTENT_TYPE,
+      reqwest::header::HeaderValue::from_static(ODOH_MEDIA_TYPE),
+    );
+    headers.insert(
+      reqwest::header::ACCEPT,
+      reqwest::header::HeaderValue::from_static(ODOH_MEDIA_TYPE),
+    );
+    headers.insert(
+      reqwest::header::CACHE_CONTROL,
+      reqwest::header::HeaderValue::from_static("no-cache, no-store"),
+    );
+    // the own token is never sent to next hops given only by clients
+    if let (Some(auth), true) = (&self.auth_client, is_configured) {
+      let token = auth.id_token().await?;
+      if let Ok(v) = reqwest::header::HeaderValue::from_str(&format!("Bearer {token}")) {
+        headers.insert(reqwest::header::AUTHORIZATION, v);
+      }
+    }
+    let response = match is_configured {
+      true => {
+        let inner = self.http_client.inner();
+        let lock = inner.read().await;
+        lock.post(next_hop_url).headers(headers).body(body).send().await?
+      }
+      false => {
+        self
+          .external_client
+          .post(next_hop_url)
+          .headers(headers)
+          .body(body)
+          .send()
+          .await?
+      }
+    };
+
+    // relay the status and body of the next hop as they are, e.g., 401 for key rotation of the target
+    let status = response.status().as_u16();
+    let body = response.bytes().await?;
+    Response::builder()
+      .status(status)
+      .header(header::CONTENT_TYPE, ODOH_MEDIA_TYPE)
+      .header(header::CACHE_CONTROL, "no-cache, no-store")
+      .body(Body::from(body))
+      .map_err(|e| DapError::RelayServerError(e.to_string()))
+  }
+
+  /// Check if the next hop is given in the config, i.e., in allowed targets or endpoints of the proxy itself
+  fn is_configured(&self, next_hop_url: &Url) -> bool {
+    let authority = next_hop_url.authority();
+    self
+      .config
+      .allowed_targets
+      .iter()
+      .flatten()
+      .chain(self.http_client.endpoints().iter())
+      .any(|v| v.authority().eq_ignore_ascii_case(authority))
+  }
+}
+
+/// Build an empty response with the status
+fn status_response(status: StatusCode) -> Response<Body> {
+  let mut response = Response::new(Body::empty());
+  *response.status_mut() = status;
+  response
+}
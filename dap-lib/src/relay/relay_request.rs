@@ -0,0 +1,161 @@
+use crate::error::*;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Hop given in query parameters, i.e., authority and path
+pub(super) struct Hop {
+  pub authority: String,
+  pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// ODoH query to be relayed, parsed from `targethost`/`targetpath` and `relayhost[i]`/`relaypath[i]` query parameters
+pub(super) struct RelayRequest {
+  /// target resolver
+  pub target: Hop,
+  /// subsequent relays after this relay in order
+  pub relays: Vec<Hop>,
+}
+
+impl RelayRequest {
+  /// Parse query parameters of the request
+  pub fn parse(query: &str, max_subseq_relays: usize) -> Result<Self> {
+    let invalid = |e: &str| DapError::RelayInvalidRequest(e.to_string());
+    let params = url::form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>();
+    let get = |key: &str| params.iter().find(|(k, _)| k == key).map(|(_, v)| v.to_string());
+
+    let (Some(authority), Some(path)) = (get("targethost"), get("targetpath")) else {
+      return Err(invalid("targethost and targetpath are required"));
+    };
+    let target = hop(authority, path)?;
+
+    let mut relays = vec![];
+    for idx in 1.. {
+      let (relay_host, relay_path) = (get(&format!("relayhost[{idx}]")), get(&format!("relaypath[{idx}]")));
+      let (Some(authority), Some(path)) = (relay_host.clone(), relay_path.clone()) else {
+        if relay_host.is_some() || relay_path.is_some() {
+          return Err(invalid("relayhost and relaypath must be given in pairs"));
+        }
+        break;
+      };
+      if idx > max_subseq_relays {
+        return Err(invalid("too many subsequent relays"));
+      }
+      relays.push(hop(authority, path)?);
+    }
+    // relays must be numbered from 1 without gaps
+    let numbered = params
+      .iter()
+      .filter(|(k, _)| k.starts_with("relayhost[") || k.starts_with("relaypath["))
+      .count();
+    if numbered != relays.len() * 2 {
+      return Err(invalid("relays must be numbered sequentially from 1"));
+    }
+
+    Ok(Self { target, relays })
+  }
+
+  /// Check if any authority appears twice in the subsequent hops
+  pub fn is_looped(&self) -> bool {
+    let mut seen: Vec<&str> = vec![];
+    for authority in self
+      .relays
+      .iter()
+      .chain(std::iter::once(&self.target))
+      .map(|v| v.authority.as_str())
+    {
+      if seen.iter().any(|v| v.eq_ignore_ascii_case(authority)) {
+        return true;
+      }
+      seen.push(authority);
+    }
+    false
+  }
+
+  /// Build the url of the next hop, where the first subsequent relay is given the rest of the path
+  pub fn next_hop_url(&self) -> Result<Url> {
+    let Some((next, rest)) = self.relays.split_first() else {
+      let mut url = Url::parse(&format!("https://{}", self.target.authority))?;
+      url.set_path(&self.target.path);
+      return Ok(url);
+    };
+    let mut url = Url::parse(&format!("https://{}", next.authority))?;
+    url.set_path(&next.path);
+    url
+      .query_pairs_mut()
+      .append_pair("targethost", &self.target.authority)
+      .append_pair("targetpath", &self.target.path);
+    for (idx, relay) in rest.iter().enumerate() {
+      url
+        .query_pairs_mut()
+        .append_pair(&format!("relayhost[{}]", idx + 1), &relay.authority)
+        .append_pair(&format!("relaypath[{}]", idx + 1), &relay.path);
+    }
+    Ok(url)
+  }
+}
+
+/// Build a hop after checking the authority does not contain anything but host and port
+fn hop(authority: String, path: String) -> Result<Hop> {
+  let url = Url::parse(&format!("https://{authority}"))
+    .map_err(|_| DapError::RelayInvalidRequest(format!("invalid authority {authority}")))?;
+  let is_authority_only = url.username().is_empty()
+    && url.password().is_none()
+    && url.path() == "/"
+    && url.query().is_none()
+    && url.fragment().is_none()
+    && !authority.contains(['/', '?', '#', '@']);
+  if !is_authority_only || !path.starts_with('/') {
+    return Err(DapError::RelayInvalidRequest(format!("invalid hop {authority}{path}")));
+  }
+  Ok(Hop { authority, path })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn relay_request_works() {
+    // odoh
+    let req = RelayRequest::parse("targethost=odoh.cloudflare-dns.com&targetpath=%2Fdns-query", 2).unwrap();
+    assert!(req.relays.is_empty());
+    assert_eq!(
+      req.next_hop_url().unwrap().as_str(),
+      "https://odoh.cloudflare-dns.com/dns-query"
+    );
+
+    // modoh, shifted to the next relay
+    let query = "targethost=odoh.cloudflare-dns.com&targetpath=%2Fdns-query\
+      &relayhost%5B1%5D=relay1.example%3A8443&relaypath%5B1%5D=%2Fproxy\
+      &relayhost%5B2%5D=relay2.example&relaypath%5B2%5D=%2Fproxy";
+    let req = RelayRequest::parse(query, 2).unwrap();
+    assert_eq!(req.relays.len(), 2);
+    assert!(!req.is_looped());
+    let next = req.next_hop_url().unwrap();
+    assert_eq!(next.authority(), "relay1.example:8443");
+    assert_eq!(next.path(), "/proxy");
+    let shifted = RelayRequest::parse(next.query().unwrap(), 1).unwrap();
+    assert_eq!(shifted.target, req.target);
+    assert_eq!(shifted.relays, req.relays[1..]);
+
+    // too many relays, gaps, missing pairs and invalid hops
+    assert!(RelayRequest::parse(query, 1).is_err());
+    let gap = "targethost=t.example&targetpath=%2Fdns-query&relayhost%5B2%5D=r.example&relaypath%5B2%5D=%2Fproxy";
+    assert!(RelayRequest::parse(gap, 2).is_err());
+    assert!(RelayRequest::parse("targethost=t.example&targetpath=%2Fq&relayhost%5B1%5D=r.example", 2).is_err());
+    assert!(RelayRequest::parse("targethost=t.example%2Fx&targetpath=%2Fq", 2).is_err());
+    assert!(RelayRequest::parse("targethost=user%40t.example&targetpath=%2Fq", 2).is_err());
+    assert!(RelayRequest::parse("targetpath=%2Fq", 2).is_err());
+  }
+
+  #[test]
+  fn loop_detection_works() {
+    let query = "targethost=t.example&targetpath=%2Fdns-query\
+      &relayhost%5B1%5D=r.example&relaypath%5B1%5D=%2Fproxy\
+      &relayhost%5B2%5D=R.example&relaypath%5B2%5D=%2Fproxy2";
+    assert!(RelayRequest::parse(query, 2).unwrap().is_looped());
+    let query = "targethost=r.example&targetpath=%2Fdns-query&relayhost%5B1%5D=r.example&relaypath%5B1%5D=%2Fproxy";
+    assert!(RelayRequest::parse(query, 2).unwrap().is_looped());
+  }
+}
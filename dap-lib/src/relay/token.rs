@@ -0,0 +1,184 @@
+use crate::{error::*, globals::TokenValidationConfig};
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Debug)]
+/// JOSE header of id tokens
+struct Header {
+  alg: String,
+}
+
+#[derive(Deserialize, Debug)]
+/// Claims of id tokens checked by the relay
+struct Claims {
+  exp: u64,
+  nbf: Option<u64>,
+  iss: Option<String>,
+  aud: Option<Audience>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+/// Audience claim given as a string or an array of strings
+enum Audience {
+  Single(String),
+  Multiple(Vec<String>),
+}
+
+impl Audience {
+  fn contains(&self, audience: &str) -> bool {
+    match self {
+      Audience::Single(v) => v == audience,
+      Audience::Multiple(v) => v.iter().any(|v| v == audience),
+    }
+  }
+}
+
+/// Validator of bearer tokens given to the relay, i.e., id tokens in JWT signed with EdDSA by the token server
+pub(super) struct TokenValidator {
+  keys: Vec<VerifyingKey>,
+  issuer: Option<String>,
+  audiences: Option<Vec<String>>,
+}
+
+impl TryFrom<&TokenValidationConfig> for TokenValidator {
+  type Error = DapError;
+
+  fn try_from(config: &TokenValidationConfig) -> Result<Self> {
+    let keys = config
+      .public_keys
+      .iter()
+      .map(|key| {
+        let bytes = BASE64
+          .decode(key.as_bytes())
+          .map_err(|e| DapError::TokenError(format!("invalid public key: {e}")))?;
+        let bytes: [u8; 32] = bytes
+          .try_into()
+          .map_err(|_| DapError::TokenError("public key must be 32 bytes".to_string()))?;
+        VerifyingKey::from_bytes(&bytes).map_err(|e| DapError::TokenError(format!("invalid public key: {e}")))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    if keys.is_empty() {
+      return Err(DapError::TokenError("no public key to validate tokens".to_string()));
+    }
+    Ok(Self {
+      keys,
+      issuer: config.issuer.clone(),
+      audiences: config.audiences.clone(),
+    })
+  }
+}
+
+impl TokenValidator {
+  /// Validate the token at the given unix time in secs
+  pub fn validate(&self, token: &str, now: u64) -> Result<()> {
+    let invalid = |e: &str| DapError::TokenError(e.to_string());
+    let mut parts = token.split('.');
+    let (Some(header_b64), Some(claims_b64), Some(signature_b64), None) =
+      (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+      return Err(invalid("malformed token"));
+    };
+    let decode = |v: &str| {
+      BASE64URL_NOPAD
+        .decode(v.as_bytes())
+        .map_err(|_| invalid("malformed token"))
+    };
+
+    let header: Header = serde_json::from_slice(&decode(header_b64)?).map_err(|_| invalid("malformed header"))?;
+    if header.alg != "EdDSA" {
+      return Err(invalid("unsupported algorithm"));
+    }
+    let signature = Signature::from_slice(&decode(signature_b64)?).map_err(|_| invalid("malformed signature"))?;
+    let signed = &token[..header_b64.len() + 1 + claims_b64.len()];
+    if !self
+      .keys
+      .iter()
+      .any(|key| key.verify(signed.as_bytes(), &signature).is_ok())
+    {
+      return Err(invalid("invalid signature"));
+    }
+
+    let claims: Claims = serde_json::from_slice(&decode(claims_b64)?).map_err(|_| invalid("malformed claims"))?;
+    if claims.exp <= now || claims.nbf.is_some_and(|nbf| nbf > now) {
+      return Err(invalid("token is expired or not yet valid"));
+    }
+    if let Some(issuer) = &self.issuer {
+      if claims.iss.as_ref() != Some(issuer) {
+        return Err(invalid("unexpected issuer"));
+      }
+    }
+    if let Some(audiences) = &self.audiences {
+      if !claims
+        .aud
+        .as_ref()
+        .is_some_and(|aud| audiences.iter().any(|v| aud.contains(v)))
+      {
+        return Err(invalid("unexpected audience"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Validate the bearer token in the value of the authorization header
+  pub fn validate_header(&self, authorization: Option<&str>) -> Result<()> {
+    let Some(token) = authorization.and_then(|v| v.strip_prefix("Bearer ")) else {
+      return Err(DapError::TokenError("no bearer token".to_string()));
+    };
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_err(|e| DapError::TokenError(e.to_string()))?
+      .as_secs();
+    self.validate(token.trim(), now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ed25519_dalek::{Signer, SigningKey};
+
+  fn token(key: &SigningKey, alg: &str, claims: &str) -> String {
+    let header = BASE64URL_NOPAD.encode(format!("{{\"alg\":\"{alg}\",\"typ\":\"JWT\"}}").as_bytes());
+    let claims = BASE64URL_NOPAD.encode(claims.as_bytes());
+    let signature = key.sign(format!("{header}.{claims}").as_bytes());
+    format!("{header}.{claims}.{}", BASE64URL_NOPAD.encode(&signature.to_bytes()))
+  }
+
+  #[test]
+  fn token_validation_works() {
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let validator = TokenValidator::try_from(&TokenValidationConfig {
+      public_keys: vec![BASE64.encode(key.verifying_key().as_bytes())],
+      issuer: Some("https://token.example".to_string()),
+      audiences: Some(vec!["client_a".to_string()]),
+    })
+    .unwrap();
+    let now = 1_700_000_000;
+    let valid = r#"{"exp":1700000600,"iss":"https://token.example","aud":["client_b","client_a"]}"#;
+    assert!(validator.validate(&token(&key, "EdDSA", valid), now).is_ok());
+    assert!(validator
+      .validate_header(Some(&format!("Bearer {}", token(&key, "EdDSA", valid))))
+      .is_err()); // expired at the current time
+    assert!(validator.validate_header(None).is_err());
+
+    // expired, wrong issuer or audience, not yet valid
+    for claims in [
+      r#"{"exp":1699999999,"iss":"https://token.example","aud":"client_a"}"#,
+      r#"{"exp":1700000600,"iss":"https://other.example","aud":"client_a"}"#,
+      r#"{"exp":1700000600,"iss":"https://token.example","aud":"client_c"}"#,
+      r#"{"exp":1700000600,"nbf":1700000300,"iss":"https://token.example","aud":"client_a"}"#,
+    ] {
+      assert!(validator.validate(&token(&key, "EdDSA", claims), now).is_err());
+    }
+
+    // other key, algorithm or tampered token
+    let other = SigningKey::from_bytes(&[8u8; 32]);
+    assert!(validator.validate(&token(&other, "EdDSA", valid), now).is_err());
+    assert!(validator.validate(&token(&key, "ES256", valid), now).is_err());
+    let tampered = token(&key, "EdDSA", valid).replacen('.', ".e", 1);
+    assert!(validator.validate(&tampered, now).is_err());
+  }
+}
@@ -9,6 +9,7 @@ use url::Url;
 pub trait ResolveIps {
   async fn resolve_ips(&self, target_url: &Url) -> Result<ResolveIpResponse>;
 }
+#[derive(Debug, Clone)]
 /// Response of ResolveIps trait
 pub struct ResolveIpResponse {
   /// hostname of target url